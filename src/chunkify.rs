@@ -1,139 +1,190 @@
 use crate::{
-    data::{ ShapeZ, StyledLine, Vvec, BB, MinMax, HasBB, CustomShape, BoundingType, StretchableBB},
+    data::{ ShapeZ, StyledLine, PolygonZ, BB, P2, P3, MinMax, BoundingType, StretchableBB},
+    error::{ Error, Result },
     logger::*,
-    triangulate::PolyTriangle,
+    triangulate::{ PolyTriangle, batches },
 };
 
 use std::{
-    ops::{ Div, Add, Mul, Sub },
-    borrow::Borrow,
-    collections::HashMap,
+    cmp::{ Ordering, Reverse },
+    hash::Hash,
+    collections::{ HashMap, BinaryHeap },
+    str::FromStr,
 };
 
 use ass::*;
 
-// Returns true if inner is completely inside outer, else false
-pub fn bb_in_bb_xy<T>(outer: &BB<T>, inner: &BB<T>) -> bool
-    where
-        T: std::cmp::PartialOrd
-{
-    (outer.0).0 <= (inner.0).0 &&
-        (outer.0).1 <= (inner.0).1 &&
-        (outer.1).0 >= (inner.1).0 &&
-        (outer.1).1 >= (inner.1).1
-}
+// the shapes of every cell that has any, a grid with many cuts has mostly empty cells
+pub type Cells<S> = HashMap<(u64,u64),Vec<S>>;
 
 pub type ChunkLine<T> = (u64,u64,Vec<ShapeZ<T>>);
 pub type ChunksLine<T> = Vec<ChunkLine<T>>;
 
-// take shapes, a global boundingbox and the amount of cuts to do over each axis
-// cuts the shapes into a scales regular grid
-pub fn cut<T>(cuts: u64, gbb: BB<T>, shapes: &[ShapeZ<T>], logger: &mut Logger) -> ChunksLine<T>
-    where
-        T: Clone + Copy + Default + PartialEq + PartialOrd + Into<usize>,
-        T: BoundingType + MinMax,
-        T: Div<Output = T> + Add<Output = T> + Mul<Output = T>,
-        u64: Ass<T>,
-{
-    let cuts_u64 = cuts;
-    let cuts = (cuts).ass();
-    let mut grid: Vvec<ShapeZ<T>> = vec![vec![]; (cuts_u64 * cuts_u64) as usize];
-    let bb0x = (gbb.0).0;
-    let bb0y = (gbb.0).1;
-    if bb0x != T::default() || bb0y != T::default(){ // only because it is compressed before it is chunkified
-        logger.log(Issue::NonOriginBoundingbox);
-        return vec![];
-    }
-    let gwid = (gbb.1).0;
-    let ghei = (gbb.1).1;
-    let csizex = (gwid / cuts) + (1u64).ass();
-    let csizey = (ghei / cuts) + (1u64).ass();
-    for shape in shapes{
-        let bb = shape.bounding_box();
-        let x0 = (bb.0).0;
-        let y0 = (bb.0).1;
-        let x1 = (bb.1).0;
-        let y1 = (bb.1).1;
-        let cx = x0 / csizex;
-        let cy = y0 / csizey;
-        let sbb = ((x0,y0,T::default()),(x1,y1,T::default()));
-        let cbb = ((cx * csizex, cy * csizey, T::default()),
-                    (cx * csizex + csizex, cy * csizey + csizey,T::default()));
-        let outside = bb_out_bb_xy(&cbb, &sbb);
-        if outside { continue; }
-        let inside = bb_in_bb_xy(&cbb, &sbb);
-        if inside{ // if the whole shape is in the chunk, just put it in
-            let vpos = (cy * cuts + cx).into();
-            grid[vpos].push(shape.clone());
-        }else if !shape.points.is_empty(){ // we need to cut the shape
-            let (fx,fy) = &shape.points[0];
-            let mut old_cx = (*fx) / csizex;
-            let mut old_cy = (*fy) / csizey;
-            let mut points = Vec::new();
-            let mut lastx = T::default();
-            let mut lasty = T::default();
-            let z = shape.z;
-            for (x,y) in shape{
-                let new_cx = (*x) / csizex;
-                let new_cy = (*y) / csizey;
-                if new_cx == old_cx && new_cy == old_cy{
-                    points.push((*x,*y));
-                }else{ // if we changed chunk ...
-                    points.push((*x,*y)); // keep out of chunk point to keep the line partly inside the chunk
-                    let mut newshape = ShapeZ{
-                        points,
-                        z,
-                        bb: T::start_box(),
-                    };
-                    newshape.stretch_bb(); // calculate the right boundingbox
-                    let vpos: usize = (old_cy * cuts + old_cx).into();
-                    grid[vpos].push(newshape);
-                    points = vec![(lastx,lasty),(*x,*y)]; // start a new collection for the new chunk
-                    old_cx = new_cx;
-                    old_cy = new_cy;
-                }
-                lastx = *x;
-                lasty = *y;
+// The regular grid chunks are cut on, the cells start at the corner of the global boundingbox.
+// Cells are a whole number wide so the grid is a bit bigger than the boundingbox.
+pub struct Grid{
+    pub cuts: u64,
+    pub origin: P2<f64>,
+    pub csize: P2<f64>,
+}
+
+impl Grid{
+    pub fn new<T: Copy + Into<f64>>(cuts: u64, gbb: &BB<T>) -> Self{
+        let size = |lo: T, hi: T| ((hi.into() - lo.into()) / cuts as f64).floor() + 1.0;
+        Self{
+            cuts,
+            origin: ((gbb.0).0.into(), (gbb.0).1.into()),
+            csize: (size((gbb.0).0, (gbb.1).0), size((gbb.0).1, (gbb.1).1)),
+        }
+    }
+
+    // the cell a point is in, points outside the grid go in the nearest cell
+    pub fn cell(&self, (x,y): P2<f64>) -> (u64,u64){
+        let c = |v: f64, o: f64, s: f64| (((v - o) / s).floor().max(0.0) as u64).min(self.cuts - 1);
+        (c(x, self.origin.0, self.csize.0), c(y, self.origin.1, self.csize.1))
+    }
+
+    // min and max corner of a cell
+    pub fn rect(&self, (cx,cy): (u64,u64)) -> (P2<f64>,P2<f64>){
+        let min = (self.origin.0 + cx as f64 * self.csize.0, self.origin.1 + cy as f64 * self.csize.1);
+        (min, (min.0 + self.csize.0, min.1 + self.csize.1))
+    }
+
+    // put the cells in chunks with their x and y, row by row, cells without shapes are left out
+    pub fn number<S>(&self, cells: Cells<S>) -> Vec<(u64,u64,Vec<S>)>{
+        let mut chunks = cells.into_iter().map(|((x,y),vec)| (x,y,vec)).collect::<Vec<_>>();
+        chunks.sort_by_key(|(x,y,_)| (*y,*x));
+        chunks
+    }
+
+    // every cell with its x and y, row by row, also the empty ones, only for grids with few cuts
+    pub fn all<S>(&self, mut cells: Cells<S>) -> Vec<(u64,u64,Vec<S>)>{
+        (0..self.cuts * self.cuts)
+            .map(|i| (i % self.cuts, i / self.cuts))
+            .map(|(x,y)| (x, y, cells.remove(&(x,y)).unwrap_or_default()))
+            .collect()
+    }
+}
+
+// Liang–Barsky: the part of the segment from a to b inside the rectangle,
+// as the parameters along the segment where it goes in and out, or None if it misses
+pub fn liang_barsky(a: P2<f64>, b: P2<f64>, (min,max): (P2<f64>,P2<f64>)) -> Option<(f64,f64)>{
+    let (dx,dy) = (b.0 - a.0, b.1 - a.1);
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    for (p,q) in [(-dx, a.0 - min.0), (dx, max.0 - a.0), (-dy, a.1 - min.1), (dy, max.1 - a.1)]{
+        if p == 0.0{ // parallel to this edge
+            if q < 0.0 { return None; }
+            continue;
+        }
+        let r = q / p;
+        if p < 0.0 { t0 = t0.max(r); }
+        else { t1 = t1.min(r); }
+        if t0 > t1 { return None; }
+    }
+    Some((t0,t1))
+}
+
+// the point at t along the segment, kept inside the rectangle it was clipped to
+fn along(a: P2<f64>, b: P2<f64>, t: f64, (min,max): (P2<f64>,P2<f64>)) -> P2<f64>{
+    if t == 0.0 { return a; }
+    if t == 1.0 { return b; }
+    ((a.0 + t * (b.0 - a.0)).clamp(min.0, max.0), (a.1 + t * (b.1 - a.1)).clamp(min.1, max.1))
+}
+
+// a part of a line and the cell it is in
+pub type Piece = ((u64,u64),Vec<P2<f64>>);
+
+// Clip a line to every cell it passes through, with new points where it crosses a cell edge.
+// Gives back the pieces with their cell, a line that goes in and out of a cell has a piece for every visit.
+pub fn clip_line(grid: &Grid, points: &[P2<f64>]) -> Vec<Piece>{
+    if points.len() == 1 { return vec![(grid.cell(points[0]), points.to_vec())]; }
+    let mut pieces = Vec::new();
+    // the pieces in the cells the previous segment went through
+    let mut open: HashMap<(u64,u64),Vec<P2<f64>>> = HashMap::new();
+    for seg in points.windows(2){
+        let (a,b) = (seg[0], seg[1]);
+        let (ca,cb) = (grid.cell(a), grid.cell(b));
+        let mut next = HashMap::new();
+        for cx in ca.0.min(cb.0)..=ca.0.max(cb.0){
+            for cy in ca.1.min(cb.1)..=ca.1.max(cb.1){
+                let rect = grid.rect((cx,cy));
+                let (t0,t1) = match liang_barsky(a, b, rect){
+                    Some((t0,t1)) if t1 > t0 || a == b => (t0,t1),
+                    _ => continue, // misses or only touches a corner
+                };
+                let (pa,pb) = (along(a, b, t0, rect), along(a, b, t1, rect));
+                let piece = match open.remove(&(cx,cy)){
+                    Some(mut piece) if piece.last() == Some(&pa) => { piece.push(pb); piece },
+                    Some(piece) => { pieces.push(((cx,cy),piece)); vec![pa,pb] },
+                    None => vec![pa,pb],
+                };
+                next.insert((cx,cy), piece);
             }
-            let mut newshape = ShapeZ{
-                points,
-                z,
-                bb: T::start_box(),
-            }; // round up and push the last chunk
-            newshape.stretch_bb();
-            let vpos = (old_cy * cuts + old_cx).into();
-            grid[vpos].push(newshape);
         }
+        pieces.extend(open.drain()); // the line left these cells
+        open = next;
     }
-    let mut chunks = Vec::new(); // just assign chunk numbers
-    for (i,vec) in grid.into_iter().enumerate(){
-        let i = i as u64;
-        let x = i % cuts_u64;
-        let y = i / cuts_u64;
-        chunks.push((x,y,vec));
+    pieces.extend(open);
+    pieces
+}
+
+// Clip all lines to the grid, make builds a line like the given one with the clipped points
+fn cut_lines<T,L>(grid: &Grid, lines: &[L], make: impl Fn(&L, Vec<P2<T>>) -> L) -> Cells<L>
+    where
+        T: Copy + PartialEq + Into<f64>,
+        f64: Ass<T>,
+        L: Line<T>,
+{
+    let mut cells: Cells<L> = HashMap::new();
+    for line in lines{
+        let points = line.points().iter().map(|p| to_f64(*p)).collect::<Vec<_>>();
+        for ((cx,cy),piece) in clip_line(grid, &points){
+            let mut piece = piece.into_iter().map(|(x,y)| (x.round().ass(), y.round().ass())).collect::<Vec<P2<T>>>();
+            piece.dedup();
+            // what is left of a piece that only grazes a cell
+            if piece.len() < 2 && points.len() > 1 { continue; }
+            cells.entry((cx,cy)).or_default().push(make(line, piece));
+        }
     }
-    chunks
+    cells
 }
 
-// true if inner is completely outside outer, else false
-pub fn bb_out_bb_xy<T>(outer: &BB<T>, inner: &BB<T>) -> bool
+// take shapes, a global boundingbox and the amount of cuts to do over each axis
+// cuts the shapes into a scales regular grid
+pub fn cut<T>(cuts: u64, gbb: BB<T>, shapes: &[ShapeZ<T>]) -> ChunksLine<T>
     where
-        T: std::cmp::PartialOrd
+        T: Copy + PartialEq + Into<f64> + BoundingType + MinMax,
+        f64: Ass<T>,
 {
-    ((outer.0).0 > (inner.1).0 || (outer.1).0 < (inner.0).0) &&
-    ((outer.0).1 > (inner.1).1 || (outer.1).1 < (inner.0).1)
+    let grid = Grid::new(cuts, &gbb);
+    grid.number(cut_shapes(&grid, shapes))
+}
+
+// cut heightlines into the cells of a grid
+pub fn cut_shapes<T>(grid: &Grid, shapes: &[ShapeZ<T>]) -> Cells<ShapeZ<T>>
+    where
+        T: Copy + PartialEq + Into<f64> + BoundingType + MinMax,
+        f64: Ass<T>,
+{
+    cut_lines(grid, shapes, |shape, points|{
+        let mut newshape = ShapeZ{ points, z: shape.z, bb: T::start_box() };
+        newshape.stretch_bb(); // calculate the right boundingbox
+        newshape
+    })
 }
 
 // remove heightlines that are not dividable by the modulo
 // this to have less points and make the map more readable
-pub fn pick_heights<T>(modulo: u64, chunk:Vec<ShapeZ<T>>) -> Vec<ShapeZ<T>>
+// the height is z / multi + zmin and can be below zero, in steps of 1 / multi that is z + zmin * multi
+pub fn pick_heights<T>(modulo: u64, zmin: i64, multi: u64, chunk:Vec<ShapeZ<T>>) -> Vec<ShapeZ<T>>
     where
         T: Into<u64> + Copy
 {
+    let (step, multi) = ((modulo * multi) as i64, multi as i64);
     let mut filtered = Vec::new();
     for shape in chunk{
-        let z = shape.z.into();
-        if z % modulo != 0{
+        let z = shape.z.into() as i64 + zmin * multi;
+        if z.rem_euclid(step) != 0{
             continue;
         }
         filtered.push(shape);
@@ -141,77 +192,404 @@ pub fn pick_heights<T>(modulo: u64, chunk:Vec<ShapeZ<T>>) -> Vec<ShapeZ<T>>
     filtered
 }
 
+// A line of points, heightlines and styled lines are simplified the same way
+pub trait Line<T>{
+    fn points(&self) -> &Vec<P2<T>>;
+    fn points_mut(&mut self) -> &mut Vec<P2<T>>;
+}
+
+impl<T> Line<T> for ShapeZ<T>{
+    fn points(&self) -> &Vec<P2<T>>{ &self.points }
+    fn points_mut(&mut self) -> &mut Vec<P2<T>>{ &mut self.points }
+}
+
+impl<T> Line<T> for StyledLine<T>{
+    fn points(&self) -> &Vec<P2<T>>{ &self.points }
+    fn points_mut(&mut self) -> &mut Vec<P2<T>>{ &mut self.points }
+}
+
 // Simplify the lines by just taking out every n point
-pub fn pick_points<T>(max: usize, chunk: Vec<ShapeZ<T>>) -> Vec<ShapeZ<T>>
+pub fn pick_points<T,L>(max: usize, mut chunk: Vec<L>) -> Vec<L>
     where
-        T: Copy + Eq
+        T: Copy,
+        L: Line<T>,
 {
     let mut ps = 0;
-    for shape in &chunk{
-        ps += shape.points_len();
+    for line in &chunk{
+        ps += line.points().len();
     }
     let modulo = ps / max + 1;
-    let mut nchunk = Vec::new();
-    for shape in chunk.into_iter(){
-        let points = shape.points;
-        let z = shape.z;
-        let bb = shape.bb;
+    for line in chunk.iter_mut(){
+        let points = std::mem::take(line.points_mut());
+        let last = points.len().saturating_sub(1);
         let mut npoints = Vec::new();
-        let last = points.len() - 1;
         for (i,p) in points.into_iter().enumerate(){
             if i % modulo == 0 || i == last{ // never leave out last or first point
                 npoints.push(p); // because the lines will not fit together that way
             } // and you will lose the opportunity to do "optimize_lines"
         }
-        nchunk.push(ShapeZ{
-            points: npoints,
-            z,
-            bb,
+        *line.points_mut() = npoints;
+    }
+    chunk
+}
+
+// How the lines of a LOD level are simplified
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Simplify{
+    // keep every point
+    None,
+    // keep every n-th point so a chunk has at most 5000 points
+    Every,
+    // Douglas–Peucker: leave out points closer to the line than the tolerance
+    DouglasPeucker,
+    // Visvalingam–Whyatt: leave out points that make a triangle smaller than the tolerance squared
+    Visvalingam,
+    // Douglas–Peucker on all lines of a chunk together, so lines never cross after simplification
+    Topology,
+}
+
+impl FromStr for Simplify{
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "none" => Ok(Simplify::None),
+            "every" => Ok(Simplify::Every),
+            "dp" => Ok(Simplify::DouglasPeucker),
+            "vw" => Ok(Simplify::Visvalingam),
+            "topo" => Ok(Simplify::Topology),
+            x => Err(Error::Argument(format!("Unknown simplification: {}, expected none, every, dp, vw or topo", x))),
+        }
+    }
+}
+
+// Simplify all lines of a chunk, the first and last point of every line are always kept
+pub fn simplify_lines<T,L>(method: Simplify, tolerance: f64, chunk: Vec<L>, logger: &mut Logger) -> Vec<L>
+    where
+        T: Copy + Into<f64>,
+        L: Line<T>,
+{
+    fn each<T,L>(mut chunk: Vec<L>, simplify: impl Fn(&[P2<T>]) -> Vec<P2<T>>) -> Vec<L>
+        where L: Line<T>
+    {
+        for line in chunk.iter_mut(){
+            let points = simplify(line.points());
+            *line.points_mut() = points;
+        }
+        chunk
+    }
+    match method{
+        Simplify::None => chunk,
+        Simplify::Every => pick_points(5000, chunk),
+        Simplify::DouglasPeucker => each(chunk, |ps| douglas_peucker(ps, tolerance)),
+        Simplify::Visvalingam => each(chunk, |ps| visvalingam(ps, tolerance)),
+        Simplify::Topology => simplify_topology(tolerance, chunk, logger),
+    }
+}
+
+fn to_f64<T: Copy + Into<f64>>((x,y): P2<T>) -> P2<f64>{
+    (x.into(), y.into())
+}
+
+// distance from p to the segment from a to b
+fn segment_distance(p: P2<f64>, a: P2<f64>, b: P2<f64>) -> f64{
+    let (dx,dy) = (b.0 - a.0, b.1 - a.1);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 == 0.0 { 0.0 }
+    else { (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len2).clamp(0.0, 1.0) };
+    let (cx,cy) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()
+}
+
+// the point between a and b furthest from the segment between them, with its distance
+fn furthest(points: &[P2<f64>], a: usize, b: usize) -> (f64,usize){
+    let mut furthest = (0.0, a);
+    for (i,p) in points.iter().enumerate().take(b).skip(a + 1){
+        let d = segment_distance(*p, points[a], points[b]);
+        if d > furthest.0 { furthest = (d,i); }
+    }
+    furthest
+}
+
+// Keep the point furthest from the line between the ends when it is further than the tolerance,
+// and do the same for both halves. Gives back which points are kept.
+fn douglas_peucker_keep(points: &[P2<f64>], tolerance: f64) -> Vec<bool>{
+    let n = points.len();
+    if n < 3 { return vec![true; n]; }
+    let mut keep = vec![false; n];
+    keep[0] = true;
+    keep[n - 1] = true;
+    let mut stack = vec![(0, n - 1)];
+    while let Some((a,b)) = stack.pop(){
+        let (d,i) = furthest(points, a, b);
+        if d > tolerance{
+            keep[i] = true;
+            stack.push((a,i));
+            stack.push((i,b));
+        }
+    }
+    keep
+}
+
+pub fn douglas_peucker<T: Copy + Into<f64>>(points: &[P2<T>], tolerance: f64) -> Vec<P2<T>>{
+    let keep = douglas_peucker_keep(&points.iter().map(|p| to_f64(*p)).collect::<Vec<_>>(), tolerance);
+    points.iter().zip(keep).filter(|(_,k)| *k).map(|(p,_)| *p).collect()
+}
+
+// > 0 when c is left of the line from a to b, < 0 when right
+fn orientation(a: P2<f64>, b: P2<f64>, c: P2<f64>) -> f64{
+    (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+}
+
+// true when the segments cross in a point that is not an end of either, touching is not crossing
+fn segments_cross(a: P2<f64>, b: P2<f64>, c: P2<f64>, d: P2<f64>) -> bool{
+    orientation(a, b, c) * orientation(a, b, d) < 0.0 && orientation(c, d, a) * orientation(c, d, b) < 0.0
+}
+
+// Douglas–Peucker on all lines of a chunk together.
+// A segment within the tolerance is still split when it crosses another segment of the simplified lines,
+// until nothing crosses, so lines that did not cross before simplification do not cross after it.
+// Every such split is logged as a rejected simplification.
+// Segments of the input itself can not be split, when they cross they are left as they are.
+pub fn simplify_topology<T,L>(tolerance: f64, mut chunk: Vec<L>, logger: &mut Logger) -> Vec<L>
+    where
+        T: Copy + Into<f64>,
+        L: Line<T>,
+{
+    let lines = chunk.iter().map(|l| l.points().iter().map(|p| to_f64(*p)).collect::<Vec<_>>()).collect::<Vec<_>>();
+    let mut keeps = lines.iter().map(|l| douglas_peucker_keep(l, tolerance)).collect::<Vec<_>>();
+    let (mut minx, mut miny, mut maxx, mut maxy) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for (x,y) in lines.iter().flatten(){
+        minx = minx.min(*x);
+        miny = miny.min(*y);
+        maxx = maxx.max(*x);
+        maxy = maxy.max(*y);
+    }
+    // the segments of the simplified lines: line, first and last point index, None once it is split
+    let mut segs = Vec::new();
+    for (l,keep) in keeps.iter().enumerate(){
+        let kept = keep.iter().enumerate().filter(|(_,k)| **k).map(|(i,_)| i).collect::<Vec<_>>();
+        segs.extend(kept.windows(2).map(|w| Some((l, w[0], w[1]))));
+    }
+    // put the segments in a grid so only segments close to each other are checked
+    let cell = ((maxx - minx).max(maxy - miny) / (segs.len() as f64).sqrt().max(1.0)).max(1.0);
+    let to_cell = |(x,y): P2<f64>| (((x - minx) / cell) as i64, ((y - miny) / cell) as i64);
+    let seg_cells = |(l,a,b): (usize,usize,usize)|{
+        let (c0,c1) = (to_cell(lines[l][a]), to_cell(lines[l][b]));
+        let xs = c0.0.min(c1.0)..=c0.0.max(c1.0);
+        xs.flat_map(move |x| (c0.1.min(c1.1)..=c0.1.max(c1.1)).map(move |y| (x,y)))
+    };
+    let mut grid: HashMap<(i64,i64),Vec<usize>> = HashMap::new();
+    for (i,seg) in segs.iter().enumerate(){
+        for c in seg_cells(seg.unwrap()){
+            grid.entry(c).or_default().push(i);
+        }
+    }
+    // after the first pass only the halves of split segments can cross something new
+    let mut check = (0..segs.len()).collect::<Vec<_>>();
+    while !check.is_empty(){
+        let mut split = Vec::new();
+        for &i in &check{
+            let (l,a,b) = if let Some(seg) = segs[i] { seg } else { continue; };
+            for g in seg_cells((l,a,b)){
+                for &j in &grid[&g]{
+                    let (m,c,d) = if let Some(seg) = segs[j] { seg } else { continue; };
+                    if j != i && segments_cross(lines[l][a], lines[l][b], lines[m][c], lines[m][d]){
+                        split.push(i);
+                        split.push(j);
+                    }
+                }
+            }
+        }
+        split.sort_unstable();
+        split.dedup();
+        check.clear();
+        for i in split{
+            let (l,a,b) = if let Some(seg) = segs[i] { seg } else { continue; };
+            // every point in between is on the segment, or there are none: the other segment is split instead
+            let (_,p) = furthest(&lines[l], a, b);
+            if p == a || keeps[l][p] { continue; }
+            keeps[l][p] = true;
+            logger.log(Issue::RejectedSimplification);
+            for c in seg_cells((l,a,b)){
+                if let Some(list) = grid.get_mut(&c){ list.retain(|j| *j != i); }
+            }
+            segs[i] = None;
+            for half in [(l,a,p), (l,p,b)]{
+                segs.push(Some(half));
+                for c in seg_cells(half){
+                    grid.entry(c).or_default().push(segs.len() - 1);
+                }
+                check.push(segs.len() - 1);
+            }
+        }
+    }
+    for (line,keep) in chunk.iter_mut().zip(keeps){
+        let points = std::mem::take(line.points_mut());
+        *line.points_mut() = points.into_iter().zip(keep).filter(|(_,k)| *k).map(|(p,_)| p).collect();
+    }
+    chunk
+}
+
+// an area in the heap, smallest first
+#[derive(PartialEq)]
+struct Area(f64, usize);
+
+impl Eq for Area{}
+
+impl PartialOrd for Area{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering>{
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Area{
+    fn cmp(&self, other: &Self) -> Ordering{
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+// Leave out the point that makes the smallest triangle with its neighbours,
+// until every triangle is at least the tolerance squared
+pub fn visvalingam<T: Copy + Into<f64>>(points: &[P2<T>], tolerance: f64) -> Vec<P2<T>>{
+    let n = points.len();
+    if n < 3 { return points.to_vec(); }
+    let area = |a: usize, b: usize, c: usize|{
+        let (a,b,c) = (to_f64(points[a]), to_f64(points[b]), to_f64(points[c]));
+        ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)).abs() / 2.0
+    };
+    // the points still in the line are a linked list
+    let mut prev = (0..n).map(|i| i.saturating_sub(1)).collect::<Vec<_>>();
+    let mut next = (1..=n).collect::<Vec<_>>();
+    let mut areas = vec![f64::INFINITY; n];
+    let mut keep = vec![true; n];
+    let mut heap = BinaryHeap::new();
+    for (i,a) in areas.iter_mut().enumerate().take(n - 1).skip(1){
+        *a = area(i - 1, i, i + 1);
+        heap.push(Reverse(Area(*a, i)));
+    }
+    let min_area = tolerance * tolerance;
+    while let Some(Reverse(Area(a,i))) = heap.pop(){
+        // this point was left out or has a new area, compared by bits so a NaN area matches itself
+        if !keep[i] || a.to_bits() != areas[i].to_bits() { continue; }
+        // NaN areas come last in the heap and are kept, like areas above the tolerance
+        if a.is_nan() || a >= min_area { break; }
+        keep[i] = false;
+        let (p,q) = (prev[i], next[i]);
+        next[p] = q;
+        prev[q] = p;
+        for j in [p,q]{
+            if j == 0 || j == n - 1 { continue; }
+            // never smaller than the area just left out, so points go in order of how much they matter
+            // a NaN area stays NaN, max would make it the area just left out
+            let new = area(prev[j], j, next[j]);
+            areas[j] = if new.is_nan() { new } else { new.max(a) };
+            heap.push(Reverse(Area(areas[j], j)));
+        }
+    }
+    points.iter().zip(keep).filter(|(_,k)| *k).map(|(p,_)| *p).collect()
+}
+
+// A ring of a polygon, so it can be simplified like the other lines
+struct Ring<T>{
+    points: Vec<P2<T>>,
+}
+
+impl<T> Line<T> for Ring<T>{
+    fn points(&self) -> &Vec<P2<T>>{ &self.points }
+    fn points_mut(&mut self) -> &mut Vec<P2<T>>{ &mut self.points }
+}
+
+// the area inside a ring, closed or not
+fn ring_area<T: Copy + Into<f64>>(ring: &[P3<T>]) -> f64{
+    let mut sum = 0.0;
+    for (i,a) in ring.iter().enumerate(){
+        let b = ring[(i + 1) % ring.len()];
+        sum += a.0.into() * b.1.into() - b.0.into() * a.1.into();
+    }
+    (sum / 2.0).abs()
+}
+
+// Simplify the rings of polygons for a LOD level and leave out polygons and holes smaller than min_area.
+// The rings of all polygons are simplified together, so with topo they do not cross each other.
+// Polygons without an outer ring left are left out, the points that are kept keep their height.
+pub fn simplify_polygons<T>(method: Simplify, tolerance: f64, min_area: f64, polygons: &[PolygonZ<T>], logger: &mut Logger)
+    -> Vec<PolygonZ<T>>
+    where
+        T: Copy + PartialEq + Into<f64> + BoundingType + MinMax,
+{
+    // the polygon every ring is from, if it is an outer ring and the ring itself
+    let mut owners = Vec::new();
+    let mut rings = Vec::new();
+    for (i,polygon) in polygons.iter().enumerate(){
+        if polygon.outers.iter().map(|r| ring_area(r)).sum::<f64>() < min_area { continue; }
+        for (outer,list) in [(true, &polygon.outers), (false, &polygon.inners)]{
+            for ring in list.iter().filter(|r| ring_area(r) >= min_area){
+                let mut points = ring.iter().map(|(x,y,_)| (*x,*y)).collect::<Vec<_>>();
+                // points made the same by rounding to whole metres would stop topo from splitting there
+                points.dedup();
+                owners.push((i, outer, ring));
+                rings.push(Ring{ points });
+            }
+        }
+    }
+    let rings = simplify_lines(method, tolerance, rings, logger);
+    let mut res: Vec<Option<PolygonZ<T>>> = polygons.iter().map(|_| None).collect();
+    for ((i,outer,original),ring) in owners.into_iter().zip(rings){
+        let closed = ring.points.len() > 1 && ring.points.first() == ring.points.last();
+        if ring.points.len() < 3 + closed as usize { continue; } // nothing left to fill
+        // the kept points are in the order of the original ring, so their heights are found in one pass
+        let mut heights = original.iter();
+        let ring = ring.points.into_iter().map(|(x,y)|{
+            let z = heights.find(|p| p.0 == x && p.1 == y).map_or(original[0].2, |p| p.2);
+            (x,y,z)
+        }).collect();
+        let polygon = res[i].get_or_insert_with(|| PolygonZ{
+            inners: Vec::new(),
+            outers: Vec::new(),
+            bb: T::start_box(),
+            style: polygons[i].style,
         });
+        if outer { polygon.outers.push(ring); } else { polygon.inners.push(ring); }
     }
-    nchunk
+    res.into_iter().flatten().filter(|p| !p.outers.is_empty()).map(|mut p|{
+        p.stretch_bb();
+        p
+    }).collect()
+}
+
+// Lines that can be merged when they share an end point and have the same key
+pub trait Mergeable<T>: Line<T> + StretchableBB{
+    type Key: Copy + Eq + Hash;
+    fn key(&self) -> Self::Key;
+}
+
+// heightlines merge with lines of the same height
+impl<T> Mergeable<T> for ShapeZ<T>
+    where T: Copy + Eq + Hash + BoundingType + MinMax
+{
+    type Key = T;
+    fn key(&self) -> T{ self.z }
+}
+
+// styled lines merge with lines of the same style
+impl<T> Mergeable<T> for StyledLine<T>
+    where T: BoundingType + MinMax + Copy
+{
+    type Key = usize;
+    fn key(&self) -> usize{ self.style }
 }
 
 // Merge lines with the same start or end point
 // Will result in less lines and less points
 // Will increase drawing performance if you have lots of lines with not so much points
 // As you will have in low LOD chunks
-pub fn optimize_lines<T>(mut old: Vec<ShapeZ<T>>) -> Vec<ShapeZ<T>>
+// The ends of all lines are kept in a hash map, so finding the line to merge with is O(1)
+pub fn optimize_lines<T,L>(old: Vec<L>) -> Vec<L>
     where
-        T: Copy + Eq + Default + MinMax,
+        T: Copy + Eq + Hash,
+        L: Mergeable<T>,
 {
-    type Fl<T> = ((T,T),(T,T));
-    enum Fres { FF, FL, LF, LL }
-    // just get first and last points
-    fn get_fl<T: Copy>(shape: &ShapeZ<T>) -> Fl<T>{
-        let first = shape.points[0];
-        let last = shape.points[shape.points.len() - 1];
-        (first,last)
-    }
-    // Return the first shape that connects to the input shape, or None
-    fn find_other<T>(shape: &ShapeZ<T>, others: &mut Vec<ShapeZ<T>>) -> Option<(Fres,ShapeZ<T>)>
-        where
-            T: PartialEq + Copy,
-    {
-        let (f0,l0) = get_fl(shape);
-        let mut fres = Fres::FF;
-        let mut ind = std::usize::MAX;
-        let b: &Vec<ShapeZ<T>> = others.borrow();
-        for (i,other) in b.iter().enumerate(){
-            if shape.z != other.z { continue; }
-            let (f1,l1) = get_fl(other);
-            if f0 == f1 { fres = Fres::FF; ind = i; break; }
-            if f0 == l1 { fres = Fres::FL; ind = i; break; }
-            if l0 == f1 { fres = Fres::LF; ind = i; break; }
-            if l0 == l1 { fres = Fres::LL; ind = i; break; }
-        }
-        if ind == std::usize::MAX{
-            None
-        }else{
-            let shape = others.swap_remove(ind); // O(1) remove, will not preserve order, we don't care
-            Some((fres,shape))
-        }
-    }
     // return reversed vec
     fn reversed<T>(mut v: Vec<T>) -> Vec<T>{
         v.reverse();
@@ -225,222 +603,525 @@ pub fn optimize_lines<T>(mut old: Vec<ShapeZ<T>>) -> Vec<ShapeZ<T>>
         a.extend(b);
         a
     }
-    // merge two shapes into one, knowing that they connect
-    fn merge<T>(fres: Fres, shape: ShapeZ<T>, oshape: ShapeZ<T>) -> ShapeZ<T>
-        where
-            T: BoundingType + MinMax + Copy,
-    {
-        let sp = shape.points;
-        let op = oshape.points;
-        let z = shape.z;
-        let np = match fres{ // note that reversing a line does not change how it looks
-            Fres::FF => conc(reversed(sp),op), // reverse sp so that last sp = first op, we concat
-            Fres::FL => conc(op,sp), // begin sp = end op so just put sp after op
-            Fres::LF => conc(sp,op), // exactly other way around
-            Fres::LL => conc(sp,reversed(op)), // just the inverse of the first case
-        };
-        let mut nz = ShapeZ{
-            points: np,
-            z,
-            bb: T::start_box(),
+    // merge two lines into one, knowing at which ends they connect
+    fn merge<T,L: Mergeable<T>>(at_first: bool, other_first: bool, mut line: L, mut other: L) -> L{
+        let sp = std::mem::take(line.points_mut());
+        let op = std::mem::take(other.points_mut());
+        let np = match (at_first, other_first){ // note that reversing a line does not change how it looks
+            (true, true) => conc(reversed(sp),op), // reverse sp so that last sp = first op, we concat
+            (true, false) => conc(op,sp), // begin sp = end op so just put sp after op
+            (false, true) => conc(sp,op), // exactly other way around
+            (false, false) => conc(sp,reversed(op)), // just the inverse of the first case
         };
-        nz.stretch_bb();
-        nz
+        *line.points_mut() = np;
+        line.stretch_bb();
+        line
     }
 
+    // for every key and end point: the lines that start (true) or end (false) there
+    type Ends<K,T> = HashMap<(K,P2<T>),Vec<(usize,bool)>>;
+    let mut ends: Ends<L::Key,T> = HashMap::new();
+    for (i,line) in old.iter().enumerate(){
+        if let (Some(first), Some(last)) = (line.points().first(), line.points().last()){
+            ends.entry((line.key(), *first)).or_default().push((i,true));
+            ends.entry((line.key(), *last)).or_default().push((i,false));
+        }
+    }
+    let mut lines = old.into_iter().map(Some).collect::<Vec<_>>();
     let mut independents = Vec::new();
-    while let Some(shape) = old.pop() {
-        let other = find_other(&shape, &mut old);
-        if let Some((fres,oshape)) = other{ // if we find a match, merge and put it back
-            let news = merge(fres,shape,oshape);
-            old.push(news);
-        }else{
-            independents.push(shape); // if not, put it in the new vec
+    for i in 0..lines.len(){
+        let mut line = if let Some(line) = lines[i].take() { line } else { continue; };
+        // merge as long as another line connects to one of the ends
+        while let (Some(first), Some(last)) = (line.points().first().copied(), line.points().last().copied()){
+            let mut found = None;
+            for (at_first, p) in [(true, first), (false, last)]{
+                if let Some(others) = ends.get_mut(&(line.key(), p)){
+                    others.retain(|(j,_)| lines[*j].is_some()); // forget lines that are merged or done
+                    if let Some((j,other_first)) = others.first(){
+                        found = Some((at_first, *j, *other_first));
+                        break;
+                    }
+                }
+            }
+            let (at_first, j, other_first) = if let Some(found) = found { found } else { break; };
+            if let Some(other) = lines[j].take(){
+                line = merge(at_first, other_first, line, other);
+            }
         }
+        independents.push(line); // nothing connects to it anymore
     }
     independents
 }
 
-fn xy_to_chunk<T>((x,y): (T,T), (csizex,csizey,offx,offy): (T,T,T,T)) -> (usize,usize)
-    where
-        T: Div<Output = T> + Sub<Output = T>,
-        T: Into<u64>,
-{
-    (((x - offx) / csizex).into() as usize, ((y - offy) / csizey).into() as usize)
-}
-
 pub type ChunkStyledLine<T> = (u64,u64,Vec<StyledLine<T>>);
 pub type ChunksStyledLine<T> = Vec<ChunkStyledLine<T>>;
 
 // take shapes, a global boundingbox and the amount of cuts to do over each axis
 // cuts the shapes into a scales regular grid
-pub fn cut_styled<T>(cuts: u8, gbb: BB<T>, shapes: &[StyledLine<T>]) -> ChunksStyledLine<T>
+pub fn cut_styled<T>(cuts: u64, gbb: BB<T>, shapes: &[StyledLine<T>]) -> ChunksStyledLine<T>
     where
-        T: Clone + Copy + Default + PartialEq + PartialOrd,
-        T: BoundingType + MinMax,
-        T: Div<Output = T> + Add<Output = T> + Mul<Output = T> + Sub<Output = T>,
-        u8: Ass<T>,
-        u64: Ass<T>,
-        T: Ass<usize> + Into<u64>,
-        usize: Ass<T>,
-        T: std::fmt::Debug,
+        T: Copy + PartialEq + Into<f64> + BoundingType + MinMax,
+        f64: Ass<T>,
 {
-    let cuts_u64 = cuts as u64;
-    let cuts_usize = cuts as usize;
-    let cuts = (cuts).ass();
-    let mut grid: Vvec<StyledLine<T>> = vec![vec![]; (cuts_u64 * cuts_u64) as usize];
-    let gwid = (gbb.1).0 - (gbb.0).0;
-    let ghei = (gbb.1).1 - (gbb.0).1;
-    let csizex = (gwid / cuts) + (1u64).ass();
-    let csizey = (ghei / cuts) + (1u64).ass();
-    let cinf = (csizex,csizey,(gbb.0).0,(gbb.0).1);
-    for shape in shapes{
-        let bb = shape.bounding_box();
-        let x0 = (bb.0).0;
-        let y0 = (bb.0).1;
-        let x1 = (bb.1).0;
-        let y1 = (bb.1).1;
-        let (cx, cy):(T,T) = { let (tx,ty) = xy_to_chunk((x0,y0), cinf); (tx.ass(),ty.ass())};
-        let sbb = ((x0,y0,T::default()),(x1,y1,T::default()));
-        let cbb = ((cx * csizex + cinf.2, cy * csizey + cinf.3, T::default()),
-                    (cx * csizex + csizex + cinf.2, cy * csizey + csizey + cinf.3,T::default()));
-        let outside = bb_out_bb_xy(&cbb, &sbb);
-        println!("{:?} - {:?}", cbb, sbb);
-        if outside { continue; }
-        let inside = bb_in_bb_xy(&cbb, &sbb);
-        if inside{ // if the whole shape is in the chunk, just put it in
-            let vpos: usize = (cy * cuts + cx).ass();
-            grid[vpos].push(shape.clone());
-        }else if !shape.points.is_empty(){ // we need to cut the shape
-            let fxy = &shape.points[0];
-            let (mut old_cx, mut old_cy) = xy_to_chunk(*fxy, cinf);
-            let mut points = Vec::new();
-            let tdef = T::default();
-            let mut last = (tdef,tdef);
-            let style = shape.style;
-            for xy in shape{
-                let (new_cx, new_cy) = xy_to_chunk(*xy, cinf);
-                if new_cx == old_cx && new_cy == old_cy{
-                    points.push(*xy);
-                }else{ // if we changed chunk ...
-                    points.push(*xy); // keep out of chunk point to keep the line partly inside the chunk
-                    let mut newshape = StyledLine{
-                        points,
-                        style,
-                        bb: T::start_box(),
-                    };
-                    newshape.stretch_bb(); // calculate the right boundingbox
-                    let vpos: usize = old_cy * cuts_usize + old_cx;
-                    grid[vpos].push(newshape);
-                    points = vec![last,*xy]; // start a new collection for the new chunk
-                    old_cx = new_cx;
-                    old_cy = new_cy;
-                }
-                last = *xy;
-            }
-            let mut newshape = StyledLine{
-                points,
-                style,
-                bb: T::start_box(),
-            }; // round up and push the last chunk
-            newshape.stretch_bb();
-            let vpos: usize = old_cy * cuts_usize + old_cx;
-            grid[vpos].push(newshape);
-        }
-    }
-    let mut chunks = Vec::new(); // just assign chunk numbers
-    for (i,vec) in grid.into_iter().enumerate(){
-        let i = i as u64;
-        let x = i % cuts_u64;
-        let y = i / cuts_u64;
-        chunks.push((x,y,vec));
-    }
-    chunks
+    let grid = Grid::new(cuts, &gbb);
+    grid.number(cut_styled_lines(&grid, shapes))
+}
+
+// cut styled lines into the cells of a grid
+pub fn cut_styled_lines<T>(grid: &Grid, shapes: &[StyledLine<T>]) -> Cells<StyledLine<T>>
+    where
+        T: Copy + PartialEq + Into<f64> + BoundingType + MinMax,
+        f64: Ass<T>,
+{
+    cut_lines(grid, shapes, |shape, points|{
+        let mut newshape = StyledLine{ points, style: shape.style, bb: T::start_box() };
+        newshape.stretch_bb(); // calculate the right boundingbox
+        newshape
+    })
 }
 
 pub type ChunkPoly<T> = (u64,u64,Vec<PolyTriangle<T>>);
 pub type ChunksPoly<T> = Vec<ChunkPoly<T>>;
 
+// Sutherland–Hodgman: the part of a convex polygon inside the rectangle, in the same winding
+pub fn clip_polygon(polygon: &[P2<f64>], (min,max): (P2<f64>,P2<f64>)) -> Vec<P2<f64>>{
+    let mut res = polygon.to_vec();
+    // clip against one edge after the other: the axis, where the edge is and which side is inside
+    for (axis, edge, below) in [(0, min.0, false), (0, max.0, true), (1, min.1, false), (1, max.1, true)]{
+        let get = |p: P2<f64>| if axis == 0 { p.0 } else { p.1 };
+        let inside = |p: P2<f64>| if below { get(p) <= edge } else { get(p) >= edge };
+        // the same segment gives the same point in both cells next to the edge
+        let cross = |a: P2<f64>, b: P2<f64>|{
+            let (a,b) = if a < b { (a,b) } else { (b,a) };
+            let t = (edge - get(a)) / (get(b) - get(a));
+            if axis == 0 { (edge, a.1 + t * (b.1 - a.1)) }
+            else { (a.0 + t * (b.0 - a.0), edge) }
+        };
+        let input = std::mem::take(&mut res);
+        for (i,b) in input.iter().enumerate(){
+            let a = input[(i + input.len() - 1) % input.len()];
+            match (inside(a), inside(*b)){
+                (true,true) => res.push(*b),
+                (true,false) => res.push(cross(a, *b)),
+                (false,true) => { res.push(cross(a, *b)); res.push(*b); },
+                (false,false) => {},
+            }
+        }
+        if res.is_empty() { break; }
+    }
+    res
+}
+
 // Cut triangles into chunks
-pub fn chunkify_polytriangles<T>(cuts: u8, gbb: BB<T>, polygons: Vec<PolyTriangle<T>>) -> ChunksPoly<T>
+// Every triangle is clipped to the cells it covers, so every chunk covers exactly its own area
+pub fn chunkify_polytriangles<T>(cuts: u64, gbb: BB<T>, polygons: &[PolyTriangle<T>]) -> ChunksPoly<T>
+    where
+        T: Copy + Eq + std::hash::Hash + Into<f64> + BoundingType + MinMax,
+        f64: Ass<T>,
+{
+    let grid = Grid::new(cuts, &gbb);
+    grid.number(clip_triangles(&grid, polygons))
+}
+
+// clip triangles to the cells of a grid
+pub fn clip_triangles<T>(grid: &Grid, polygons: &[PolyTriangle<T>]) -> Cells<PolyTriangle<T>>
     where
-        T: Clone + Copy + Default + PartialEq + PartialOrd + Eq + std::hash::Hash,
-        T: BoundingType + MinMax,
-        T: Div<Output = T> + Add<Output = T> + Mul<Output = T> + Sub<Output = T>,
-        T: Into<u64>,
-        T: std::fmt::Display + std::fmt::Debug,
-        u64: Ass<T>,
-        u8: Ass<T>,
+        T: Copy + Eq + std::hash::Hash + Into<f64> + BoundingType + MinMax,
+        f64: Ass<T>,
 {
-    let cuts_usize = cuts as usize;
-    let cuts = cuts.ass();
-    let mut grid: Vvec<PolyTriangle<T>> = vec![vec![]; cuts_usize * cuts_usize];
-    let gwid = (gbb.1).0 - (gbb.0).0;
-    let ghei = (gbb.1).1 - (gbb.0).1;
-    let csizex = (gwid / cuts) + (1u64).ass();
-    let csizey = (ghei / cuts) + (1u64).ass();
-    let cinf = (csizex,csizey,(gbb.0).0,(gbb.0).1);
-    println!("{:?} {} {}", gbb, csizex, csizey);
+    let mut cells: Cells<PolyTriangle<T>> = HashMap::new();
     for polygon in polygons{
         let mut localgrid = HashMap::new();
-        for i in 0..polygon.indices.len() / 3{
-            let ia = polygon.indices[i * 3];
-            let ib = polygon.indices[i * 3 + 1];
-            let ic = polygon.indices[i * 3 + 2];
-            let va = polygon.vertices[ia as usize];
-            let vb = polygon.vertices[ib as usize];
-            let vc = polygon.vertices[ic as usize];
-            // Check in which cells the vertices fall
-            let mut cells = vec![
-                xy_to_chunk(va, cinf),
-                xy_to_chunk(vb, cinf),
-                xy_to_chunk(vc, cinf),
-            ];
-            cells.dedup();
-            // We add the vertex to each cell it falls into
-            for (cx,cy) in cells{
-                let (mut vertices,mut indices,mut indexmap) = if let Some((v,id,im)) = localgrid.remove(&(cx,cy)){
-                    (v,id,im)
-                }else{
-                    (Vec::new(),Vec::new(),HashMap::new())
-                };
-                // We must re-index each vertex that is added
-                let mut localize = |vx|{
-                    if let Some(ind) = indexmap.get(&vx){
-                        indices.push(*ind as u16);
-                    }else{
-                        vertices.push(vx);
-                        let ind = vertices.len() - 1;
-                        indexmap.insert(vx, ind);
-                        indices.push(ind as u16);
+        for tri in polygon.indices.chunks_exact(3){
+            let tri = tri.iter().map(|i| to_f64(polygon.vertices[*i as usize])).collect::<Vec<_>>();
+            let min = tri.iter().fold((f64::MAX, f64::MAX), |m,p| (m.0.min(p.0), m.1.min(p.1)));
+            let max = tri.iter().fold((f64::MIN, f64::MIN), |m,p| (m.0.max(p.0), m.1.max(p.1)));
+            let (c0,c1) = (grid.cell(min), grid.cell(max));
+            for cx in c0.0..=c1.0{
+                for cy in c0.1..=c1.1{
+                    let clipped = clip_polygon(&tri, grid.rect((cx,cy)))
+                        .into_iter().map(|(x,y)| (x.round().ass(), y.round().ass())).collect::<Vec<P2<T>>>();
+                    if clipped.len() < 3 { continue; }
+                    let (vertices,indices,indexmap) = localgrid.entry((cx,cy))
+                        .or_insert_with(|| (Vec::new(), Vec::new(), HashMap::new()));
+                    // We must re-index each vertex that is added
+                    let mut localize = |vx: P2<T>|{
+                        let ind = *indexmap.entry(vx).or_insert_with(||{
+                            vertices.push(vx);
+                            vertices.len() - 1
+                        });
+                        indices.push(ind);
+                    };
+                    // the clipped triangle is convex, so a fan triangulates it
+                    for k in 1..clipped.len() - 1{
+                        let (a,b,c) = (clipped[0], clipped[k], clipped[k + 1]);
+                        let (fa,fb,fc) = (to_f64(a), to_f64(b), to_f64(c));
+                        if (fb.0 - fa.0) * (fc.1 - fa.1) == (fc.0 - fa.0) * (fb.1 - fa.1) { continue; } // nothing left after rounding
+                        localize(a);
+                        localize(b);
+                        localize(c);
                     }
-                };
-                localize(va);
-                localize(vb);
-                localize(vc);
-                localgrid.insert((cx,cy), (vertices,indices,indexmap));
+                }
             }
         }
         for ((cx,cy),(vertices,indices,_)) in localgrid{
-            let mut pt = PolyTriangle{
-                vertices,
-                indices,
-                style: polygon.style,
-                bb: T::start_box(),
-            };
-            pt.stretch_bb();
-            grid[cy * cuts_usize + cx].push(pt);
+            if indices.is_empty() { continue; }
+            for mut pt in batches(&vertices, &indices, polygon.style, T::start_box()){
+                pt.stretch_bb();
+                cells.entry((cx,cy)).or_default().push(pt);
+            }
         }
     }
-    // Collect all the chunks into a vector
-    let cuts_u64 = cuts_usize as u64;
-    let mut chunks = Vec::new();
-    for (i,vec) in grid.into_iter().enumerate(){
-        let i = i as u64;
-        let x = i % cuts_u64;
-        let y = i / cuts_u64;
-        chunks.push((x,y,vec));
-    }
-    chunks
+    cells
 }
 
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::triangulate::MAX_VERTICES;
+
+    #[test]
+    fn douglas_peucker_keeps_the_ends(){
+        let line = [(0.0,0.0),(1.0,0.1),(2.0,-0.1),(3.0,0.1),(4.0,0.0)];
+        assert_eq!(douglas_peucker(&line, 1.0), vec![(0.0,0.0),(4.0,0.0)]);
+        assert_eq!(douglas_peucker(&line, 0.0), line.to_vec());
+    }
+
+    #[test]
+    fn douglas_peucker_tolerance_boundary(){
+        // the middle point is exactly 1 from the line, only further than the tolerance is kept
+        let line = [(0,0),(5,1),(10,0)];
+        assert_eq!(douglas_peucker(&line, 1.0), vec![(0,0),(10,0)]);
+        assert_eq!(douglas_peucker(&line, 0.99), line.to_vec());
+    }
+
+    #[test]
+    fn douglas_peucker_closed_ring(){
+        // the ends are the same point, the furthest point from it is kept
+        let ring = [(0,0),(10,0),(10,10),(0,10),(0,0)];
+        assert_eq!(douglas_peucker(&ring, 1.0), ring.to_vec());
+        let thin = [(0,0),(10,0),(10,1),(0,1),(0,0)];
+        assert_eq!(douglas_peucker(&thin, 5.0), vec![(0,0),(10,1),(0,0)]);
+    }
+
+    #[test]
+    fn short_lines_are_kept(){
+        for line in [vec![], vec![(1.0,1.0)], vec![(1.0,1.0),(2.0,2.0)]]{
+            assert_eq!(douglas_peucker(&line, 10.0), line);
+            assert_eq!(visvalingam(&line, 10.0), line);
+        }
+    }
+
+    #[test]
+    fn visvalingam_keeps_the_ends(){
+        let line = [(0.0,0.0),(1.0,0.1),(2.0,-0.1),(3.0,0.1),(4.0,0.0)];
+        assert_eq!(visvalingam(&line, 10.0), vec![(0.0,0.0),(4.0,0.0)]);
+        let ring = [(0,0),(10,0),(10,10),(0,10),(0,0)];
+        assert_eq!(visvalingam(&ring, 1.0), ring.to_vec());
+        assert_eq!(visvalingam(&ring, 100.0), vec![(0,0),(0,0)]);
+    }
+
+    #[test]
+    fn visvalingam_tolerance_boundary(){
+        // the triangle has area 4, points are left out below the tolerance squared
+        let line = [(0,0),(2,2),(4,0)];
+        assert_eq!(visvalingam(&line, 2.0), line.to_vec());
+        assert_eq!(visvalingam(&line, 2.01), vec![(0,0),(4,0)]);
+    }
+
+    #[test]
+    fn visvalingam_equal_areas_go_in_order(){
+        // every triangle has area 1, the first point goes first and makes its neighbours bigger
+        let line = [(0,0),(1,1),(2,0),(3,1),(4,0)];
+        assert_eq!(visvalingam(&line, 1.2), vec![(0,0),(3,1),(4,0)]);
+    }
+
+    #[test]
+    fn visvalingam_keeps_nan_areas(){
+        let line = [(0.0,0.0),(1.0,0.0),(2.0,0.0),(3.0,f64::NAN),(4.0,0.0),(5.0,0.0)];
+        let xs = visvalingam(&line, 1.0).iter().map(|p| p.0).collect::<Vec<_>>();
+        assert_eq!(xs, vec![0.0,2.0,3.0,4.0,5.0]);
+    }
+
+    fn topology(lines: &[&[P2<f64>]], tolerance: f64) -> Vec<Vec<P2<f64>>>{
+        let rings = lines.iter().map(|l| Ring{ points: l.to_vec() }).collect::<Vec<_>>();
+        simplify_topology(tolerance, rings, &mut Logger::default()).into_iter().map(|r| r.points).collect()
+    }
+
+    #[test]
+    fn topology_rejects_a_crossing_simplification(){
+        // dp would make the first line a straight line through the second
+        let a: &[P2<f64>] = &[(0.0,0.0),(5.0,3.0),(10.0,0.0)];
+        let b: &[P2<f64>] = &[(5.0,1.0),(5.0,-2.0)];
+        assert_eq!(douglas_peucker(a, 5.0).len(), 2);
+        assert_eq!(topology(&[a,b], 5.0), vec![a.to_vec(), b.to_vec()]);
+        // far enough away it is simplified
+        let c: &[P2<f64>] = &[(5.0,-1.0),(5.0,-2.0)];
+        assert_eq!(topology(&[a,c], 5.0)[0], vec![(0.0,0.0),(10.0,0.0)]);
+    }
+
+    #[test]
+    fn topology_leaves_crossing_input(){
+        let a: &[P2<f64>] = &[(0.0,0.0),(10.0,0.0)];
+        let b: &[P2<f64>] = &[(5.0,-1.0),(5.0,1.0)];
+        assert_eq!(topology(&[a,b], 5.0), vec![a.to_vec(), b.to_vec()]);
+    }
+
+    #[test]
+    fn topology_points_on_the_segment(){
+        // the points in between are on the simplified segment, so splitting there does not help
+        let b: &[P2<f64>] = &[(5.0,-1.0),(5.0,1.0)];
+        for a in [&[(0.0,0.0),(5.0,0.0),(5.0,0.0),(10.0,0.0)][..], &[(0.0,0.0),(3.0,0.0),(7.0,0.0),(10.0,0.0)][..]]{
+            assert_eq!(topology(&[a,b], 5.0), vec![vec![(0.0,0.0),(10.0,0.0)], b.to_vec()]);
+        }
+    }
+
+    #[test]
+    fn topology_many_crossings(){
+        // a zigzag over many short lines, dp would make it a straight line through all of them
+        let zigzag = (0..=2000).map(|i| (i as f64, if i % 2 == 0 { 0.0 } else { 1.0 })).collect::<Vec<_>>();
+        let posts = (0..1000).map(|i| vec![(2.0 * i as f64 + 1.0, -0.5), (2.0 * i as f64 + 1.0, 0.5)]).collect::<Vec<_>>();
+        let mut lines = vec![&zigzag[..]];
+        lines.extend(posts.iter().map(|p| &p[..]));
+        let simple = topology(&lines, 10.0).swap_remove(0);
+        assert!(simple.len() > 2);
+        for w in simple.windows(2){
+            assert!(posts.iter().all(|p| !segments_cross(w[0], w[1], p[0], p[1])));
+        }
+    }
+
+    #[test]
+    fn crossing_segments(){
+        let cross = |a, b, c, d| segments_cross(a, b, c, d);
+        assert!(cross((0.0,0.0), (2.0,2.0), (0.0,2.0), (2.0,0.0)));
+        // touching in an end or in the middle is not crossing
+        assert!(!cross((0.0,0.0), (2.0,0.0), (2.0,0.0), (3.0,1.0)));
+        assert!(!cross((0.0,0.0), (2.0,0.0), (1.0,0.0), (1.0,1.0)));
+        // neither is lying on top of each other
+        assert!(!cross((0.0,0.0), (2.0,0.0), (1.0,0.0), (3.0,0.0)));
+        assert!(!cross((0.0,0.0), (1.0,0.0), (2.0,0.0), (3.0,0.0)));
+    }
+
+    fn sline(style: usize, points: &[P2<u32>]) -> StyledLine<u32>{
+        let mut line = StyledLine{ points: points.to_vec(), style, bb: u32::start_box() };
+        line.stretch_bb();
+        line
+    }
+
+    fn merged(lines: Vec<StyledLine<u32>>) -> Vec<(usize,Vec<P2<u32>>)>{
+        let mut res = optimize_lines(lines).into_iter().map(|l| (l.style, l.points)).collect::<Vec<_>>();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn merge_end_to_start(){
+        let lines = vec![sline(0, &[(0,0),(1,0)]), sline(0, &[(1,0),(2,1)]), sline(0, &[(2,1),(3,1)])];
+        assert_eq!(merged(lines), vec![(0, vec![(0,0),(1,0),(2,1),(3,1)])]);
+        let merged = optimize_lines(vec![sline(0, &[(0,0),(1,0)]), sline(0, &[(1,0),(2,1)])]);
+        assert_eq!(merged[0].bb, ((0,0,u32::MAX),(2,1,u32::MIN)));
+    }
+
+    #[test]
+    fn merge_reversed_lines(){
+        // both start in the same point and both end in the same point
+        let starts = vec![sline(0, &[(1,0),(0,0)]), sline(0, &[(1,0),(2,0)])];
+        let ends = vec![sline(0, &[(0,0),(1,0)]), sline(0, &[(2,0),(1,0)])];
+        for lines in [starts, ends]{
+            let res = merged(lines);
+            assert_eq!(res.len(), 1);
+            let mut points = res[0].1.clone();
+            if points[0] != (0,0) { points.reverse(); }
+            assert_eq!(points, vec![(0,0),(1,0),(2,0)]);
+        }
+    }
+
+    #[test]
+    fn merge_closed_loops(){
+        // a loop on its own stays as it is, two halves become one loop
+        let square = [(0,0),(1,0),(1,1),(0,1),(0,0)];
+        assert_eq!(merged(vec![sline(0, &square)]), vec![(0, square.to_vec())]);
+        let res = merged(vec![sline(0, &[(0,0),(1,0),(1,1)]), sline(0, &[(1,1),(0,1),(0,0)])]);
+        assert_eq!(res.len(), 1);
+        let points = &res[0].1;
+        assert_eq!(points.len(), 5);
+        assert_eq!(points.first(), points.last());
+        let at = points.iter().position(|p| *p == (0,0)).unwrap();
+        assert_eq!(points[at..].iter().chain(&points[1..=at]).copied().collect::<Vec<_>>(), square.to_vec());
+    }
+
+    #[test]
+    fn merge_only_the_same_style(){
+        let lines = vec![sline(0, &[(0,0),(1,0)]), sline(1, &[(1,0),(2,0)]), sline(0, &[(1,0),(1,1)])];
+        assert_eq!(merged(lines), vec![(0, vec![(0,0),(1,0),(1,1)]), (1, vec![(1,0),(2,0)])]);
+        // heightlines merge by height
+        let hline = |z, points: Vec<P2<u32>>|{
+            let mut line = ShapeZ{ points, z, bb: u32::start_box() };
+            line.stretch_bb();
+            line
+        };
+        let res = optimize_lines(vec![hline(5, vec![(0,0),(1,0)]), hline(10, vec![(1,0),(2,0)])]);
+        assert_eq!(res.len(), 2);
+    }
+
+    // 3 × 3 cells of 10 by 10
+    fn grid() -> Grid{
+        Grid::new(3, &((0.0,0.0,0.0),(29.0,29.0,0.0)))
+    }
+
+    fn pieces(points: &[P2<f64>]) -> Vec<Piece>{
+        let mut pieces = clip_line(&grid(), points);
+        pieces.sort_by(|a,b| a.0.cmp(&b.0).then(a.1[0].partial_cmp(&b.1[0]).unwrap()));
+        pieces
+    }
+
+    #[test]
+    fn liang_barsky_clips(){
+        let rect = ((0.0,0.0),(10.0,10.0));
+        assert_eq!(liang_barsky((-5.0,5.0), (15.0,5.0), rect), Some((0.25,0.75)));
+        assert_eq!(liang_barsky((-5.0,-1.0), (15.0,-1.0), rect), None);
+        assert_eq!(liang_barsky((1.0,1.0), (2.0,2.0), rect), Some((0.0,1.0)));
+    }
+
+    #[test]
+    fn clip_through_a_cell_without_points(){
+        // the middle cell has none of the points of the line
+        assert_eq!(pieces(&[(5.0,12.0),(12.0,5.0)]), vec![
+            ((0,0), vec![(7.0,10.0),(10.0,7.0)]),
+            ((0,1), vec![(5.0,12.0),(7.0,10.0)]),
+            ((1,0), vec![(10.0,7.0),(12.0,5.0)]),
+        ]);
+        assert_eq!(pieces(&[(5.0,5.0),(25.0,5.0)]).len(), 3);
+    }
+
+    #[test]
+    fn clip_on_a_cell_border(){
+        // on the border between two rows it goes in the row the points are in
+        assert_eq!(pieces(&[(5.0,10.0),(15.0,10.0)]), vec![
+            ((0,1), vec![(5.0,10.0),(10.0,10.0)]),
+            ((1,1), vec![(10.0,10.0),(15.0,10.0)]),
+        ]);
+        // touching a corner does not make a piece
+        assert_eq!(pieces(&[(5.0,15.0),(15.0,5.0)]).len(), 2);
+    }
+
+    #[test]
+    fn clip_leaves_and_comes_back(){
+        assert_eq!(pieces(&[(5.0,5.0),(15.0,5.0),(15.0,8.0),(5.0,8.0)]), vec![
+            ((0,0), vec![(5.0,5.0),(10.0,5.0)]),
+            ((0,0), vec![(10.0,8.0),(5.0,8.0)]),
+            ((1,0), vec![(10.0,5.0),(15.0,5.0),(15.0,8.0),(10.0,8.0)]),
+        ]);
+    }
+
+    #[test]
+    fn cut_rounds_and_drops_grazing_pieces(){
+        // the piece in cell 0,0 goes from 9.5,10 to 10,9.67, both are 10,10 when rounded
+        assert_eq!(pieces(&[(8.0,11.0),(11.0,9.0)]).len(), 3);
+        let shape = ShapeZ{ points: vec![(8u32,11u32),(11,9)], z: 5, bb: u32::start_box() };
+        let mut cells = grid().number(cut_shapes(&grid(), &[shape]));
+        cells.sort_by_key(|(x,y,_)| (*x,*y));
+        let cells = cells.into_iter().map(|(x,y,shapes)| (x, y, shapes[0].points.clone())).collect::<Vec<_>>();
+        assert_eq!(cells, vec![(0, 1, vec![(8,11),(10,10)]), (1, 0, vec![(10,10),(11,9)])]);
+    }
+
+    // the area of every cell the triangles are clipped to
+    fn clipped_areas(grid: &Grid, tri: PolyTriangle<u32>) -> Vec<((u64,u64),f64)>{
+        let mut areas = clip_triangles(grid, &[tri]).into_iter().map(|(cell,pts)|{
+            let area = pts.iter().flat_map(|pt| pt.indices.chunks_exact(3).map(move |t|{
+                let [a,b,c] = [0,1,2].map(|k| to_f64(pt.vertices[t[k] as usize]));
+                orientation(a, b, c).abs() / 2.0
+            })).sum();
+            (cell, area)
+        }).collect::<Vec<_>>();
+        areas.sort_by_key(|(cell,_)| *cell);
+        areas
+    }
+
+    fn triangle(vertices: Vec<P2<u32>>, indices: Vec<u16>) -> PolyTriangle<u32>{
+        PolyTriangle{ vertices, indices, style: 0, bb: u32::start_box() }
+    }
+
+    #[test]
+    fn clip_a_triangle_over_a_whole_cell(){
+        // none of the corners are in the middle cell, it is covered completely
+        let square = clip_polygon(&[(0.0,0.0),(60.0,0.0),(0.0,60.0)], ((10.0,10.0),(20.0,20.0)));
+        assert_eq!(square.len(), 4);
+        let areas = clipped_areas(&grid(), triangle(vec![(0,0),(60,0),(0,60)], vec![0,1,2]));
+        assert!(areas.contains(&((1,1), 100.0)));
+        // the grid is 30 by 30 and all of it is in the triangle
+        assert_eq!(areas.iter().map(|(_,a)| a).sum::<f64>(), 900.0);
+    }
+
+    #[test]
+    fn clip_a_triangle_on_a_cell_border(){
+        // the left edge is on the border, the cell to the left gets nothing
+        let left = clip_polygon(&[(10.0,0.0),(20.0,0.0),(10.0,10.0)], ((0.0,0.0),(10.0,10.0)));
+        assert!(left.iter().all(|p| p.0 == 10.0));
+        let areas = clipped_areas(&grid(), triangle(vec![(10,0),(20,0),(10,10)], vec![0,1,2]));
+        assert_eq!(areas, vec![((1,0), 50.0)]);
+    }
+
+    #[test]
+    fn clip_to_more_than_max_vertices(){
+        // a strip of 2 × 32768 vertices, clipping adds 2 vertices for every pair of triangles
+        let n = (MAX_VERTICES / 2) as u32;
+        let vertices = (0..n).flat_map(|i| [(2 * i, 0), (2 * i, 100)]).collect::<Vec<_>>();
+        let indices = (0..n as u16 - 1).flat_map(|i| [2 * i, 2 * i + 2, 2 * i + 1, 2 * i + 2, 2 * i + 3, 2 * i + 1]).collect::<Vec<_>>();
+        let strip = || triangle(vertices.clone(), indices.clone());
+        // one column of two cells, the lower one is 51 high
+        let grid = Grid::new(2, &((0u32,0u32,0u32),(200_000,100,0)));
+        let cells = clip_triangles(&grid, &[strip()]);
+        let below = &cells[&(0,0)];
+        assert!(below.len() > 1);
+        for pt in below{
+            assert!(pt.vertices.len() <= MAX_VERTICES);
+            assert!(pt.indices.iter().all(|i| (*i as usize) < pt.vertices.len()));
+        }
+        let width = (2 * (n - 1)) as f64;
+        let areas = clipped_areas(&grid, strip());
+        assert_eq!(areas, vec![((0,0), width * 51.0), ((0,1), width * 49.0)]);
+    }
+
+    // an open ring of a square with its corner at (x,y)
+    fn square_ring((x,y): P2<u32>, side: u32) -> Vec<P3<u32>>{
+        vec![(x,y,0), (x + side,y,0), (x + side,y + side,0), (x,y + side,0)]
+    }
+
+    fn polygon(outer: Vec<P3<u32>>, inners: Vec<Vec<P3<u32>>>, style: usize) -> PolygonZ<u32>{
+        let mut p = PolygonZ{ outers: vec![outer], inners, bb: u32::start_box(), style };
+        p.stretch_bb();
+        p
+    }
+
+    #[test]
+    fn simplify_polygons_culls_small_ones(){
+        let polygons = vec![
+            polygon(square_ring((0,0), 100), vec![square_ring((10,10), 10)], 0),
+            polygon(square_ring((200,0), 10), Vec::new(), 1),
+        ];
+        let kept = simplify_polygons(Simplify::None, 1.0, 200.0, &polygons, &mut Logger::default());
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].style, 0);
+        assert!(kept[0].inners.is_empty());
+        let all = simplify_polygons(Simplify::None, 1.0, 0.0, &polygons, &mut Logger::default());
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].inners.len(), 1);
+    }
+
+    #[test]
+    fn simplify_polygons_dedups_rings(){
+        let mut ring = square_ring((0,0), 100);
+        ring.insert(1, (0,0,0));
+        ring.insert(3, (100,0,0));
+        let kept = simplify_polygons(Simplify::None, 1.0, 0.0, &[polygon(ring, Vec::new(), 0)], &mut Logger::default());
+        assert_eq!(kept[0].outers[0], square_ring((0,0), 100));
+    }
+
+    #[test]
+    fn parse_simplify(){
+        assert_eq!("dp".parse::<Simplify>().unwrap(), Simplify::DouglasPeucker);
+        assert_eq!("vw".parse::<Simplify>().unwrap(), Simplify::Visvalingam);
+        assert!("rdp".parse::<Simplify>().is_err());
+    }
+}
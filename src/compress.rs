@@ -1,89 +1,138 @@
 use crate::{
     data::{
-        PolygonZ, Vvec, StretchableBB, get_global_bb, UpdateableBB, ShapeZ, P2, P3, VvP4, StyledLine
+        PolygonZ, Vvec, StretchableBB, get_global_bb, UpdateableBB, ShapeZ, P3, BB, VvP4, StyledLine
     },
-    info::CompTarget,
+    info::Infos,
     logger::*,
+    decode::FormatKind,
+    header::write_header,
+    encoding::{ Encoding, Encodable, Layout, write_bb },
     triangulate::triangulate,
     triangulate::PolyTriangle,
 };
 
 use bin_buffer::*;
-use ass::*;
 
-// Perform compression by using a range offset and multiplier
-pub trait OffScaleFromU64{
-    fn offscale(x: f64, o: u64, m: u64) -> Self;
+// Perform compression by using a range offset and multiplier: q = (w - offset) * multi
+fn quantize(w: f64, offset: f64, multi: u64) -> u32{
+    ((w - offset) * multi as f64).round() as u32
 }
 
-// Generic implementation
-impl<T> OffScaleFromU64 for T
-    where
-        u64: Ass<T>,
-{
-    fn offscale(x: f64, o: u64, m: u64) -> Self{
-        (((x - o as f64) * m as f64).round() as u64).ass()
+// Quantizes every axis with its own offset and multiplier and keeps the largest error of every axis,
+// the error of the values that are written and not of a separate run
+struct Quantizer{
+    offset: [f64;3],
+    multi: [u64;3],
+    error: [f64;3],
+}
+
+impl Quantizer{
+    fn new(infos: &Infos) -> Self{
+        let (ox,oy,oz) = infos.offset;
+        let (mx,my,mz) = infos.multi;
+        Self{
+            offset: [ox as f64, oy as f64, oz as f64],
+            multi: [mx, my, mz],
+            error: [0.0;3],
+        }
+    }
+
+    fn axis(&mut self, axis: usize, w: f64) -> u32{
+        let (offset, multi) = (self.offset[axis], self.multi[axis]);
+        let q = quantize(w, offset, multi);
+        let back = q as f64 / multi as f64 + offset;
+        self.error[axis] = self.error[axis].max((w - back).abs());
+        q
+    }
+
+    fn error(&self) -> P3<f64>{
+        (self.error[0], self.error[1], self.error[2])
     }
 }
 
-// We must be able to cast a bounding box to the right type as well
-fn bb_to_t<T>(bb: (P3<f64>,P3<f64>)) -> (P3<T>,P3<T>)
-    where
-        u64: Ass<T>,
-{
-    (
-        (((bb.0).0 as u64).ass(), ((bb.0).1 as u64).ass(), ((bb.0).2 as u64).ass()),
-        (((bb.1).0 as u64).ass(), ((bb.1).1 as u64).ass(), ((bb.1).2 as u64).ass()),
-    )
+// The boundingboxes are computed again after quantizing
+fn zero_bb() -> BB<u32>{
+    ((0,0,0),(0,0,0))
+}
+
+// Floats that are not compressed are always written raw
+pub fn target_encoding(infos: &Infos, encoding: Encoding) -> Encoding{
+    if infos.floats() { Encoding::Raw } else { encoding }
 }
 
 // Ability to be convertable to a compressed buffer
-pub trait Compressable
+pub trait Compressable: Sized
 {
-    fn compress(self, infos: (u64,u64,u64,u64,CompTarget), logger: &mut Logger) -> Buffer;
+    // the kind written in the header
+    const KIND: FormatKind;
+
+    // write the quantization, boundingbox and shapes after what is already in the buffer
+    // gives back the largest error of every axis of the written values, floats have none
+    fn compress_into(self, infos: Infos, encoding: Encoding, buffer: &mut Buffer, logger: &mut Logger) -> P3<f64>;
+
+    fn compress(self, infos: Infos, encoding: Encoding, logger: &mut Logger) -> Buffer{
+        self.compress_measured(infos, encoding, logger).0
+    }
+
+    fn compress_measured(self, infos: Infos, encoding: Encoding, logger: &mut Logger) -> (Buffer,P3<f64>){
+        let mut buffer = Vec::new();
+        write_header(Self::KIND, target_encoding(&infos, encoding).flags(), &mut buffer);
+        let error = self.compress_into(infos, encoding, &mut buffer, logger);
+        (buffer, error)
+    }
 }
 
 // Ability to be transformed into triangles and than compressed into buffer
-pub trait TriangleCompressable{
-    fn triangle_compress(self, infos: (u64,u64,u64,u64,CompTarget), logger: &mut Logger) -> Buffer;
+pub trait TriangleCompressable: Sized{
+    const KIND: FormatKind;
+
+    fn triangle_compress_into(self, infos: Infos, encoding: Encoding, buffer: &mut Buffer, logger: &mut Logger) -> P3<f64>;
+
+    fn triangle_compress(self, infos: Infos, encoding: Encoding, logger: &mut Logger) -> Buffer{
+        self.triangle_compress_measured(infos, encoding, logger).0
+    }
+
+    fn triangle_compress_measured(self, infos: Infos, encoding: Encoding, logger: &mut Logger) -> (Buffer,P3<f64>){
+        let mut buffer = Vec::new();
+        write_header(Self::KIND, target_encoding(&infos, encoding).flags(), &mut buffer);
+        let error = self.triangle_compress_into(infos, encoding, &mut buffer, logger);
+        (buffer, error)
+    }
 }
 
 // Macro that builds a generic implementation of Compressable
+// The kind and the encoding are written in the header of the buffer by the trait
 macro_rules! ImplCompressable {
-    ($tname:ident,$tfname:ident,$btype:ty,$fname:ident,$trans:ident) => {
+    ($tname:ident,$tfname:ident,$btype:ty,$fname:ident,$trans:ident,$kind:expr) => {
         impl $tname for $btype
         {
+            const KIND: FormatKind = $kind;
+
             fn $tfname
-                (mut self, (mx,my,mz,multi,target): (u64,u64,u64,u64,CompTarget), logger: &mut Logger) -> Buffer{
-                    let mut buffer = Vec::new();
-                    (mx,my,mz,multi).into_buffer(&mut buffer);
-                    macro_rules! TargetIntoBuffer {
-                        ($ttype:ident) => {
-                            let tsize = std::mem::size_of::<$ttype>() as u8;
-                            tsize.into_buffer(&mut buffer);
-                            let mut ns = $fname::<$ttype>(self,mx,my,multi);
-                            ns.iter_mut().for_each(|x| x.stretch_bb());
-                            ns.iter_mut().for_each(|x| x.update_bb());
-                            let bb = get_global_bb(&ns);
-                            println!("Global Boundingbox: {:?}", bb);
-                            // transform?
-                            let ns = $trans(ns,logger);
-                            bb.into_buffer(&mut buffer);
-                            ns.into_buffer(&mut buffer);
-                        };
-                    }
-                    match target{
-                        CompTarget::U8 => { TargetIntoBuffer!(u8); },
-                        CompTarget::U16 => { TargetIntoBuffer!(u16); },
-                        CompTarget::U32 => { TargetIntoBuffer!(u32); },
-                        CompTarget::NONE => {
-                            self.iter_mut().for_each(|x| x.stretch_bb());
-                            let bb = get_global_bb(&self);
-                            bb.into_buffer(&mut buffer);
-                            self.into_buffer(&mut buffer);
-                        },
+                (mut self, infos: Infos, encoding: Encoding, buffer: &mut Buffer, logger: &mut Logger) -> P3<f64>{
+                    let layout = Layout{ encoding: target_encoding(&infos, encoding), sizes: infos.sizes() };
+                    let (ox,oy,oz) = infos.offset;
+                    // the offsets can be below zero, they are written as the bits of an i64
+                    (ox as u64,oy as u64,oz as u64).into_buffer(buffer);
+                    infos.multi.into_buffer(buffer);
+                    layout.sizes.into_buffer(buffer);
+                    if infos.floats(){
+                        self.iter_mut().for_each(|x| x.stretch_bb());
+                        let bb = get_global_bb(&self);
+                        bb.into_buffer(buffer);
+                        self.into_buffer(buffer);
+                        return (0.0,0.0,0.0);
                     }
-                    buffer
+                    let (mut ns, error) = $fname(self, &infos);
+                    ns.iter_mut().for_each(|x| x.stretch_bb());
+                    ns.iter_mut().for_each(|x| x.update_bb());
+                    let bb = get_global_bb(&ns);
+                    println!("Global Boundingbox: {:?}", bb);
+                    // transform?
+                    let ns = $trans(ns,logger);
+                    write_bb(bb, layout.sizes, buffer);
+                    ns.encode(layout, buffer);
+                    error
                 }
         }
     };
@@ -93,54 +142,43 @@ macro_rules! ImplCompressable {
 fn id<T>(v: T, _logger: &mut Logger) -> T { v }
 
 // Actually implement it for the needed types
-ImplCompressable!(Compressable,compress,Vec<ShapeZ<f64>>,compress_shapez_into,id);
-ImplCompressable!(Compressable,compress,Vec<PolygonZ<f64>>,compress_polygonz_into,id);
-ImplCompressable!(Compressable,compress,Vec<PolyTriangle<u32>>,compress_polytriangle_into,id);
-ImplCompressable!(Compressable,compress,Vec<StyledLine<u32>>,compress_styledline_into,id);
-ImplCompressable!(TriangleCompressable,triangle_compress,Vec<PolygonZ<f64>>,compress_polygonz_into,triangulate);
-
-// Take ShapeZ of f64 and turn into ShapeZ of given T
-// Used to implement Compressable
-pub fn compress_shapez_into<T: Bufferable>
-    (shapezs: Vec<ShapeZ<f64>>, mx: u64, my: u64, multi: u64) -> Vec<ShapeZ<T>>
-    where
-        u64: Ass<T>,
-{
+ImplCompressable!(Compressable,compress_into,Vec<ShapeZ<f64>>,compress_shapez_into,id,FormatKind::Height);
+ImplCompressable!(Compressable,compress_into,Vec<PolygonZ<f64>>,compress_polygonz_into,id,FormatKind::Polygonz);
+ImplCompressable!(Compressable,compress_into,Vec<PolyTriangle<u32>>,compress_polytriangle_into,id,FormatKind::PolyChunk);
+ImplCompressable!(Compressable,compress_into,Vec<StyledLine<u32>>,compress_styledline_into,id,FormatKind::GeolineChunk);
+ImplCompressable!(TriangleCompressable,triangle_compress_into,Vec<PolygonZ<f64>>,compress_polygonz_into,triangulate,FormatKind::Triangles);
+
+// Take ShapeZ of f64 and quantize every axis with its own offset and multiplier
+// Used to implement Compressable, gives back the largest error of every axis too
+pub fn compress_shapez_into(shapezs: Vec<ShapeZ<f64>>, infos: &Infos) -> (Vec<ShapeZ<u32>>,P3<f64>){
+    let mut q = Quantizer::new(infos);
     let mut nshapezs = Vec::new();
     for shape in shapezs{
         let mut vec = Vec::new();
         for (x,y) in shape.points{
-            let xx = T::offscale(x, mx, multi);
-            let yy = T::offscale(y, my, multi);
-            vec.push((xx,yy));
+            vec.push((q.axis(0, x), q.axis(1, y)));
         }
         nshapezs.push(ShapeZ{
             points: vec,
-            z: (shape.z as u64).ass(),
-            bb: bb_to_t::<T>(shape.bb),
+            z: q.axis(2, shape.z),
+            bb: zero_bb(),
         });
     }
-    nshapezs
+    (nshapezs, q.error())
 }
 
 // Same as above but for PolygonZ
-pub fn compress_polygonz_into<T: Bufferable>
-    (polygonzs: Vec<PolygonZ<f64>>, mx: u64, my: u64, multi: u64) -> Vec<PolygonZ<T>>
-    where
-        u64: Ass<T>,
-{
+pub fn compress_polygonz_into(polygonzs: Vec<PolygonZ<f64>>, infos: &Infos) -> (Vec<PolygonZ<u32>>,P3<f64>){
+    let mut q = Quantizer::new(infos);
     let mut npolygonzs = Vec::new();
     for pz in polygonzs{
         let style = pz.style;
-        let build = |old: Vvec<P3<f64>>|{
+        let mut build = |old: Vvec<P3<f64>>|{
             let mut col = Vec::new();
             for sub in old{
                 let mut vec = Vec::new();
                 for (x,y,z) in sub{
-                    let xx = T::offscale(x, mx, multi);
-                    let yy = T::offscale(y, my, multi);
-                    let zz = T::offscale(z, 0, 0); //TODO
-                    vec.push((xx,yy,zz));
+                    vec.push((q.axis(0, x), q.axis(1, y), q.axis(2, z)));
                 }
                 col.push(vec);
             }
@@ -149,66 +187,45 @@ pub fn compress_polygonz_into<T: Bufferable>
         npolygonzs.push(PolygonZ{
             inners: build(pz.inners),
             outers: build(pz.outers),
-            bb: bb_to_t::<T>(pz.bb),
+            bb: zero_bb(),
             style,
         });
     }
-    npolygonzs
+    (npolygonzs, q.error())
 }
 
 // styles lines start as u32
-pub fn compress_styledline_into<T: Bufferable>
-    (slines: Vec<StyledLine<u32>>, mx: u64, my: u64, multi: u64) -> Vec<StyledLine<T>>
-    where
-        u64: Ass<T>,
-{
+pub fn compress_styledline_into(slines: Vec<StyledLine<u32>>, infos: &Infos) -> (Vec<StyledLine<u32>>,P3<f64>){
+    let mut q = Quantizer::new(infos);
     let mut nslines = Vec::new();
     for sl in slines{
-        let style = sl.style;
-        let build = |old: Vec<P2<u32>>|{
-            let mut vec = Vec::new();
-            for (x,y) in old{
-                let xx = T::offscale(x as f64, mx, multi);
-                let yy = T::offscale(y as f64, my, multi);
-                vec.push((xx,yy));
-            }
-            vec
-        };
-        let ((a,b,c),(d,e,f)) = sl.bb;
-        let fbb = ((a as f64, b as f64, c as f64),(d as f64, e as f64, f as f64));
+        let points = sl.points.into_iter().map(|(x,y)| (q.axis(0, x as f64), q.axis(1, y as f64))).collect();
         nslines.push(StyledLine{
-            points: build(sl.points),
-            bb: bb_to_t::<T>(fbb),
-            style,
+            points,
+            bb: zero_bb(),
+            style: sl.style,
         });
     }
-    nslines
+    (nslines, q.error())
 }
 
 // Start with u32 just like styled lines
-pub fn compress_polytriangle_into<T: Bufferable>
-    (polytriangles: Vec<PolyTriangle<u32>>, mx: u64, my: u64, multi: u64) -> Vec<PolyTriangle<T>>
-    where
-        u64: Ass<T>,
-{
+pub fn compress_polytriangle_into(polytriangles: Vec<PolyTriangle<u32>>, infos: &Infos) -> (Vec<PolyTriangle<u32>>,P3<f64>){
+    let mut q = Quantizer::new(infos);
     let mut npts = Vec::new();
     for pt in polytriangles{
         let mut vec = Vec::new();
         for (x,y) in pt.vertices{
-            let xx = T::offscale(x as f64, mx, multi);
-            let yy = T::offscale(y as f64, my, multi);
-            vec.push((xx,yy));
+            vec.push((q.axis(0, x as f64), q.axis(1, y as f64)));
         }
-        let ((a,b,c),(d,e,f)) = pt.bb;
-        let fbb = ((a as f64, b as f64, c as f64),(d as f64, e as f64, f as f64));
         npts.push(PolyTriangle{
             vertices: vec,
             indices: pt.indices,
             style: pt.style,
-            bb: bb_to_t::<T>(fbb),
+            bb: zero_bb(),
         });
     }
-    npts
+    (npts, q.error())
 }
 
 // Specific commpression method for heightmaps
@@ -224,7 +241,7 @@ pub fn compress_heightmap(shapes: VvP4, logger: &mut Logger)
         let mut npoints = Vec::new();
         let z = shape[0].2;
         for point in shape{
-            if (point.2 - z).abs() > std::f64::EPSILON{
+            if (point.2 - z).abs() > f64::EPSILON{
                 logger.log(Issue::TwoPlusZInHeightline);
                 continue 'outer;
             }
@@ -240,3 +257,115 @@ pub fn compress_heightmap(shapes: VvP4, logger: &mut Logger)
     shapezs
 }
 
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::{
+        info::{ info_package, Infos, CompTarget, meets_tolerance },
+        decode::{ decode_buffer, Decoded, Quantization },
+    };
+
+    fn triangle((x,y,z): P3<f64>, side: f64) -> PolygonZ<f64>{
+        PolygonZ{
+            outers: vec![vec![(x,y,z), (x + side,y,z), (x,y + side,z), (x,y,z)]],
+            inners: Vec::new(),
+            bb: ((0.0,0.0,0.0),(0.0,0.0,0.0)),
+            style: 0,
+        }
+    }
+
+    fn dequantize(q: &Quantization, (x,y,z): P3<u32>) -> P3<f64>{
+        let (qx,qy) = q.dequantize((x,y));
+        (qx, qy, z as f64 / q.multi.2 as f64 + q.mz as f64)
+    }
+
+    // the largest error of every axis of the points and their quantized values turned back
+    fn max_error(original: &[P3<f64>], quantized: &[P3<u32>], infos: &Infos) -> P3<f64>{
+        original.iter().zip(quantized).fold((0.0,0.0,0.0), |(ex,ey,ez),((x,y,z),(qx,qy,qz))|{
+            let (bx,by,bz) = infos.dequantize((*qx as f64, *qy as f64, *qz as f64));
+            (ex.max((x - bx).abs()), ey.max((y - by).abs()), ez.max((z - bz).abs()))
+        })
+    }
+
+    // compress and decode the polygons, gives the quantization and the points turned back
+    fn round_trip(polys: Vec<PolygonZ<f64>>, infos: Infos, encoding: Encoding) -> (Quantization, Vec<P3<f64>>){
+        let buffer = polys.compress(infos, encoding, &mut Logger::default());
+        let mut buf = ReadBuffer::from_raw(buffer);
+        let c = match decode_buffer(None, &mut buf, "test"){
+            Ok(Decoded::Polygonz(c)) => c,
+            _ => panic!("not read back as polygonz"),
+        };
+        let points = c.shapes.iter().flat_map(|p| p.outers.concat()).map(|p| dequantize(&c.quant, p)).collect();
+        (c.quant, points)
+    }
+
+    // west and south of the origin, the offsets are below zero
+    #[test]
+    fn negative_coordinates(){
+        let polys = vec![triangle((-75.3, -33.8, -12.5), 4.0), triangle((-60.1, -20.25, 3.0), 2.5)];
+        let original = polys.iter().flat_map(|p| p.outers.concat()).collect::<Vec<_>>();
+        for encoding in [Encoding::Raw, Encoding::Delta]{
+            let (q, points) = round_trip(polys.clone(), info_package(&polys, None), encoding);
+            assert!(q.mx < 0 && q.my < 0 && q.mz < 0, "offsets {} {} {}", q.mx, q.my, q.mz);
+            assert_eq!(points.len(), original.len());
+            for ((x,y,z),(qx,qy,qz)) in original.iter().zip(&points){
+                assert!((x - qx).abs() <= 0.5 / q.multi.0 as f64, "x {} became {}", x, qx);
+                assert!((y - qy).abs() <= 0.5 / q.multi.1 as f64, "y {} became {}", y, qy);
+                assert!((z - qz).abs() <= 0.5 / q.multi.2 as f64, "z {} became {}", z, qz);
+            }
+        }
+    }
+
+    // every axis its own type: u8 x, u16 y and u32 z
+    #[test]
+    fn mixed_axis_sizes(){
+        let polys = vec![triangle((10.0, 20.0, 100.0), 200.0), triangle((150.5, 500.25, 3900.125), 40.0)];
+        let original = polys.iter().flat_map(|p| p.outers.concat()).collect::<Vec<_>>();
+        let infos = Infos{
+            offset: (10, 20, 100),
+            multi: (1, 100, 1000),
+            target: (CompTarget::U8, CompTarget::U16, CompTarget::U32),
+        };
+        for encoding in [Encoding::Raw, Encoding::Delta]{
+            let (q, points) = round_trip(polys.clone(), infos, encoding);
+            assert_eq!(q.tsize, (1,2,4));
+            assert_eq!(q.multi, (1,100,1000));
+            assert_eq!(points.len(), original.len());
+            for ((x,y,z),(qx,qy,qz)) in original.iter().zip(&points){
+                assert!((x - qx).abs() <= 0.5, "x {} became {}", x, qx);
+                assert!((y - qy).abs() <= 0.005, "y {} became {}", y, qy);
+                assert!((z - qz).abs() <= 0.0005, "z {} became {}", z, qz);
+            }
+        }
+    }
+
+    // the largest error of every axis stays within the tolerance, heights of heightlines included,
+    // and it is the error of the quantized values
+    #[test]
+    fn error_within_tolerance(){
+        let polys = vec![triangle((644508.123, 5774260.987, -3.3333), 17.77), triangle((644700.5, 5774500.25, 41.0999), 3.21)];
+        let lines = vec![ShapeZ{
+            points: vec![(644508.123, 5774260.987), (644600.777, 5774300.111)],
+            z: -5.3,
+            bb: ((0.0,0.0,0.0),(0.0,0.0,0.0)),
+        }];
+        let line_points = |lines: &[ShapeZ<f64>]| lines.iter().flat_map(|s| s.points.iter().map(move |(x,y)| (*x, *y, s.z))).collect::<Vec<_>>();
+        for tolerance in [0.5, 0.1, 0.03, 0.001]{
+            let infos = info_package(&polys, Some(tolerance));
+            let (quantized, error) = compress_polygonz_into(polys.clone(), &infos);
+            let original = polys.iter().flat_map(|p| p.outers.concat()).collect::<Vec<_>>();
+            let points = quantized.iter().flat_map(|p| p.outers.concat()).collect::<Vec<_>>();
+            assert_eq!(error, max_error(&original, &points, &infos));
+            let (ex,ey,ez) = error;
+            assert!([ex,ey,ez].iter().all(|e| meets_tolerance(*e, tolerance)), "{} {} {} over {}", ex, ey, ez, tolerance);
+            let infos = info_package(&lines, Some(tolerance));
+            let infos = infos.with_z(-6, 1, infos.multi.2);
+            let (quantized, error) = compress_shapez_into(lines.clone(), &infos);
+            let points = quantized.iter().flat_map(|s| s.points.iter().map(move |(x,y)| (*x, *y, s.z))).collect::<Vec<_>>();
+            assert_eq!(error, max_error(&line_points(&lines), &points, &infos));
+            let (ex,ey,ez) = error;
+            assert!([ex,ey,ez].iter().all(|e| meets_tolerance(*e, tolerance)), "{} {} {} over {}", ex, ey, ez, tolerance);
+        }
+    }
+}
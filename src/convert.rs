@@ -1,62 +1,234 @@
-// these functions just change from x.y() to y(x);
-// because the last conversion functions were copied from our kotlin codebase.
-// I did not want to rearrange the code since its complicated, just let it be.
-
-fn floor(v: f64) -> f64 { v.floor() }
-fn ln(v: f64) -> f64 { v.ln() }
-fn cos(v: f64) -> f64 { v.cos() }
-fn sin(v: f64) -> f64 { v.sin() }
-fn atan(v: f64) -> f64 { v.atan() }
-fn tan(v: f64) -> f64 { v.tan() }
-fn sqrt(v: f64) -> f64 { v.sqrt() }
-
-// I don't understand shit about how this works.
-pub fn degree_to_utm((lon,lat): (f64,f64)) -> (usize,char,f64,f64){
-    // some constants i pulled out of the code
-    let (a,b,c,d,e,f) = (6.0,183.0,1.0,0.5,0.999_6,6_399_593.625);
-    let (g,h,i,j,k,l) = (0.082_094_437_9_f64,2.0,3.0,100.0,0.01,0.006_739_496_742);
-    let zone = floor(lon / a + 31.0);
-    let letter = lat_to_utm_letter(lat);
-
-    let deg = std::f64::consts::PI / 180.0;
-
-    let mut easting = d * ln(
-    (c + cos(lat * deg) * sin(lon * deg - (a * zone - b) * deg)) /
-    (c - cos(lat * deg) * sin(lon * deg - (a * zone - b) * deg))
-    ) * e * f / (c + g.powf(h) * cos(lat * deg).powf(h)).powf(d) * (c + g.powf(h
-    ) / h * (d * ln((c + cos(lat * deg) * sin(lon * deg - (a * zone - b) * deg
-    )) / (c - cos(lat * deg) * sin(lon * deg - (a * zone - b) * deg))
-    )).powf(h) * cos(lat * deg).powf(h) / i) + 500_000.0;
-    easting = (easting * j).round() * k;
-    let mut northing = (atan(
-    tan(lat * deg) / cos(lon * deg - (a * zone - b) * deg)
-    ) - lat * deg) * e * f / sqrt(
-    c + l * cos(lat * deg).powf(h)
-    ) * (c + l / h * (d * ln((c + cos(lat * deg) * sin(lon * deg - (a * zone - b) * deg)) / (c - cos(
-    lat * deg) * sin(lon * deg - (a * zone - b) * deg))
-    )).powf(h) * cos(lat * deg).powf(h)) + e * f * (lat * deg - 0.005_054_622_556 * (lat
-    * deg + sin(h * lat * deg
-    ) / h) + 4.258_201_531e-05 * (i * (lat * deg + sin(h * lat * deg) / h) + sin(h * lat * deg
-    ) * cos(lat * deg).powf(h)) / 4.0 - 1.674_057_895e-07 * (5.0 * (i * (lat * deg + sin(h * lat * deg) / h)     + sin(
-    h * lat * deg) * cos(lat * deg).powf(h)) / 4.0 + sin(h * lat * deg) * cos(
-    lat * deg).powf(h) * cos(lat * deg).powf(h)) / i);
-    if letter < 'M' { northing += 10_000_000.0; }
-    northing = (northing * j).round() * k;
-
-    (zone as usize, letter, easting, northing)
-}
-
-// Just select the right zone letter from the latitude.
-fn lat_to_utm_letter(lat: f64) -> char{
-    let letters = vec!['C', 'D', 'E', 'F', 'G', 'H', 'J', 'K', 'L', 'M',
-        'N', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W'];
-    let mut counter = -72;
-    for l in letters{
-        if lat < counter as f64 {
-            return l;
-        }
-        counter += 8
+// Transverse Mercator on the WGS84 ellipsoid using the Krüger series (to the fourth order of n),
+// this is accurate to well below a millimetre within the width of a utm zone and can be inverted.
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_F: f64 = 1.0 / 298.257_223_563;
+const UTM_K0: f64 = 0.999_6;
+const UTM_FALSE_EASTING: f64 = 500_000.0;
+const UTM_FALSE_NORTHING_SOUTH: f64 = 10_000_000.0;
+
+// series coefficients, they only depend on the ellipsoid
+struct Kruger{
+    // eccentricity
+    e: f64,
+    // radius of the rectifying sphere
+    r: f64,
+    alpha: [f64; 4],
+    beta: [f64; 4],
+    delta: [f64; 4],
+}
+
+fn kruger() -> Kruger{
+    let f = WGS84_F;
+    let n = f / (2.0 - f);
+    let (n2,n3,n4) = (n*n, n*n*n, n*n*n*n);
+    Kruger{
+        e: (f * (2.0 - f)).sqrt(),
+        r: WGS84_A / (1.0 + n) * (1.0 + n2 / 4.0 + n4 / 64.0),
+        alpha: [
+            n / 2.0 - 2.0 / 3.0 * n2 + 5.0 / 16.0 * n3 + 41.0 / 180.0 * n4,
+            13.0 / 48.0 * n2 - 3.0 / 5.0 * n3 + 557.0 / 1440.0 * n4,
+            61.0 / 240.0 * n3 - 103.0 / 140.0 * n4,
+            49561.0 / 161280.0 * n4,
+        ],
+        beta: [
+            n / 2.0 - 2.0 / 3.0 * n2 + 37.0 / 96.0 * n3 - 1.0 / 360.0 * n4,
+            1.0 / 48.0 * n2 + 1.0 / 15.0 * n3 - 437.0 / 1440.0 * n4,
+            17.0 / 480.0 * n3 - 37.0 / 840.0 * n4,
+            4397.0 / 161280.0 * n4,
+        ],
+        delta: [
+            2.0 * n - 2.0 / 3.0 * n2 - 2.0 * n3 + 116.0 / 45.0 * n4,
+            7.0 / 3.0 * n2 - 8.0 / 5.0 * n3 - 227.0 / 45.0 * n4,
+            56.0 / 15.0 * n3 - 136.0 / 35.0 * n4,
+            4279.0 / 630.0 * n4,
+        ],
+    }
+}
+
+// Degrees lon/lat to metres on a transverse mercator with the given central meridian and scale.
+// No false easting or northing is added.
+pub fn tm_forward(lon0: f64, k0: f64, (lon,lat): (f64,f64)) -> (f64,f64){
+    let k = kruger();
+    let (phi, dl) = (lat.to_radians(), (lon - lon0).to_radians());
+    let sp = phi.sin();
+    let t = (sp.atanh() - k.e * (k.e * sp).atanh()).sinh();
+    let xi = t.atan2(dl.cos());
+    let eta = (dl.sin() / (1.0 + t * t).sqrt()).atanh();
+    let mut x = eta;
+    let mut y = xi;
+    for (j,a) in k.alpha.iter().enumerate(){
+        let j2 = 2.0 * (j + 1) as f64;
+        x += a * (j2 * xi).cos() * (j2 * eta).sinh();
+        y += a * (j2 * xi).sin() * (j2 * eta).cosh();
+    }
+    (k0 * k.r * x, k0 * k.r * y)
+}
+
+// The inverse of tm_forward, metres back to degrees lon/lat
+pub fn tm_inverse(lon0: f64, k0: f64, (x,y): (f64,f64)) -> (f64,f64){
+    let k = kruger();
+    let xi = y / (k0 * k.r);
+    let eta = x / (k0 * k.r);
+    let mut xi1 = xi;
+    let mut eta1 = eta;
+    for (j,b) in k.beta.iter().enumerate(){
+        let j2 = 2.0 * (j + 1) as f64;
+        xi1 -= b * (j2 * xi).sin() * (j2 * eta).cosh();
+        eta1 -= b * (j2 * xi).cos() * (j2 * eta).sinh();
+    }
+    let chi = (xi1.sin() / eta1.cosh()).asin();
+    let mut phi = chi;
+    for (j,d) in k.delta.iter().enumerate(){
+        phi += d * (2.0 * (j + 1) as f64 * chi).sin();
     }
-    'X'
+    let dl = eta1.sinh().atan2(xi1.cos());
+    // back into -180..180 for zones next to the antimeridian
+    ((lon0 + dl.to_degrees() + 180.0).rem_euclid(360.0) - 180.0, phi.to_degrees())
+}
+
+// The utm zone a longitude is in, 1..=60
+pub fn utm_zone(lon: f64) -> u8{
+    (((lon + 180.0) / 6.0).floor() as i64).rem_euclid(60) as u8 + 1
+}
+
+// The central meridian of a utm zone in degrees
+pub fn utm_central_meridian(zone: u8) -> f64{
+    zone as f64 * 6.0 - 183.0
+}
+
+// Degrees lon/lat to easting/northing in the given utm zone
+pub fn wgs84_to_utm(zone: u8, north: bool, p: (f64,f64)) -> (f64,f64){
+    let (x,y) = tm_forward(utm_central_meridian(zone), UTM_K0, p);
+    (x + UTM_FALSE_EASTING, if north { y } else { y + UTM_FALSE_NORTHING_SOUTH })
+}
+
+// Easting/northing in the given utm zone to degrees lon/lat
+pub fn utm_to_wgs84(zone: u8, north: bool, (e,n): (f64,f64)) -> (f64,f64){
+    let y = if north { n } else { n - UTM_FALSE_NORTHING_SOUTH };
+    tm_inverse(utm_central_meridian(zone), UTM_K0, (e - UTM_FALSE_EASTING, y))
+}
+
+// Dutch Rijksdriehoeksstelsel (RD New, EPSG:28992) to and from WGS84 with the approximation
+// polynomials of Schreutelkamp and Strang van Hees, they are accurate to about a metre in the Netherlands.
+// Centre of the projection, Amersfoort, in RD and in WGS84 degrees
+const RD_X0: f64 = 155_000.0;
+const RD_Y0: f64 = 463_000.0;
+const RD_LAT0: f64 = 52.155_174_40;
+const RD_LON0: f64 = 5.387_206_21;
+
+// (p, q, coefficient) of a polynomial in a^p b^q
+type Terms = [(i32,i32,f64)];
+
+const RD_X: [(i32,i32,f64); 9] = [
+    (0, 1, 190_094.945), (1, 1, -11_832.228), (2, 1, -114.221), (0, 3, -32.391), (1, 0, -0.705),
+    (3, 1, -2.340), (1, 3, -0.608), (0, 2, -0.008), (2, 3, 0.148),
+];
+const RD_Y: [(i32,i32,f64); 10] = [
+    (1, 0, 309_056.544), (0, 2, 3_638.893), (2, 0, 73.077), (1, 2, -157.984), (3, 0, 59.788),
+    (0, 1, 0.433), (2, 2, -6.439), (1, 1, -0.032), (0, 4, 0.092), (1, 4, -0.054),
+];
+const RD_LAT: [(i32,i32,f64); 11] = [
+    (0, 1, 3_235.653_89), (2, 0, -32.582_97), (0, 2, -0.247_50), (2, 1, -0.849_78), (0, 3, -0.065_50),
+    (2, 2, -0.017_09), (1, 0, -0.007_38), (4, 0, 0.005_30), (2, 3, -0.000_39), (4, 1, 0.000_33),
+    (1, 1, -0.000_12),
+];
+const RD_LON: [(i32,i32,f64); 12] = [
+    (1, 0, 5_260.529_16), (1, 1, 105.946_84), (1, 2, 2.456_56), (3, 0, -0.818_85), (1, 3, 0.055_94),
+    (3, 1, -0.056_07), (0, 1, 0.011_99), (3, 2, -0.002_56), (1, 4, 0.001_28), (0, 2, 0.000_22),
+    (2, 0, -0.000_22), (5, 0, 0.000_26),
+];
+
+fn polynomial(terms: &Terms, a: f64, b: f64) -> f64{
+    terms.iter().map(|(p,q,c)| c * a.powi(*p) * b.powi(*q)).sum()
+}
+
+// Degrees lon/lat to RD x/y
+pub fn wgs84_to_rd((lon,lat): (f64,f64)) -> (f64,f64){
+    let (dlat, dlon) = (0.36 * (lat - RD_LAT0), 0.36 * (lon - RD_LON0));
+    (RD_X0 + polynomial(&RD_X, dlat, dlon), RD_Y0 + polynomial(&RD_Y, dlat, dlon))
 }
 
+// RD x/y to degrees lon/lat
+pub fn rd_to_wgs84((x,y): (f64,f64)) -> (f64,f64){
+    let (dx, dy) = ((x - RD_X0) * 1e-5, (y - RD_Y0) * 1e-5);
+    (RD_LON0 + polynomial(&RD_LON, dx, dy) / 3600.0, RD_LAT0 + polynomial(&RD_LAT, dx, dy) / 3600.0)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn close(a: (f64,f64), b: (f64,f64), tol: f64) -> bool{
+        (a.0 - b.0).abs() <= tol && (a.1 - b.1).abs() <= tol
+    }
+
+    #[test]
+    fn zones(){
+        assert_eq!(utm_zone(3.0), 31);
+        assert_eq!(utm_zone(5.12), 31);
+        assert_eq!(utm_zone(6.0), 32);
+        assert_eq!(utm_zone(-79.39), 17);
+        assert_eq!(utm_zone(-180.0), 1);
+        assert_eq!(utm_zone(180.0), 1);
+        assert_eq!(utm_central_meridian(31), 3.0);
+    }
+
+    // on the central meridian the northing is the meridian arc times the scale,
+    // the WGS84 meridian arc from the equator to 45 degrees is 4984944.378 m
+    #[test]
+    fn central_meridian(){
+        assert!(close(wgs84_to_utm(31, true, (3.0, 0.0)), (500_000.0, 0.0), 1e-6));
+        let n45 = 0.999_6 * 4_984_944.378;
+        assert!(close(wgs84_to_utm(31, true, (3.0, 45.0)), (500_000.0, n45), 1e-3));
+        assert!(close(wgs84_to_utm(31, false, (3.0, -45.0)), (500_000.0, 10_000_000.0 - n45), 1e-3));
+    }
+
+    // CN Tower, 43°38'33.24"N 79°23'13.7"W is 17T 630084 4833438
+    #[test]
+    fn reference_point(){
+        let lat = 43.0 + 38.0 / 60.0 + 33.24 / 3600.0;
+        let lon = -(79.0 + 23.0 / 60.0 + 13.7 / 3600.0);
+        let (e,n) = wgs84_to_utm(17, true, (lon, lat));
+        assert!(close((e,n), (630_084.0, 4_833_438.0), 1.0), "got {} {}", e, n);
+    }
+
+    #[test]
+    fn round_trip(){
+        let points = [(5.12, 52.09), (0.0, 0.1), (8.9, 60.0), (-1.5, 51.0), (174.77, -41.29), (-70.6, -33.4)];
+        for (lon,lat) in points{
+            // also far outside the zone, like lines that cross a zone boundary
+            for zone in [utm_zone(lon), utm_zone(lon) % 60 + 1]{
+                let north = lat >= 0.0;
+                let back = utm_to_wgs84(zone, north, wgs84_to_utm(zone, north, (lon,lat)));
+                assert!(close(back, (lon,lat), 1e-9), "zone {}: {:?} came back as {:?}", zone, (lon,lat), back);
+            }
+        }
+    }
+
+    #[test]
+    fn rd(){
+        assert!(close(wgs84_to_rd((RD_LON0, RD_LAT0)), (RD_X0, RD_Y0), 1e-9));
+        assert!(close(rd_to_wgs84((RD_X0, RD_Y0)), (RD_LON0, RD_LAT0), 1e-12));
+        // the two polynomials are separate approximations, they agree to within decimetres
+        for p in [(13_000.0, 372_000.0), (136_000.0, 456_000.0), (257_000.0, 592_000.0), (185_000.0, 308_000.0)]{
+            let back = wgs84_to_rd(rd_to_wgs84(p));
+            assert!(close(back, p, 0.5), "{:?} came back as {:?}", p, back);
+        }
+    }
+
+    // the worked examples of Schreutelkamp and Strang van Hees, "Benaderingsformules voor de transformatie
+    // tussen RD- en WGS84-kaartcoördinaten": the Westertoren in Amsterdam and the Martinitoren in Groningen
+    #[test]
+    fn rd_reference_points(){
+        let points = [
+            ((120_700.723, 487_525.501), (4.883_525_59, 52.374_532_53)),
+            ((233_883.131, 582_065.167), (6.568_200_53, 53.219_383_17)),
+        ];
+        for (rd, wgs84) in points{
+            let got = rd_to_wgs84(rd);
+            assert!(close(got, wgs84, 1e-7), "{:?} became {:?}, expected {:?}", rd, got, wgs84);
+            let got = wgs84_to_rd(wgs84);
+            assert!(close(got, rd, 0.01), "{:?} became {:?}, expected {:?}", wgs84, got, rd);
+        }
+    }
+}
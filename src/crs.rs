@@ -0,0 +1,285 @@
+use crate::{
+    data::{ VvP4, PolysP4 },
+    convert::*,
+    error::{ Error, Result },
+};
+
+use std::{
+    fmt,
+    path::Path,
+    str::FromStr,
+};
+
+// The coordinate reference systems we can read and project to.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Crs{
+    // degrees longitude, latitude
+    Wgs84,
+    // WGS84 utm, zone 1..=60
+    Utm{ zone: u8, north: bool },
+    // Dutch Rijksdriehoeksstelsel
+    RdNew,
+}
+
+impl Crs{
+    pub fn from_epsg(code: u32) -> Option<Self>{
+        match code{
+            4326 => Some(Crs::Wgs84),
+            28992 => Some(Crs::RdNew),
+            32601..=32660 => Some(Crs::Utm{ zone: (code - 32600) as u8, north: true }),
+            32701..=32760 => Some(Crs::Utm{ zone: (code - 32700) as u8, north: false }),
+            _ => None,
+        }
+    }
+
+    pub fn epsg(self) -> u32{
+        match self{
+            Crs::Wgs84 => 4326,
+            Crs::RdNew => 28992,
+            Crs::Utm{ zone, north: true } => 32600 + zone as u32,
+            Crs::Utm{ zone, north: false } => 32700 + zone as u32,
+        }
+    }
+
+    // coordinates are in metres, not degrees
+    pub fn is_projected(self) -> bool{
+        self != Crs::Wgs84
+    }
+
+    fn unproject(self, p: (f64,f64)) -> (f64,f64){
+        match self{
+            Crs::Wgs84 => p,
+            Crs::Utm{ zone, north } => utm_to_wgs84(zone, north, p),
+            Crs::RdNew => rd_to_wgs84(p),
+        }
+    }
+
+    fn project(self, p: (f64,f64)) -> (f64,f64){
+        match self{
+            Crs::Wgs84 => p,
+            Crs::Utm{ zone, north } => wgs84_to_utm(zone, north, p),
+            Crs::RdNew => wgs84_to_rd(p),
+        }
+    }
+
+    // Detect the crs from the wkt in a .prj file.
+    // The outermost EPSG authority is used when there is one, else the name.
+    pub fn from_wkt(wkt: &str) -> Option<Self>{
+        // the authority of the outer object is the last one
+        if let Some(i) = wkt.rfind("AUTHORITY["){
+            let code = wkt[i..].split('"').nth(3).and_then(|c| c.parse().ok());
+            if let Some(crs) = code.and_then(Crs::from_epsg){
+                return Some(crs);
+            }
+        }
+        let name = wkt.split('"').nth(1)?.to_ascii_lowercase().replace(' ', "_");
+        if wkt.trim_start().starts_with("GEOGCS"){
+            return if name.contains("wgs_1984") || name.contains("wgs_84") { Some(Crs::Wgs84) } else { None };
+        }
+        // RD_New or Amersfoort / RD New
+        if name.contains("rd_new"){
+            return Some(Crs::RdNew);
+        }
+        // WGS_1984_UTM_Zone_31N or WGS 84 / UTM zone 31N
+        let rest = &name[name.find("utm_zone_")? + "utm_zone_".len()..];
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).collect::<String>();
+        let zone = digits.parse::<u8>().ok().filter(|z| (1..=60).contains(z))?;
+        let north = match rest[digits.len()..].chars().next()?{
+            'n' => true,
+            's' => false,
+            _ => return None,
+        };
+        if name.contains("wgs") { Some(Crs::Utm{ zone, north }) } else { None }
+    }
+
+    // Read the .prj file next to a shapefile, no .prj means we do not know the crs
+    pub fn read_prj(shp_path: &str) -> Result<Option<Self>>{
+        let prj = Path::new(shp_path).with_extension("prj");
+        if !prj.exists(){
+            return Ok(None);
+        }
+        let prj_path = prj.to_string_lossy().to_string();
+        let wkt = std::fs::read_to_string(&prj).map_err(|e| Error::io(&prj_path, e))?;
+        match Crs::from_wkt(&wkt){
+            Some(crs) => Ok(Some(crs)),
+            None => Err(Error::crs(&prj_path, format!("unsupported coordinate reference system: {}", wkt.trim()))),
+        }
+    }
+}
+
+impl FromStr for Crs{
+    type Err = Error;
+
+    // wgs84, utm31n, utm31s, rdnew or epsg:<code>, also the urn:ogc:def:crs:EPSG::<code> and CRS84 of geojson
+    fn from_str(s: &str) -> Result<Self>{
+        let l = s.trim().to_ascii_lowercase();
+        let err = || Error::Argument(format!("Unknown crs: {}, expected wgs84, utm<zone><n|s>, rdnew or epsg:<code>", s));
+        if l == "wgs84" || l.ends_with("crs84"){
+            return Ok(Crs::Wgs84);
+        }
+        if l == "rdnew" || l == "rd"{
+            return Ok(Crs::RdNew);
+        }
+        if let Some(rest) = l.strip_prefix("utm"){
+            let (zone, hemi) = rest.split_at(rest.len().saturating_sub(1));
+            let zone = zone.parse::<u8>().ok().filter(|z| (1..=60).contains(z)).ok_or_else(err)?;
+            return match hemi{
+                "n" => Ok(Crs::Utm{ zone, north: true }),
+                "s" => Ok(Crs::Utm{ zone, north: false }),
+                _ => Err(err()),
+            };
+        }
+        if let Some(i) = l.find("epsg"){
+            let code = l[i + 4..].trim_start_matches(':').parse::<u32>().map_err(|_| err())?;
+            return Crs::from_epsg(code).ok_or_else(||
+                Error::Argument(format!("Unsupported crs: EPSG:{}", code))
+            );
+        }
+        Err(err())
+    }
+}
+
+impl fmt::Display for Crs{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            Crs::Wgs84 => write!(f, "WGS84 (EPSG:4326)"),
+            Crs::RdNew => write!(f, "RD New (EPSG:28992)"),
+            Crs::Utm{ zone, north } =>
+                write!(f, "UTM zone {}{} (EPSG:{})", zone, if *north { 'N' } else { 'S' }, self.epsg()),
+        }
+    }
+}
+
+// Show a crs that may not be known
+pub fn crs_name(crs: Option<Crs>) -> String{
+    match crs{
+        Some(crs) => crs.to_string(),
+        None => String::from("unknown"),
+    }
+}
+
+// The utm zone lon/lat input is projected into when there is no target crs.
+// One zone is used for a whole dataset, so lines crossing a zone boundary stay in one piece.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct UtmZone{
+    pub zone: u8,
+    pub north: bool,
+}
+
+impl UtmZone{
+    // the zone a lon/lat point lies in
+    pub fn of((lon,lat): (f64,f64)) -> Self{
+        Self{ zone: utm_zone(lon), north: lat >= 0.0 }
+    }
+
+    pub fn crs(self) -> Crs{
+        Crs::Utm{ zone: self.zone, north: self.north }
+    }
+}
+
+impl FromStr for UtmZone{
+    type Err = Error;
+
+    // 31, 31n or 31s, the northern hemisphere when not given
+    fn from_str(s: &str) -> Result<Self>{
+        let l = s.trim().to_ascii_lowercase();
+        let (zone, north) = match l.strip_suffix('s'){
+            Some(zone) => (zone, false),
+            None => (l.strip_suffix('n').unwrap_or(&l), true),
+        };
+        let zone = zone.parse::<u8>().ok().filter(|z| (1..=60).contains(z)).ok_or_else(||
+            Error::Argument(format!("Unknown utm zone: {}, expected 1 to 60 with an optional n or s", s))
+        )?;
+        Ok(Self{ zone, north })
+    }
+}
+
+// How the points of an input get into the target crs.
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Projection{
+    // points stay as they are
+    Keep,
+    // from one crs into another, through wgs84
+    Reproject{ from: Crs, to: Crs },
+}
+
+impl Projection{
+    // Without a target projected inputs are kept as they are and lon/lat is turned into utm, in the given zone.
+    // Inputs of which we do not know the crs can not be reprojected.
+    pub fn new(source: Option<Crs>, target: Option<Crs>, utm_zone: Option<UtmZone>, path: &str) -> Result<Self>{
+        match (source, target){
+            (Some(Crs::Wgs84), None) => match utm_zone{
+                Some(zone) => Ok(Projection::Reproject{ from: Crs::Wgs84, to: zone.crs() }),
+                None => Err(Error::crs(path, "lon/lat input needs a utm zone to be projected into".to_string())),
+            },
+            (_, None) => Ok(Projection::Keep),
+            (Some(from), Some(to)) if from == to => Ok(Projection::Keep),
+            (Some(from), Some(to)) => Ok(Projection::Reproject{ from, to }),
+            (None, Some(to)) => Err(Error::crs(path, format!(
+                "the crs is not known (no .prj), so it can not be projected to {}", to
+            ))),
+        }
+    }
+
+    pub fn point(&self, p: (f64,f64)) -> (f64,f64){
+        match self{
+            Projection::Keep => p,
+            Projection::Reproject{ from, to } => to.project(from.unproject(p)),
+        }
+    }
+}
+
+// What is done to every point that is read: projected into the target crs,
+// x and y rounded to the precision and the height snapped to the interval.
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub struct Points{
+    pub proj: Projection,
+    // in units of the target crs, None keeps full precision
+    pub precision: Option<f64>,
+    // heights are snapped to multiples of this, None keeps them as they are
+    pub height_snap: Option<f64>,
+}
+
+impl Points{
+    // the points as they are in the file
+    pub fn raw() -> Self{
+        Self{ proj: Projection::Keep, precision: None, height_snap: None }
+    }
+
+    pub fn xy(&self, p: (f64,f64)) -> (f64,f64){
+        let (x,y) = self.proj.point(p);
+        match self.precision{
+            Some(step) => (snap(x, step), snap(y, step)),
+            None => (x,y),
+        }
+    }
+
+    pub fn z(&self, z: f64) -> f64{
+        match self.height_snap{
+            Some(step) => snap(z, step),
+            None => z,
+        }
+    }
+
+    pub fn lines(&self, lines: &mut VvP4){
+        if *self == Points::raw() { return; }
+        for p in lines.iter_mut().flatten(){
+            let (x,y) = self.xy((p.0,p.1));
+            p.0 = x;
+            p.1 = y;
+            p.2 = self.z(p.2);
+        }
+    }
+
+    pub fn polys(&self, polys: &mut PolysP4){
+        for (outers,inners) in polys.iter_mut(){
+            self.lines(outers);
+            self.lines(inners);
+        }
+    }
+}
+
+// round to the nearest multiple of step
+pub fn snap(v: f64, step: f64) -> f64{
+    (v / step).round() * step
+}
@@ -72,8 +72,8 @@ macro_rules! ImplMinMax {
     ($ttype:ident) => {
         impl MinMax for $ttype
         {
-            fn minv() -> Self{ std::$ttype::MIN }
-            fn maxv() -> Self{ std::$ttype::MAX }
+            fn minv() -> Self{ $ttype::MIN }
+            fn maxv() -> Self{ $ttype::MAX }
             fn min_of(self, x: Self) -> Self{ self.min(x) }
             fn max_of(self, x: Self) -> Self{ self.max(x) }
         }
@@ -714,3 +714,26 @@ pub fn split(shapes: Vec<Shape>, logger: &mut Logger) -> Splitted{
     (points,pointms,pointzs,plines,plinems,plinezs,mpoints,mpointms,mpointzs,polys,polyms,polyzs)
 }
 
+
+// All polylines of a shape as heightlines, every point gets the given height
+pub fn polylines_at_height(shape: Shape, z: f64, logger: &mut Logger) -> VvP4{
+    let (_,_,_,plines,plinems,plinezs,..) = split(vec![shape], logger);
+    let mut res: VvP4 = plines.into_iter().map(|l| l.into_iter().map(|(x,y)| (x,y,z,0.0)).collect()).collect();
+    res.extend(plinems.into_iter().map(|l| l.into_iter().map(|(x,y,m)| (x,y,z,m)).collect()));
+    res.extend(plinezs.into_iter().map(|l| l.into_iter().map(|(x,y,_,m)| (x,y,z,m)).collect()));
+    res
+}
+
+// All polygons of a shape as 3d polygons, the ones without z get the given height
+pub fn polygons_at_height(shape: Shape, z: f64, logger: &mut Logger) -> PolysP4{
+    let (..,polys,polyms,polyzs) = split(vec![shape], logger);
+    fn rings<P>(rings: Vvec<P>, f: &impl Fn(P) -> P4<f64>) -> VvP4{
+        rings.into_iter().map(|r| r.into_iter().map(f).collect()).collect()
+    }
+    let mut res = polyzs;
+    let f2 = |(x,y)| (x,y,z,0.0);
+    res.extend(polys.into_iter().map(|(o,i)| (rings(o, &f2), rings(i, &f2))));
+    let f3 = |(x,y,m)| (x,y,z,m);
+    res.extend(polyms.into_iter().map(|(o,i)| (rings(o, &f3), rings(i, &f3))));
+    res
+}
@@ -0,0 +1,115 @@
+use crate::{
+    error::{ Error, Result },
+    shapefile::{ Shape, Reader, dbase::{ Record, FieldValue } },
+};
+
+// Read the shapes of a shapefile together with their row of the .dbf table next to it
+pub fn read_shapes_and_records(path: &str) -> Result<Vec<(Shape,Record)>>{
+    let err = |cause| Error::Shapefile{ path: path.to_string(), cause };
+    let shapes = Reader::from_path(path).and_then(|r| r.read()).map_err(err)?;
+    let records = Reader::from_path(path).and_then(|r| r.read_records()).map_err(err)?;
+    // reading them side by side would quietly drop what one of them has more of
+    if shapes.len() != records.len(){
+        return Err(Error::dbf(path, shapes.len().min(records.len()),
+            format!("the .dbf has {} records for {} shapes", records.len(), shapes.len())));
+    }
+    let res = shapes.into_iter().zip(records).collect::<Vec<_>>();
+    println!("Read file \"{}\": shapes: {}", path, res.len());
+    Ok(res)
+}
+
+// Every record has the same fields, so a missing field is a mistake in the arguments
+pub fn check_field(records: &[(Shape,Record)], field: &str, path: &str) -> Result<()>{
+    let record = if let Some((_,r)) = records.first(){ r } else { return Ok(()); };
+    if record.contains_key(field){
+        return Ok(());
+    }
+    let mut fields = record.keys().cloned().collect::<Vec<_>>();
+    fields.sort();
+    Err(Error::Argument(format!("\"{}\" has no attribute {}, it has: {}", path, field, fields.join(", "))))
+}
+
+// The field as a number, character fields are parsed
+pub fn record_number(record: &Record, field: &str, path: &str, index: usize) -> Result<f64>{
+    let err = |cause: String| Error::dbf(path, index, cause);
+    match record.get(field){
+        Some(FieldValue::Numeric(Some(x))) => Ok(*x),
+        Some(FieldValue::Float(Some(x))) => Ok(*x as f64),
+        Some(FieldValue::Integer(x)) => Ok(*x as f64),
+        Some(FieldValue::Double(x)) => Ok(*x),
+        Some(FieldValue::Character(Some(s))) => s.trim().parse()
+            .map_err(|_| err(format!("{} is \"{}\", not a number", field, s.trim()))),
+        Some(_) => Err(err(format!("{} has no number", field))),
+        None => Err(err(format!("no attribute {}", field))),
+    }
+}
+
+// The field as text, to tell classes apart
+pub fn record_class(record: &Record, field: &str, path: &str, index: usize) -> Result<String>{
+    let err = |cause: String| Error::dbf(path, index, cause);
+    match record.get(field){
+        Some(FieldValue::Character(Some(s))) => Ok(s.trim().to_string()),
+        Some(FieldValue::Numeric(Some(x))) => Ok(x.to_string()),
+        Some(FieldValue::Float(Some(x))) => Ok(x.to_string()),
+        Some(FieldValue::Integer(x)) => Ok(x.to_string()),
+        Some(FieldValue::Double(x)) => Ok(x.to_string()),
+        Some(FieldValue::Logical(Some(x))) => Ok(x.to_string()),
+        Some(_) => Err(err(format!("{} is empty", field))),
+        None => Err(err(format!("no attribute {}", field))),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::shapefile::{ Writer, Point, Polyline, dbase };
+
+    // a shapefile in the temp dir with a line for every record, the .dbf can be written over after
+    fn write(name: &str, records: Vec<Record>) -> String{
+        let path = std::env::temp_dir().join(format!("shapefile-linter-dbf-{}.shp", name));
+        let lines = (0..records.len()).map(|i| Polyline::new(vec![Point::new(0.0, i as f64), Point::new(1.0, i as f64)])).collect::<Vec<_>>();
+        Writer::from_path(&path).unwrap().write_shapes_and_records(&lines, records).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn record(fields: Vec<(&str, FieldValue)>) -> Record{
+        fields.into_iter().map(|(k,v)| (k.to_string(), v)).collect()
+    }
+
+    #[test]
+    fn missing_field(){
+        let path = write("missing", vec![record(vec![("height", FieldValue::Numeric(Some(5.0)))])]);
+        let records = read_shapes_and_records(&path).unwrap();
+        assert!(check_field(&records, "height", &path).is_ok());
+        let err = check_field(&records, "hoogte", &path).unwrap_err();
+        assert!(err.to_string().contains("it has: height"));
+        assert!(record_number(&records[0].1, "hoogte", &path, 0).is_err());
+        assert!(record_class(&records[0].1, "hoogte", &path, 0).is_err());
+    }
+
+    #[test]
+    fn field_types(){
+        let path = write("types", vec![
+            record(vec![("z", FieldValue::Character(Some(String::from(" 12.5 ")))), ("n", FieldValue::Numeric(Some(3.0)))]),
+            record(vec![("z", FieldValue::Character(Some(String::from("high")))), ("n", FieldValue::Numeric(None))]),
+        ]);
+        let records = read_shapes_and_records(&path).unwrap();
+        assert_eq!(record_number(&records[0].1, "z", &path, 0).unwrap(), 12.5);
+        assert_eq!(record_class(&records[0].1, "z", &path, 0).unwrap(), "12.5");
+        assert_eq!(record_class(&records[0].1, "n", &path, 0).unwrap(), "3");
+        // text that is not a number is no height, an empty number is neither
+        assert!(record_number(&records[1].1, "z", &path, 1).is_err());
+        assert!(record_number(&records[1].1, "n", &path, 1).is_err());
+        assert!(record_class(&records[1].1, "n", &path, 1).is_err());
+    }
+
+    #[test]
+    fn record_count_mismatch(){
+        let row = |z| record(vec![("height", FieldValue::Numeric(Some(z)))]);
+        let path = write("mismatch", vec![row(1.0), row(2.0)]);
+        assert_eq!(read_shapes_and_records(&path).unwrap().len(), 2);
+        dbase::write_to_path(&vec![row(1.0)], std::path::Path::new(&path).with_extension("dbf")).unwrap();
+        let err = read_shapes_and_records(&path).err().unwrap();
+        assert!(err.to_string().contains("1 records for 2 shapes"));
+    }
+}
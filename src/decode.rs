@@ -0,0 +1,507 @@
+use crate::{
+    data::*,
+    error::{ Error, Result },
+    header::read_header,
+    encoding::{ Encoding, Encodable, Layout, read_bb },
+    triangulate::PolyTriangle,
+    quadtree::QuadNode,
+};
+
+use std::str::FromStr;
+
+use bin_buffer::*;
+
+// Every binary format we write.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum FormatKind{
+    // compressed heightlines from height and mergeheight
+    Height,
+    // compressed polygons from polygonz
+    Polygonz,
+    // compressed triangles from triangulate
+    Triangles,
+    // level-x-y.hlinechunk from chunkify
+    HlineChunk,
+    // level-x-y.polychunk from geopolys
+    PolyChunk,
+    // level-x-y.geolinechunk from geolines
+    GeolineChunk,
+    // chunks.info from chunkify
+    ChunksInfo,
+    // chunks.polyinfo from geopolys
+    PolyInfo,
+    // chunks.geolineinfo from geolines
+    GeolineInfo,
+    // styles from geopolys and geolines
+    Styles,
+    // chunks.quadtree from the chunkers with a budget
+    Quadtree,
+}
+
+impl FormatKind{
+    // All kinds, in the order they are listed to the user.
+    pub const ALL: [FormatKind; 11] = [
+        FormatKind::Height, FormatKind::Polygonz, FormatKind::Triangles,
+        FormatKind::HlineChunk, FormatKind::PolyChunk, FormatKind::GeolineChunk,
+        FormatKind::ChunksInfo, FormatKind::PolyInfo, FormatKind::GeolineInfo,
+        FormatKind::Styles, FormatKind::Quadtree,
+    ];
+
+    // The number the kind is stored as in the header
+    pub fn code(self) -> u8{
+        match self{
+            FormatKind::Height => 1,
+            FormatKind::Polygonz => 2,
+            FormatKind::Triangles => 3,
+            FormatKind::HlineChunk => 4,
+            FormatKind::PolyChunk => 5,
+            FormatKind::GeolineChunk => 6,
+            FormatKind::ChunksInfo => 7,
+            FormatKind::PolyInfo => 8,
+            FormatKind::GeolineInfo => 9,
+            FormatKind::Styles => 10,
+            FormatKind::Quadtree => 11,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self>{
+        FormatKind::ALL.iter().copied().find(|k| k.code() == code)
+    }
+
+    // The version of the layout we write for this kind.
+    // Bump it whenever the layout of the kind changes, readers refuse other versions.
+    pub fn version(self) -> u16{
+        match self{
+            // the cuts of every level instead of one u8, none for a quadtree
+            FormatKind::PolyInfo | FormatKind::GeolineInfo => 3,
+            // a type and multiplier per axis, signed offsets, no cuts for a quadtree, z offset by the lowest height
+            FormatKind::ChunksInfo => 5,
+            // quantized relative to the corner of the cell, a type and multiplier per axis, signed offsets,
+            // z relative to the lowest height in chunks.info
+            FormatKind::HlineChunk => 4,
+            // a type and multiplier per axis, signed offsets, z offset by the lowest height
+            FormatKind::Height => 4,
+            // a type and multiplier per axis, signed x and y offsets
+            FormatKind::Polygonz | FormatKind::Triangles
+                | FormatKind::PolyChunk | FormatKind::GeolineChunk => 3,
+            _ => 1,
+        }
+    }
+
+    pub fn name(self) -> &'static str{
+        match self{
+            FormatKind::Height => "height",
+            FormatKind::Polygonz => "polygonz",
+            FormatKind::Triangles => "triangles",
+            FormatKind::HlineChunk => "hlinechunk",
+            FormatKind::PolyChunk => "polychunk",
+            FormatKind::GeolineChunk => "geolinechunk",
+            FormatKind::ChunksInfo => "info",
+            FormatKind::PolyInfo => "polyinfo",
+            FormatKind::GeolineInfo => "geolineinfo",
+            FormatKind::Styles => "styles",
+            FormatKind::Quadtree => "quadtree",
+        }
+    }
+}
+
+impl FromStr for FormatKind{
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        FormatKind::ALL.iter().copied().find(|k| k.name() == s).ok_or_else(||{
+            let names = FormatKind::ALL.iter().map(|k| k.name()).collect::<Vec<_>>().join(", ");
+            Error::Argument(format!("Unknown format kind: {}, expected one of: {}", s, names))
+        })
+    }
+}
+
+// The offsets and multipliers values were quantized with, and the size of the integers used, per axis.
+// Quantized value q came from world value w as q = (w - offset) * multi.
+#[derive(Clone,Copy,Debug)]
+pub struct Quantization{
+    pub mx: i64,
+    pub my: i64,
+    pub mz: i64,
+    pub multi: P3<u64>,
+    pub tsize: P3<u8>,
+}
+
+impl Quantization{
+    // Turn a quantized point back into world coordinates
+    pub fn dequantize(&self, (x,y): P2<u32>) -> P2<f64>{
+        let (mx,my,_) = self.multi;
+        (x as f64 / mx.max(1) as f64 + self.mx as f64, y as f64 / my.max(1) as f64 + self.my as f64)
+    }
+
+    fn layout(&self, encoding: Encoding) -> Layout{
+        Layout{ encoding, sizes: self.tsize }
+    }
+}
+
+// A compressed collection: quantization, global boundingbox and the shapes.
+// Shapes are read as u32 no matter the integer sizes they were stored with.
+pub struct Compressed<S>{
+    pub quant: Quantization,
+    pub bb: BB<u32>,
+    pub shapes: Vec<S>,
+}
+
+// A compressed height file as written by height and mergeheight, read as input for chunkify.
+pub struct HeightFile{
+    pub quant: Quantization,
+    pub bb: BB<u16>,
+    pub shapes: Vec<ShapeZ<u16>>,
+}
+
+// A heightline chunk. Its offset is the corner of its cell in the u16 coordinates of the height file,
+// quantized relative to that as the chunks.info file is relative to the world.
+pub struct HeightChunk{
+    pub level: u64,
+    pub x: u64,
+    pub y: u64,
+    pub chunk: Compressed<ShapeZ<u32>>,
+}
+
+// The chunks.info file.
+pub struct ChunksInfo{
+    // cuts of every level, empty when the chunks are in a quadtree
+    pub cuts: Vec<u64>,
+    pub quant: Quantization,
+    pub bb: BB<u16>,
+    // height modulo of every level
+    pub mods: Vec<u64>,
+}
+
+// The chunks.polyinfo and chunks.geolineinfo files.
+pub struct GridInfo{
+    pub bb: BB<u32>,
+    // cuts of every level, empty when the chunks are in a quadtree
+    pub cuts: Vec<u64>,
+}
+
+// The chunks.quadtree file: the root square and the nodes of the quadtree of every level.
+pub struct QuadIndex{
+    pub origin: P2<u64>,
+    pub side: u64,
+    pub levels: Vvec<QuadNode>,
+}
+
+// Any decoded file.
+pub enum Decoded{
+    Height(Compressed<ShapeZ<u32>>),
+    Polygonz(Compressed<PolygonZ<u32>>),
+    Triangles(Compressed<PolyTriangle<u32>>),
+    HlineChunk(HeightChunk),
+    PolyChunk(Compressed<PolyTriangle<u32>>),
+    GeolineChunk(Compressed<StyledLine<u32>>),
+    ChunksInfo(ChunksInfo),
+    PolyInfo(GridInfo),
+    GeolineInfo(GridInfo),
+    Styles(Vec<(u8,u8,u8,u8)>),
+    Quadtree(QuadIndex),
+}
+
+// read a value or fail with the name of what we tried to read
+fn read<T: Bufferable>(buf: &mut ReadBuffer, path: &str, what: &str) -> Result<T>{
+    T::from_buffer(buf).ok_or_else(|| Error::format(path, format!("unexpected end of file while reading {}", what)))
+}
+
+fn read_quantization(buf: &mut ReadBuffer, path: &str) -> Result<Quantization>{
+    Ok(Quantization{
+        mx: read::<u64>(buf, path, "x offset")? as i64,
+        my: read::<u64>(buf, path, "y offset")? as i64,
+        mz: read::<u64>(buf, path, "z offset")? as i64,
+        multi: read(buf, path, "multipliers")?,
+        tsize: read(buf, path, "type sizes")?,
+    })
+}
+
+// read shapes written with the encoding from the header
+fn read_encoded<T: Encodable>(buf: &mut ReadBuffer, layout: Layout, path: &str, what: &str) -> Result<T>{
+    T::decode(layout, buf).ok_or_else(|| Error::format(path, format!("unexpected end of file or bad {:?} encoding while reading {}", layout.encoding, what)))
+}
+
+// Floats are written when an axis did not fit u32, we can not read those back
+fn check_sizes(quant: &Quantization, path: &str) -> Result<()>{
+    let (x,y,z) = quant.tsize;
+    if [x,y,z].iter().all(|s| [1,2,4].contains(s)) { return Ok(()); }
+    Err(Error::format(path, format!("unsupported type sizes {:?}", quant.tsize)))
+}
+
+// Read the output of Compressable::compress: quantization, boundingbox and the shapes.
+// Every axis is read with its own type size.
+fn read_compressed<S: Encodable>(buf: &mut ReadBuffer, enc: Encoding, path: &str) -> Result<Compressed<S>>{
+    let quant = read_quantization(buf, path)?;
+    check_sizes(&quant, path)?;
+    let bb = read_bb(quant.tsize, buf)
+        .ok_or_else(|| Error::format(path, "unexpected end of file while reading boundingbox"))?;
+    let shapes = read_encoded(buf, quant.layout(enc), path, "shapes")?;
+    Ok(Compressed{ quant, bb, shapes })
+}
+
+fn narrow_bb(((a,b,c),(d,e,f)): BB<u32>) -> BB<u16>{
+    ((a as u16,b as u16,c as u16),(d as u16,e as u16,f as u16))
+}
+
+// Read a compressed height file that chunkify can take.
+pub fn read_height_file(buf: &mut ReadBuffer, path: &str) -> Result<HeightFile>{
+    let header = read_header(buf, path, Some(FormatKind::Height))?;
+    let Compressed{ quant, bb, shapes } = read_compressed::<ShapeZ<u32>>(buf, Encoding::from_flags(header.flags), path)?;
+    let (x,y,z) = quant.tsize;
+    if x > 2 || y > 2 || z > 2{
+        return Err(Error::format(path, format!("chunkify needs a height file of at most u16, this one has type sizes {:?}", quant.tsize)));
+    }
+    // every value fits u16 as no axis is bigger
+    let shapes = shapes.into_iter().map(|s| ShapeZ{
+        points: s.points.into_iter().map(|(x,y)| (x as u16, y as u16)).collect(),
+        z: s.z as u16,
+        bb: narrow_bb(s.bb),
+    }).collect();
+    Ok(HeightFile{
+        quant,
+        bb: narrow_bb(bb),
+        shapes,
+    })
+}
+
+fn read_hline_chunk(buf: &mut ReadBuffer, enc: Encoding, path: &str) -> Result<HeightChunk>{
+    Ok(HeightChunk{
+        level: read(buf, path, "level")?,
+        x: read(buf, path, "x")?,
+        y: read(buf, path, "y")?,
+        chunk: read_compressed(buf, enc, path)?,
+    })
+}
+
+fn read_chunks_info(buf: &mut ReadBuffer, path: &str) -> Result<ChunksInfo>{
+    let cuts = read(buf, path, "cuts")?;
+    let quant = read_quantization(buf, path)?;
+    let bmin = read::<P3<u16>>(buf, path, "boundingbox")?;
+    let bmax = read::<P3<u16>>(buf, path, "boundingbox")?;
+    let mods = read(buf, path, "mods")?;
+    Ok(ChunksInfo{ cuts, quant, bb: (bmin,bmax), mods })
+}
+
+fn read_quad_index(buf: &mut ReadBuffer, path: &str) -> Result<QuadIndex>{
+    Ok(QuadIndex{
+        origin: read(buf, path, "origin")?,
+        side: read(buf, path, "side")?,
+        levels: read(buf, path, "nodes")?,
+    })
+}
+
+fn read_grid_info(buf: &mut ReadBuffer, path: &str) -> Result<GridInfo>{
+    Ok(GridInfo{
+        bb: read(buf, path, "boundingbox")?,
+        cuts: read(buf, path, "cuts")?,
+    })
+}
+
+// Decode a buffer, the kind is read from the header.
+// If a kind is given, fails if the file is of another kind.
+pub fn decode_buffer(kind: Option<FormatKind>, buf: &mut ReadBuffer, path: &str) -> Result<Decoded>{
+    let header = read_header(buf, path, kind)?;
+    let enc = Encoding::from_flags(header.flags);
+    Ok(match header.kind{
+        FormatKind::Height => Decoded::Height(
+            read_compressed(buf, enc, path)?),
+        FormatKind::Polygonz => Decoded::Polygonz(
+            read_compressed(buf, enc, path)?),
+        FormatKind::Triangles => Decoded::Triangles(
+            read_compressed(buf, enc, path)?),
+        FormatKind::HlineChunk => Decoded::HlineChunk(read_hline_chunk(buf, enc, path)?),
+        FormatKind::PolyChunk => Decoded::PolyChunk(
+            read_compressed(buf, enc, path)?),
+        FormatKind::GeolineChunk => Decoded::GeolineChunk(
+            read_compressed(buf, enc, path)?),
+        FormatKind::ChunksInfo => Decoded::ChunksInfo(read_chunks_info(buf, path)?),
+        FormatKind::PolyInfo => Decoded::PolyInfo(read_grid_info(buf, path)?),
+        FormatKind::GeolineInfo => Decoded::GeolineInfo(read_grid_info(buf, path)?),
+        FormatKind::Styles => Decoded::Styles(read(buf, path, "styles")?),
+        FormatKind::Quadtree => Decoded::Quadtree(read_quad_index(buf, path)?),
+    })
+}
+
+// Read and decode a file from disk.
+pub fn decode_file(path: &str, kind: Option<FormatKind>) -> Result<Decoded>{
+    let raw = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    let mut buf = ReadBuffer::from_raw(raw);
+    decode_buffer(kind, &mut buf, path)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::{
+        logger::Logger,
+        header::read_header,
+        chunkify::Simplify,
+        quadtree::Budget,
+        pipeline::{
+            OutputFile, LodSettings, GeoLodSettings,
+            compress_heightlines, compress_polygonzs, compress_triangulated,
+            chunkify_heightlines, chunkify_geopolys, chunkify_geolines,
+        },
+    };
+
+    fn decode(buffer: &[u8]) -> Decoded{
+        decode_buffer(None, &mut ReadBuffer::from_raw(buffer.to_vec()), "test").unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn kind(buffer: &[u8]) -> FormatKind{
+        read_header(&mut ReadBuffer::from_raw(buffer.to_vec()), "test", None).unwrap().kind
+    }
+
+    // a closed square with its corner at (x,y)
+    fn square<T: Copy>((x,y): P2<T>, side: T, add: impl Fn(T,T) -> T) -> Vec<P2<T>>{
+        vec![(x,y), (add(x,side),y), (add(x,side),add(y,side)), (x,add(y,side)), (x,y)]
+    }
+
+    fn heightlines() -> Vec<ShapeZ<f64>>{
+        (0..4).map(|i| ShapeZ{
+            points: square((1000.0 + 150.0 * i as f64, 2000.5), 100.25, |a,b| a + b),
+            z: 10.0 * i as f64 - 20.0,
+            bb: ((0.0,0.0,0.0),(0.0,0.0,0.0)),
+        }).collect()
+    }
+
+    fn polygonzs() -> Vec<PolygonZ<f64>>{
+        (0..3).map(|i| PolygonZ{
+            outers: vec![square((500.0 + 200.0 * i as f64, 700.0), 150.0, |a,b| a + b)
+                .into_iter().skip(1).map(|(x,y)| (x, y, 3.5 * i as f64)).collect()],
+            inners: Vec::new(),
+            bb: ((0.0,0.0,0.0),(0.0,0.0,0.0)),
+            style: i,
+        }).collect()
+    }
+
+    fn chunk_kinds(outputs: &[OutputFile]) -> Vec<FormatKind>{
+        outputs.iter().map(|o|{
+            decode(&o.buffer);
+            kind(&o.buffer)
+        }).collect()
+    }
+
+    #[test]
+    fn height_round_trip(){
+        let original = heightlines();
+        let buffer = compress_heightlines(original.clone(), None, Encoding::Raw, &mut Logger::default()).unwrap();
+        let c = match decode(&buffer){
+            Decoded::Height(c) => c,
+            _ => panic!("not read back as height"),
+        };
+        assert_eq!(c.shapes.len(), original.len());
+        for (s,o) in c.shapes.iter().zip(&original){
+            assert_eq!(s.z as i64 / c.quant.multi.2 as i64 + c.quant.mz, o.z as i64);
+            for (p,q) in s.points.iter().zip(&o.points){
+                let (x,y) = c.quant.dequantize(*p);
+                // half a step, give or take float rounding
+                assert!((x - q.0).abs() <= 0.5 / c.quant.multi.0 as f64 + 1e-9, "x {} became {}", q.0, x);
+                assert!((y - q.1).abs() <= 0.5 / c.quant.multi.1 as f64 + 1e-9, "y {} became {}", q.1, y);
+            }
+        }
+        let hf = read_height_file(&mut ReadBuffer::from_raw(buffer), "test").unwrap();
+        assert_eq!(hf.shapes.len(), original.len());
+    }
+
+    // with a tolerance the multiplier is the same for every axis, a large extent needs more than u16
+    #[test]
+    fn height_fails_over_u16(){
+        let mut logger = Logger::default();
+        let small = compress_heightlines(heightlines(), Some(0.05), Encoding::Raw, &mut logger).unwrap();
+        let hf = read_height_file(&mut ReadBuffer::from_raw(small), "test").unwrap();
+        assert_eq!(hf.shapes.len(), 4);
+        let mut large = heightlines();
+        large[3].points.push((90000.0, 2000.5));
+        assert!(compress_heightlines(large.clone(), Some(0.05), Encoding::Raw, &mut logger).is_err());
+        assert!(compress_heightlines(large, Some(0.5), Encoding::Raw, &mut logger).is_err());
+        // without a tolerance the extent fills u16 with a multiplier of 1
+        let mut wide = heightlines();
+        wide[3].points.push((60000.0, 2000.5));
+        assert!(compress_heightlines(wide, None, Encoding::Raw, &mut logger).is_ok());
+    }
+
+    #[test]
+    fn every_kind_round_trips(){
+        let mut logger = Logger::default();
+        let mut kinds = Vec::new();
+        let height = compress_heightlines(heightlines(), None, Encoding::Delta, &mut logger).unwrap();
+        kinds.push(kind(&height));
+        let polys = compress_polygonzs(polygonzs(), None, Encoding::Raw, &mut logger).unwrap();
+        assert!(matches!(decode(&polys), Decoded::Polygonz(c) if c.shapes.len() == 3));
+        kinds.push(kind(&polys));
+        let triangles = compress_triangulated(polygonzs(), None, Encoding::Delta, &mut logger).unwrap();
+        assert!(matches!(decode(&triangles), Decoded::Triangles(c) if c.shapes.len() == 3));
+        kinds.push(kind(&triangles));
+        let hf = read_height_file(&mut ReadBuffer::from_raw(height), "test").unwrap();
+        for budget in [None, Some(Budget::Points(8))]{
+            let settings = LodSettings{ mods: vec![20,10], simplify: vec![Simplify::None; 2], budget, ..Default::default() };
+            let outputs = chunkify_heightlines(&hf, &settings, &mut logger).unwrap();
+            kinds.extend(chunk_kinds(&outputs));
+            let info = outputs.iter().find(|o| o.name == "chunks.info").unwrap();
+            assert!(matches!(decode(&info.buffer), Decoded::ChunksInfo(i) if i.mods == vec![20,10]));
+        }
+        let styles = vec![(1,2,3,4), (5,6,7,8), (9,10,11,12)];
+        // the chunkers take the global boundingbox from the boundingboxes of the shapes
+        let mut geopolys = polygonzs().into_iter().map(|p| PolygonZ{
+            outers: p.outers.into_iter().map(|r| r.into_iter().map(|(x,y,z)| (x as u32, y as u32, z as u32)).collect()).collect(),
+            inners: Vec::new(),
+            bb: ((0,0,0),(0,0,0)),
+            style: p.style,
+        }).collect::<Vec<_>>();
+        geopolys.iter_mut().for_each(|p| p.stretch_bb());
+        let mut geolines = (0..3).map(|i| StyledLine{
+            points: square((100 + 300 * i, 400), 250, |a,b| a + b),
+            style: i as usize,
+            bb: ((0,0,0),(0,0,0)),
+        }).collect::<Vec<_>>();
+        geolines.iter_mut().for_each(|l| l.stretch_bb());
+        for budget in [None, Some(Budget::Points(8))]{
+            let settings = GeoLodSettings{ cuts: 2, budget, ..Default::default() };
+            let outputs = chunkify_geopolys(geopolys.clone(), styles.clone(), &settings, &mut logger).unwrap();
+            kinds.extend(chunk_kinds(&outputs));
+            let written = outputs.iter().find(|o| o.name == "styles").unwrap();
+            assert!(matches!(decode(&written.buffer), Decoded::Styles(s) if s == styles));
+            let outputs = chunkify_geolines(geolines.clone(), styles.clone(), &settings, &mut logger).unwrap();
+            kinds.extend(chunk_kinds(&outputs));
+        }
+        for k in FormatKind::ALL{
+            assert!(kinds.contains(&k), "no {} file was read back", k.name());
+        }
+    }
+
+    #[test]
+    fn geopolys_levels_cull_small_polygons(){
+        let ring = |(x,y): P2<u32>, side: u32| square((x,y), side, |a,b| a + b).into_iter().skip(1).map(|(x,y)| (x,y,0)).collect::<Vec<_>>();
+        // a big square with a small hole and a small square, the tolerance of the first level is about 2000 / 100
+        let mut polys = vec![
+            PolygonZ{ outers: vec![ring((0,0), 1000)], inners: vec![ring((100,100), 10)], bb: ((0,0,0),(0,0,0)), style: 0 },
+            PolygonZ{ outers: vec![ring((2000,2000), 10)], inners: Vec::new(), bb: ((0,0,0),(0,0,0)), style: 1 },
+        ];
+        polys.iter_mut().for_each(|p| p.stretch_bb());
+        let settings = GeoLodSettings{ cuts: 1, cuts_multi: 2, simplify: vec![Simplify::DouglasPeucker, Simplify::None], detail: 100.0, ..Default::default() };
+        let outputs = chunkify_geopolys(polys.clone(), vec![(0,1,1,1), (0,2,2,2)], &settings, &mut Logger::default()).unwrap();
+        // the area of every style on a level
+        let areas = |level: &str|{
+            let mut areas = [0.0; 2];
+            for o in outputs.iter().filter(|o| o.name.starts_with(level) && o.name.ends_with(".polychunk")){
+                let chunk = match decode(&o.buffer){ Decoded::PolyChunk(c) => c, _ => panic!("{} is no polychunk", o.name) };
+                for pt in &chunk.shapes{
+                    for t in pt.indices.chunks_exact(3){
+                        let [a,b,c] = [0,1,2].map(|k| chunk.quant.dequantize(pt.vertices[t[k] as usize]));
+                        areas[pt.style] += ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)).abs() / 2.0;
+                    }
+                }
+            }
+            areas
+        };
+        assert_eq!(areas("0-"), [1_000_000.0, 0.0]);
+        assert_eq!(areas("1-"), [1_000_000.0 - 100.0, 100.0]);
+        let names = outputs.iter().map(|o| o.name.as_str()).collect::<Vec<_>>();
+        assert!(names.contains(&"0-0-0.polychunk") && names.contains(&"1-1-1.polychunk"));
+        // every can not be used for polygons
+        let every = GeoLodSettings{ simplify: vec![Simplify::Every], ..Default::default() };
+        assert!(chunkify_geopolys(polys, Vec::new(), &every, &mut Logger::default()).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::{
+    data::{ BB, P2, P3, Vvec, ShapeZ, PolygonZ, StyledLine },
+    triangulate::PolyTriangle,
+    header::FLAG_DELTA,
+    error::{ Error, Result },
+};
+
+use std::str::FromStr;
+
+use bin_buffer::*;
+
+// How the points of the shapes in a file are written, flagged in the header
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Encoding{
+    // every coordinate as an integer of the compression type
+    Raw,
+    // every point as zigzag varints of the difference with the point before it
+    Delta,
+}
+
+impl Encoding{
+    pub fn flags(self) -> u8{
+        match self{
+            Encoding::Raw => 0,
+            Encoding::Delta => FLAG_DELTA,
+        }
+    }
+
+    pub fn from_flags(flags: u8) -> Self{
+        if flags & FLAG_DELTA != 0 { Encoding::Delta } else { Encoding::Raw }
+    }
+}
+
+// How the shapes of a file are written: the encoding and the size in bytes of x, y and z
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct Layout{
+    pub encoding: Encoding,
+    pub sizes: P3<u8>,
+}
+
+impl FromStr for Encoding{
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "raw" => Ok(Encoding::Raw),
+            "delta" => Ok(Encoding::Delta),
+            x => Err(Error::Argument(format!("Unknown encoding: {}, expected raw or delta", x))),
+        }
+    }
+}
+
+// Unsigned LEB128: 7 bits per byte, the high bit is set when more bytes follow
+pub fn write_varint(mut v: u64, buf: &mut Buffer){
+    while v >= 0x80{
+        buf.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+pub fn read_varint(buf: &mut ReadBuffer) -> Option<u64>{
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7){
+        let b = u8::from_buffer(buf)?;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 { return Some(v); }
+    }
+    None
+}
+
+// Small negative and positive numbers both become small unsigned ones: 0, -1, 1, -2 → 0, 1, 2, 3
+fn zigzag(v: i64) -> u64{
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64{
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+// The number of points as a varint, then every point as the zigzag varint difference
+// with the point before it, per coordinate. The first point is relative to zero.
+// The differences wrap around, so any i64 comes back as it was.
+fn write_deltas<const N: usize>(points: &[[i64;N]], buf: &mut Buffer){
+    write_varint(points.len() as u64, buf);
+    let mut prev = [0i64;N];
+    for p in points{
+        for i in 0..N{
+            write_varint(zigzag(p[i].wrapping_sub(prev[i])), buf);
+        }
+        prev = *p;
+    }
+}
+
+fn read_deltas<const N: usize>(buf: &mut ReadBuffer) -> Option<Vec<[i64;N]>>{
+    let len = read_varint(buf)?;
+    let mut points = Vec::new();
+    let mut prev = [0i64;N];
+    for _ in 0..len{
+        for c in prev.iter_mut(){
+            *c = c.wrapping_add(unzigzag(read_varint(buf)?));
+        }
+        points.push(prev);
+    }
+    Some(points)
+}
+
+// A coordinate in the given number of bytes, the value fits as the type was chosen for it
+fn write_coord(v: u32, size: u8, buf: &mut Buffer){
+    match size{
+        1 => (v as u8).into_buffer(buf),
+        2 => (v as u16).into_buffer(buf),
+        _ => v.into_buffer(buf),
+    }
+}
+
+fn read_coord(size: u8, buf: &mut ReadBuffer) -> Option<u32>{
+    match size{
+        1 => u8::from_buffer(buf).map(u32::from),
+        2 => u16::from_buffer(buf).map(u32::from),
+        4 => u32::from_buffer(buf),
+        _ => None,
+    }
+}
+
+fn write_p3((x,y,z): P3<u32>, (sx,sy,sz): P3<u8>, buf: &mut Buffer){
+    write_coord(x, sx, buf);
+    write_coord(y, sy, buf);
+    write_coord(z, sz, buf);
+}
+
+fn read_p3((sx,sy,sz): P3<u8>, buf: &mut ReadBuffer) -> Option<P3<u32>>{
+    Some((read_coord(sx, buf)?, read_coord(sy, buf)?, read_coord(sz, buf)?))
+}
+
+pub fn write_bb(bb: BB<u32>, sizes: P3<u8>, buf: &mut Buffer){
+    write_p3(bb.0, sizes, buf);
+    write_p3(bb.1, sizes, buf);
+}
+
+pub fn read_bb(sizes: P3<u8>, buf: &mut ReadBuffer) -> Option<BB<u32>>{
+    Some((read_p3(sizes, buf)?, read_p3(sizes, buf)?))
+}
+
+// Raw: the length as u64 and every point with the sizes of x and y
+fn write_line(points: &[P2<u32>], layout: Layout, buf: &mut Buffer){
+    if layout.encoding == Encoding::Delta{
+        let flat = points.iter().map(|(x,y)| [*x as i64, *y as i64]).collect::<Vec<_>>();
+        return write_deltas(&flat, buf);
+    }
+    (points.len() as u64).into_buffer(buf);
+    for (x,y) in points{
+        write_coord(*x, layout.sizes.0, buf);
+        write_coord(*y, layout.sizes.1, buf);
+    }
+}
+
+fn read_line(layout: Layout, buf: &mut ReadBuffer) -> Option<Vec<P2<u32>>>{
+    if layout.encoding == Encoding::Delta{
+        return read_deltas::<2>(buf)?.into_iter()
+            .map(|[x,y]| Some((u32::try_from(x).ok()?, u32::try_from(y).ok()?)))
+            .collect();
+    }
+    let len = u64::from_buffer(buf)?;
+    let mut points = Vec::new();
+    for _ in 0..len{
+        points.push((read_coord(layout.sizes.0, buf)?, read_coord(layout.sizes.1, buf)?));
+    }
+    Some(points)
+}
+
+// The list of rings: its length, as u64 when raw and as varint when delta, then every ring
+fn write_rings(rings: &[Vec<P3<u32>>], layout: Layout, buf: &mut Buffer){
+    if layout.encoding == Encoding::Raw{
+        (rings.len() as u64).into_buffer(buf);
+        for ring in rings{
+            (ring.len() as u64).into_buffer(buf);
+            for p in ring{
+                write_p3(*p, layout.sizes, buf);
+            }
+        }
+        return;
+    }
+    write_varint(rings.len() as u64, buf);
+    for ring in rings{
+        let flat = ring.iter().map(|(x,y,z)| [*x as i64, *y as i64, *z as i64]).collect::<Vec<_>>();
+        write_deltas(&flat, buf);
+    }
+}
+
+fn read_rings(layout: Layout, buf: &mut ReadBuffer) -> Option<Vvec<P3<u32>>>{
+    let mut rings = Vec::new();
+    if layout.encoding == Encoding::Raw{
+        let len = u64::from_buffer(buf)?;
+        for _ in 0..len{
+            let points = u64::from_buffer(buf)?;
+            let mut ring = Vec::new();
+            for _ in 0..points{
+                ring.push(read_p3(layout.sizes, buf)?);
+            }
+            rings.push(ring);
+        }
+        return Some(rings);
+    }
+    let len = read_varint(buf)?;
+    for _ in 0..len{
+        let ring = read_deltas::<3>(buf)?.into_iter()
+            .map(|[x,y,z]| Some((u32::try_from(x).ok()?, u32::try_from(y).ok()?, u32::try_from(z).ok()?)))
+            .collect::<Option<Vec<_>>>()?;
+        rings.push(ring);
+    }
+    Some(rings)
+}
+
+// Ability to be written with a given layout.
+// Shapes are quantized to u32 and written with the size of every axis.
+pub trait Encodable: Sized{
+    fn encode(self, layout: Layout, buf: &mut Buffer);
+    fn decode(layout: Layout, buf: &mut ReadBuffer) -> Option<Self>;
+}
+
+// Same layout as the Bufferable Vec: the length as u64, then the elements
+impl<S: Encodable> Encodable for Vec<S>{
+    fn encode(self, layout: Layout, buf: &mut Buffer){
+        (self.len() as u64).into_buffer(buf);
+        for s in self{
+            s.encode(layout, buf);
+        }
+    }
+
+    fn decode(layout: Layout, buf: &mut ReadBuffer) -> Option<Self>{
+        let len = u64::from_buffer(buf)?;
+        let mut vec = Vec::new();
+        for _ in 0..len{
+            vec.push(S::decode(layout, buf)?);
+        }
+        Some(vec)
+    }
+}
+
+impl Encodable for ShapeZ<u32>{
+    fn encode(self, layout: Layout, buf: &mut Buffer){
+        write_coord(self.z, layout.sizes.2, buf);
+        write_bb(self.bb, layout.sizes, buf);
+        write_line(&self.points, layout, buf);
+    }
+
+    fn decode(layout: Layout, buf: &mut ReadBuffer) -> Option<Self>{
+        let z = read_coord(layout.sizes.2, buf)?;
+        let bb = read_bb(layout.sizes, buf)?;
+        Some(Self{
+            points: read_line(layout, buf)?,
+            z,
+            bb,
+        })
+    }
+}
+
+impl Encodable for StyledLine<u32>{
+    fn encode(self, layout: Layout, buf: &mut Buffer){
+        self.style.into_buffer(buf);
+        write_bb(self.bb, layout.sizes, buf);
+        write_line(&self.points, layout, buf);
+    }
+
+    fn decode(layout: Layout, buf: &mut ReadBuffer) -> Option<Self>{
+        let style = usize::from_buffer(buf)?;
+        let bb = read_bb(layout.sizes, buf)?;
+        Some(Self{
+            points: read_line(layout, buf)?,
+            style,
+            bb,
+        })
+    }
+}
+
+impl Encodable for PolygonZ<u32>{
+    fn encode(self, layout: Layout, buf: &mut Buffer){
+        write_bb(self.bb, layout.sizes, buf);
+        write_rings(&self.outers, layout, buf);
+        write_rings(&self.inners, layout, buf);
+        self.style.into_buffer(buf);
+    }
+
+    fn decode(layout: Layout, buf: &mut ReadBuffer) -> Option<Self>{
+        let bb = read_bb(layout.sizes, buf)?;
+        let outers = read_rings(layout, buf)?;
+        let inners = read_rings(layout, buf)?;
+        let style = usize::from_buffer(buf)?;
+        Some(Self{
+            outers,
+            inners,
+            bb,
+            style,
+        })
+    }
+}
+
+// The vertices are one line, the indices stay u16
+impl Encodable for PolyTriangle<u32>{
+    fn encode(self, layout: Layout, buf: &mut Buffer){
+        write_line(&self.vertices, layout, buf);
+        self.indices.into_buffer(buf);
+        self.style.into_buffer(buf);
+        write_bb(self.bb, layout.sizes, buf);
+    }
+
+    fn decode(layout: Layout, buf: &mut ReadBuffer) -> Option<Self>{
+        let vertices = read_line(layout, buf)?;
+        let indices = Vec::<u16>::from_buffer(buf)?;
+        let style = usize::from_buffer(buf)?;
+        let bb = read_bb(layout.sizes, buf)?;
+        Some(Self{
+            vertices,
+            indices,
+            style,
+            bb,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn varint_bytes(v: u64) -> Buffer{
+        let mut buf = Vec::new();
+        write_varint(v, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn varints(){
+        assert_eq!(varint_bytes(0), vec![0]);
+        assert_eq!(varint_bytes(127), vec![0x7f]);
+        assert_eq!(varint_bytes(128), vec![0x80, 0x01]);
+        assert_eq!(varint_bytes(300), vec![0xac, 0x02]);
+        assert_eq!(varint_bytes(u64::MAX).len(), 10);
+        for v in [0, 1, 127, 128, 16_383, 16_384, u32::MAX as u64, u64::MAX - 1, u64::MAX]{
+            let mut buf = ReadBuffer::from_raw(varint_bytes(v));
+            assert_eq!(read_varint(&mut buf), Some(v));
+        }
+        // a varint that stops halfway
+        assert_eq!(read_varint(&mut ReadBuffer::from_raw(vec![0x80, 0x80])), None);
+    }
+
+    #[test]
+    fn zigzags(){
+        for (v,z) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (2, 4), (i64::MAX, u64::MAX - 1), (i64::MIN, u64::MAX)]{
+            assert_eq!(zigzag(v), z);
+            assert_eq!(unzigzag(z), v);
+        }
+    }
+
+    #[test]
+    fn deltas(){
+        let points = vec![[0, 0], [5, -3], [-70_000, 12], [-70_001, 12], [i64::MAX, i64::MIN], [i64::MIN, i64::MAX], [0, 0]];
+        let mut buf = Vec::new();
+        write_deltas(&points, &mut buf);
+        // one byte for the count and one for every coordinate of the first point
+        assert_eq!(&buf[..3], &[7, 0, 0]);
+        let mut read = ReadBuffer::from_raw(buf);
+        assert_eq!(read_deltas::<2>(&mut read), Some(points));
+        // an empty line is just its count
+        let mut buf = Vec::new();
+        write_deltas::<3>(&[], &mut buf);
+        assert_eq!(buf, vec![0]);
+        assert_eq!(read_deltas::<3>(&mut ReadBuffer::from_raw(buf)), Some(Vec::new()));
+    }
+}
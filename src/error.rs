@@ -0,0 +1,123 @@
+use std::fmt;
+
+// Everything that can go wrong while reading, processing or writing files.
+// Errors about files carry the path, kml errors also the line and element they happened at
+// and dbf and geojson errors the index of the record or feature.
+#[derive(Debug)]
+pub enum Error{
+    // Could not read or write a file at all
+    Io{ path: String, cause: std::io::Error },
+    // The shapefile crate could not read the file
+    Shapefile{ path: String, cause: shapefile::Error },
+    // A shape has an attribute in the .dbf table we can not use
+    Dbf{ path: String, record: usize, cause: String },
+    // The file is not valid xml
+    Xml{ path: String, cause: xml::reader::Error },
+    // The file is valid xml but the kml content is not what we expect
+    Kml{ path: String, line: u64, element: String, cause: String },
+    // The file is not valid json
+    Json{ path: String, cause: json::Error },
+    // The file is valid json but the geojson content is not what we expect
+    GeoJson{ path: String, feature: usize, cause: String },
+    // The coordinate reference system of a file is not supported or can not be projected
+    Crs{ path: String, cause: String },
+    // A binary file is not in the format we expect
+    Format{ path: String, cause: String },
+    // Invalid options or arguments
+    Argument(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error{
+    // Short hand to build a kml error
+    pub fn kml(path: &str, line: u64, element: &str, cause: impl Into<String>) -> Self{
+        Error::Kml{
+            path: path.to_string(),
+            line,
+            element: element.to_string(),
+            cause: cause.into(),
+        }
+    }
+
+    // Short hand to build a dbf attribute error
+    pub fn dbf(path: &str, record: usize, cause: impl Into<String>) -> Self{
+        Error::Dbf{
+            path: path.to_string(),
+            record,
+            cause: cause.into(),
+        }
+    }
+
+    // Short hand to build a geojson error
+    pub fn geojson(path: &str, feature: usize, cause: impl Into<String>) -> Self{
+        Error::GeoJson{
+            path: path.to_string(),
+            feature,
+            cause: cause.into(),
+        }
+    }
+
+    // Short hand to build a crs error
+    pub fn crs(path: &str, cause: impl Into<String>) -> Self{
+        Error::Crs{
+            path: path.to_string(),
+            cause: cause.into(),
+        }
+    }
+
+    // Short hand to build a binary format error
+    pub fn format(path: &str, cause: impl Into<String>) -> Self{
+        Error::Format{
+            path: path.to_string(),
+            cause: cause.into(),
+        }
+    }
+
+    // Short hand to build an io error
+    pub fn io(path: &str, cause: std::io::Error) -> Self{
+        Error::Io{
+            path: path.to_string(),
+            cause,
+        }
+    }
+}
+
+impl fmt::Display for Error{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            Error::Io{ path, cause } =>
+                write!(f, "\"{}\": {}", path, cause),
+            Error::Shapefile{ path, cause } =>
+                write!(f, "\"{}\": could not read shapefile: {}", path, cause),
+            Error::Dbf{ path, record, cause } =>
+                write!(f, "\"{}\" record {}: {}", path, record, cause),
+            Error::Xml{ path, cause } =>
+                write!(f, "\"{}\": invalid xml: {}", path, cause),
+            Error::Kml{ path, line, element, cause } =>
+                write!(f, "\"{}\" line {}, <{}>: {}", path, line, element, cause),
+            Error::Json{ path, cause } =>
+                write!(f, "\"{}\": invalid json: {}", path, cause),
+            Error::GeoJson{ path, feature, cause } =>
+                write!(f, "\"{}\" feature {}: {}", path, feature, cause),
+            Error::Crs{ path, cause } =>
+                write!(f, "\"{}\": {}", path, cause),
+            Error::Format{ path, cause } =>
+                write!(f, "\"{}\": {}", path, cause),
+            Error::Argument(msg) =>
+                write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+        match self{
+            Error::Io{ cause, .. } => Some(cause),
+            Error::Shapefile{ cause, .. } => Some(cause),
+            Error::Xml{ cause, .. } => Some(cause),
+            Error::Json{ cause, .. } => Some(cause),
+            _ => None,
+        }
+    }
+}
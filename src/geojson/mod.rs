@@ -0,0 +1,7 @@
+// GeoJSON: reading it as input and writing shapes out to look at them.
+
+mod read;
+mod write;
+
+pub use self::read::*;
+pub use self::write::*;
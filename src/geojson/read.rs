@@ -0,0 +1,396 @@
+use crate::{
+    data::*,
+    crs::{ Crs, Points },
+    error::{ Error, Result },
+    logger::*,
+};
+
+use std::collections::HashMap;
+
+use json::JsonValue;
+
+// Read GeoJSON input into the same raw data the shapefile and kml readers give.
+// A file is parsed once, its crs, first point and shapes are all read from that.
+
+// A feature of the input: its index in the file, its geometry and its properties
+struct Feature<'a>{
+    index: usize,
+    geometry: &'a JsonValue,
+    properties: &'a JsonValue,
+}
+
+// A point as it is in the file, z is optional
+type Position = (f64,f64,Option<f64>);
+
+// The features of a FeatureCollection, a single Feature or a bare geometry
+fn features<'a>(root: &'a JsonValue, path: &str) -> Result<Vec<Feature<'a>>>{
+    match root["type"].as_str(){
+        Some("FeatureCollection") => Ok(root["features"].members().enumerate()
+            .map(|(index,f)| Feature{ index, geometry: &f["geometry"], properties: &f["properties"] })
+            .collect()),
+        Some("Feature") => Ok(vec![Feature{ index: 0, geometry: &root["geometry"], properties: &root["properties"] }]),
+        Some(_) => Ok(vec![Feature{ index: 0, geometry: root, properties: &JsonValue::Null }]),
+        None => Err(Error::geojson(path, 0, "no \"type\", this is not a geojson file")),
+    }
+}
+
+// The first position in a geometry, nested as deep as it is
+fn first_position(v: &JsonValue) -> Option<(f64,f64)>{
+    match (v[0].as_f64(), v[1].as_f64()){
+        (Some(x), Some(y)) => Some((x,y)),
+        _ => v["coordinates"].members().chain(v["geometries"].members()).chain(v.members())
+            .find_map(first_position),
+    }
+}
+
+// A parsed geojson file
+pub struct GeoJsonFile{
+    path: String,
+    root: JsonValue,
+}
+
+impl GeoJsonFile{
+    pub fn open(path: &str) -> Result<Self>{
+        let text = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        Self::parse(path, &text)
+    }
+
+    fn parse(path: &str, text: &str) -> Result<Self>{
+        let root = json::parse(text).map_err(|cause| Error::Json{ path: path.to_string(), cause })?;
+        Ok(Self{ path: path.to_string(), root })
+    }
+
+    pub fn path(&self) -> &str{
+        &self.path
+    }
+
+    // RFC 7946 GeoJSON is always WGS84 lon/lat.
+    // Older files can name another crs, if we do not know it the crs is unknown.
+    pub fn crs(&self) -> Option<Crs>{
+        match self.root["crs"]["properties"]["name"].as_str(){
+            None => Some(Crs::Wgs84),
+            Some(name) => name.parse().ok(),
+        }
+    }
+
+    // The first point as it is in the file, to pick a utm zone with
+    pub fn first_point(&self) -> Result<Option<(f64,f64)>>{
+        Ok(self.features()?.iter().find_map(|f| first_position(f.geometry)))
+    }
+
+    fn features(&self) -> Result<Vec<Feature<'_>>>{
+        features(&self.root, &self.path)
+    }
+}
+
+// Turns json coordinates into points, knows where it is for errors
+struct Reader<'a>{
+    path: &'a str,
+    index: usize,
+    points: Points,
+}
+
+impl Reader<'_>{
+    fn error(&self, cause: impl Into<String>) -> Error{
+        Error::geojson(self.path, self.index, cause)
+    }
+
+    fn position(&self, v: &JsonValue) -> Result<Position>{
+        let comp = |i: usize| v[i].as_f64();
+        let (x,y) = match (comp(0), comp(1)){
+            (Some(x), Some(y)) => (x,y),
+            _ => return Err(self.error(format!("invalid position {}", v.dump()))),
+        };
+        let (x,y) = self.points.xy((x,y));
+        Ok((x, y, comp(2)))
+    }
+
+    fn line(&self, v: &JsonValue) -> Result<Vec<Position>>{
+        if !v.is_array(){
+            return Err(self.error("coordinates are not an array"));
+        }
+        v.members().map(|p| self.position(p)).collect()
+    }
+
+    fn polygon(&self, v: &JsonValue) -> Result<Vvec<Position>>{
+        if !v.is_array(){
+            return Err(self.error("coordinates are not an array"));
+        }
+        v.members().map(|r| self.line(r)).collect()
+    }
+
+    // all lines of a (Multi)LineString or a GeometryCollection of them
+    fn lines(&self, geometry: &JsonValue) -> Result<Vvec<Position>>{
+        let coords = &geometry["coordinates"];
+        match geometry["type"].as_str(){
+            Some("LineString") => Ok(vec![self.line(coords)?]),
+            Some("MultiLineString") => self.polygon(coords),
+            Some("GeometryCollection") => {
+                let mut res = Vec::new();
+                for g in geometry["geometries"].members(){
+                    res.append(&mut self.lines(g)?);
+                }
+                Ok(res)
+            },
+            Some(t) => Err(self.error(format!("expected a LineString or MultiLineString, got a {}", t))),
+            None => Err(self.error("no geometry")),
+        }
+    }
+
+    // all polygons of a (Multi)Polygon or a GeometryCollection of them
+    // the first ring of a polygon is the outer ring, the others are holes
+    fn polygons(&self, geometry: &JsonValue) -> Result<Vec<Vvec<Position>>>{
+        let coords = &geometry["coordinates"];
+        match geometry["type"].as_str(){
+            Some("Polygon") => Ok(vec![self.polygon(coords)?]),
+            Some("MultiPolygon") => {
+                if !coords.is_array(){
+                    return Err(self.error("coordinates are not an array"));
+                }
+                coords.members().map(|p| self.polygon(p)).collect()
+            },
+            Some("GeometryCollection") => {
+                let mut res = Vec::new();
+                for g in geometry["geometries"].members(){
+                    res.append(&mut self.polygons(g)?);
+                }
+                Ok(res)
+            },
+            Some(t) => Err(self.error(format!("expected a Polygon or MultiPolygon, got a {}", t))),
+            None => Err(self.error("no geometry")),
+        }
+    }
+
+    // a property that is a number or a string with a number in it
+    fn number(&self, props: &JsonValue, field: &str) -> Result<f64>{
+        let v = &props[field];
+        if v.is_null(){
+            return Err(self.error(format!("no property \"{}\"", field)));
+        }
+        v.as_f64()
+            .or_else(|| v.as_str().and_then(|s| s.trim().parse().ok()))
+            .ok_or_else(|| self.error(format!("property \"{}\" is not a number", field)))
+    }
+
+    // "#rrggbb", missing colours are grey
+    fn colour(&self, props: &JsonValue, field: &str) -> Result<(u8,u8,u8)>{
+        let v = &props[field];
+        if v.is_null() { return Ok((128,128,128)); }
+        let s = v.as_str().unwrap_or_default();
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let c = |i: usize| hex.get(i..i+2).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (hex.len(), c(0), c(2), c(4)){
+            (6, Some(r), Some(g), Some(b)) => Ok((r,g,b)),
+            _ => Err(self.error(format!("invalid colour {} in \"{}\"", v.dump(), field))),
+        }
+    }
+}
+
+// the style id of a feature, numbers and strings both work
+fn style_key(props: &JsonValue, field: &str) -> Option<String>{
+    let v = &props[field];
+    if let Some(s) = v.as_str() { Some(s.to_string()) }
+    else if v.is_number() { Some(v.dump()) }
+    else { None }
+}
+
+// the heightlines of one feature
+fn feature_heightlines(r: &Reader, f: &Feature, height_field: Option<&str>) -> Result<VvP4>{
+    let z = match height_field{
+        Some(field) => Some(r.number(f.properties, field)?),
+        None => None,
+    };
+    let mut lines = Vec::new();
+    for line in r.lines(f.geometry)?{
+        let mut points = Vec::new();
+        for (x,y,pz) in line{
+            let z = z.or(pz).ok_or_else(|| r.error("no height, the points have no z and no height field is given"))?;
+            points.push((x, y, r.points.z(z), 0.0));
+        }
+        lines.push(points);
+    }
+    Ok(lines)
+}
+
+// parse heightlines from a geojson file
+// the height comes from the height field property or else from the z of every point
+// features that can not be parsed are skipped
+pub fn geojson_height(file: &GeoJsonFile, points: &Points, height_field: Option<&str>, logger: &mut Logger) -> Result<VvP4>{
+    let mut vvp4 = Vec::new();
+    for f in file.features()?{
+        let r = Reader{ path: file.path(), index: f.index, points: *points };
+        match feature_heightlines(&r, &f, height_field){
+            Ok(mut lines) => vvp4.append(&mut lines),
+            Err(e) => logger.skip(Issue::SkippedFeature, e),
+        }
+    }
+    Ok(vvp4)
+}
+
+// Shared by the polygon and line readers: gives the style index of a feature,
+// adding a style the first time its id is seen in this file.
+// Only asked for features whose geometry could be read, so skipped features add no styles.
+struct StyleMap<'a>{
+    field: &'a str,
+    ids: HashMap<String,usize>,
+    styles: &'a mut Vec<(u8,u8,u8,u8)>,
+    counter: &'a mut usize,
+}
+
+impl StyleMap<'_>{
+    fn get(&mut self, f: &Feature, logger: &mut Logger, make: impl Fn() -> Result<(u8,u8,u8,u8)>) -> Option<usize>{
+        let key = if let Some(key) = style_key(f.properties, self.field){ key }
+        else {
+            logger.log(Issue::EmptyStyleId);
+            return None;
+        };
+        if let Some(id) = self.ids.get(&key){
+            return Some(*id);
+        }
+        match make(){
+            Ok(style) => {
+                self.styles.push(style);
+                self.ids.insert(key, *self.counter);
+                *self.counter += 1;
+                Some(*self.counter - 1)
+            },
+            Err(e) => {
+                logger.skip(Issue::SkippedStyle, e);
+                None
+            },
+        }
+    }
+}
+
+// parse polygons from a geojson file
+// the style id is the style field property, the colour comes from "fill" and the outline from "outline"
+// features that can not be parsed are skipped
+pub fn geojson_geo(file: &GeoJsonFile, points: &Points, style_field: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize, logger: &mut Logger)
+    -> Result<Vec<(usize,(VvP4,VvP4))>>
+{
+    let mut map = StyleMap{ field: style_field, ids: HashMap::new(), styles, counter };
+    let mut polys = Vec::new();
+    for f in file.features()?{
+        let r = Reader{ path: file.path(), index: f.index, points: *points };
+        let polygons = match r.polygons(f.geometry){
+            Ok(polygons) => polygons,
+            Err(e) => { logger.skip(Issue::SkippedFeature, e); continue; },
+        };
+        let style = map.get(&f, logger, ||{
+            let (red,green,blue) = r.colour(f.properties, "fill")?;
+            let outline = if r.number(f.properties, "outline").unwrap_or(0.0) >= 1.0 { 1 } else { 0 };
+            Ok((outline,red,green,blue))
+        });
+        let style = if let Some(style) = style { style } else { continue; };
+        for mut rings in polygons{
+            if rings.is_empty() { continue; }
+            let conv = |ring: Vec<Position>| ring.into_iter().map(|(x,y,z)| (x, y, points.z(z.unwrap_or(0.0)), 0.0)).collect();
+            let inners = rings.split_off(1).into_iter().map(conv).collect();
+            let outers = rings.into_iter().map(conv).collect();
+            polys.push((style,(outers,inners)));
+        }
+    }
+    Ok(polys)
+}
+
+// parse lines from a geojson file
+// the style id is the style field property, the colour comes from "stroke" and the width from "stroke-width"
+// features that can not be parsed are skipped
+pub fn geojson_geo_lines(file: &GeoJsonFile, points: &Points, style_field: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize, logger: &mut Logger)
+    -> Result<Vec<(usize,VvP2)>>
+{
+    let mut map = StyleMap{ field: style_field, ids: HashMap::new(), styles, counter };
+    let mut lines = Vec::new();
+    for f in file.features()?{
+        let r = Reader{ path: file.path(), index: f.index, points: *points };
+        let ls = match r.lines(f.geometry){
+            Ok(ls) => ls,
+            Err(e) => { logger.skip(Issue::SkippedFeature, e); continue; },
+        };
+        let style = map.get(&f, logger, ||{
+            let (red,green,blue) = r.colour(f.properties, "stroke")?;
+            let width = r.number(f.properties, "stroke-width").unwrap_or(1.0).round().clamp(0.0, 255.0) as u8;
+            Ok((width,red,green,blue))
+        });
+        let style = if let Some(style) = style { style } else { continue; };
+        lines.push((style, ls.into_iter().map(|l| l.into_iter().map(|(x,y,_)| (x,y)).collect()).collect()));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn file(features: &str) -> GeoJsonFile{
+        GeoJsonFile::parse("test.geojson", &format!("{{\"type\":\"FeatureCollection\",\"features\":[{}]}}", features)).unwrap()
+    }
+
+    fn feature(geometry: &str, properties: &str) -> String{
+        format!("{{\"type\":\"Feature\",\"geometry\":{},\"properties\":{}}}", geometry, properties)
+    }
+
+    #[test]
+    fn polygon_with_holes(){
+        let f = file(&feature(
+            r##"{"type":"Polygon","coordinates":[[[0,0],[10,0],[10,10],[0,10],[0,0]],[[2,2],[4,2],[4,4],[2,2]],[[6,6],[8,6],[8,8],[6,6]]]}"##,
+            r##"{"style":"a","fill":"#ff0000"}"##,
+        ));
+        let (mut styles, mut counter) = (Vec::new(), 0);
+        let polys = geojson_geo(&f, &Points::raw(), "style", &mut styles, &mut counter, &mut Logger::default()).unwrap();
+        assert_eq!(polys.len(), 1);
+        let (style,(outers,inners)) = &polys[0];
+        assert_eq!(*style, 0);
+        assert_eq!(outers.len(), 1);
+        assert_eq!(outers[0].len(), 5);
+        assert_eq!(inners.len(), 2);
+        assert_eq!(inners[1][0], (6.0,6.0,0.0,0.0));
+        assert_eq!(styles, vec![(0,255,0,0)]);
+    }
+
+    #[test]
+    fn multilinestring(){
+        let f = file(&feature(
+            r##"{"type":"MultiLineString","coordinates":[[[0,0],[1,1]],[[2,2],[3,3],[4,4]]]}"##,
+            r##"{"style":1,"stroke":"#00ff00","stroke-width":3}"##,
+        ));
+        let (mut styles, mut counter) = (Vec::new(), 0);
+        let lines = geojson_geo_lines(&f, &Points::raw(), "style", &mut styles, &mut counter, &mut Logger::default()).unwrap();
+        assert_eq!(lines, vec![(0, vec![vec![(0.0,0.0),(1.0,1.0)], vec![(2.0,2.0),(3.0,3.0),(4.0,4.0)]])]);
+        assert_eq!(styles, vec![(3,0,255,0)]);
+    }
+
+    #[test]
+    fn missing_height_field_skips_the_feature(){
+        let line = r##"{"type":"LineString","coordinates":[[0,0],[1,1]]}"##;
+        let f = file(&[feature(line, r##"{"height":"12.5"}"##), feature(line, r##"{"other":3}"##)].join(","));
+        let lines = geojson_height(&f, &Points::raw(), Some("height"), &mut Logger::default()).unwrap();
+        assert_eq!(lines, vec![vec![(0.0,0.0,12.5,0.0),(1.0,1.0,12.5,0.0)]]);
+        // without a height field the z of the points is used, these have none
+        let lines = geojson_height(&f, &Points::raw(), None, &mut Logger::default()).unwrap();
+        assert!(lines.is_empty());
+    }
+
+    #[test]
+    fn bad_geometry_is_skipped_without_a_style(){
+        let f = file(&[
+            feature(r##"{"type":"Polygon","coordinates":[[[0,0],["x",1],[1,0]]]}"##, r##"{"style":"bad","fill":"#010203"}"##),
+            feature(r##"{"type":"Point","coordinates":[0,0]}"##, r##"{"style":"point"}"##),
+            feature(r##"{"type":"Polygon","coordinates":[[[0,0],[0,1],[1,0]]]}"##, r##"{"style":"good"}"##),
+        ].join(","));
+        let (mut styles, mut counter) = (Vec::new(), 0);
+        let polys = geojson_geo(&f, &Points::raw(), "style", &mut styles, &mut counter, &mut Logger::default()).unwrap();
+        assert_eq!(polys.len(), 1);
+        assert_eq!(polys[0].0, 0);
+        assert_eq!(styles, vec![(0,128,128,128)]);
+        assert_eq!(counter, 1);
+    }
+
+    #[test]
+    fn crs_and_first_point(){
+        let f = file(&feature(r##"{"type":"MultiLineString","coordinates":[[[5.5,52.1],[6,53]]]}"##, "{}"));
+        assert_eq!(f.crs(), Some(Crs::Wgs84));
+        assert_eq!(f.first_point().unwrap(), Some((5.5,52.1)));
+        assert!(GeoJsonFile::parse("test.geojson", "{}").unwrap().first_point().is_err());
+    }
+}
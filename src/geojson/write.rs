@@ -0,0 +1,222 @@
+use crate::{
+    data::*,
+    triangulate::PolyTriangle,
+};
+
+// Write shapes out as GeoJSON so any stage of the pipeline can be opened in QGIS and the like.
+// Coordinates are written as they are, or turned back into world coordinates
+// with the offset and multiplier when the shapes are quantized.
+
+// A shape that can be written as a GeoJSON feature
+pub trait GeoJsonFeature{
+    // the geometry object, points go through the transform
+    fn geometry(&self, tf: &Transform) -> String;
+    // extra properties next to the style, values that are points go through the transform
+    fn properties(&self, _tf: &Transform, _styles: &[(u8,u8,u8,u8)]) -> Vec<(String,String)>{
+        Vec::new()
+    }
+}
+
+// Turns (quantized) points back into world coordinates: p / multi + offset
+#[derive(Clone,Copy,Debug)]
+pub struct Transform{
+    pub offset: (f64,f64,f64),
+    pub multi: (f64,f64,f64),
+}
+
+impl Transform{
+    // points are already in world coordinates
+    pub fn identity() -> Self{
+        Self{ offset: (0.0,0.0,0.0), multi: (1.0,1.0,1.0) }
+    }
+
+    // every axis has its own offset and multiplier
+    pub fn new(mx: i64, my: i64, mz: i64, (multix,multiy,multiz): P3<u64>) -> Self{
+        Self{
+            offset: (mx as f64, my as f64, mz as f64),
+            multi: (multix.max(1) as f64, multiy.max(1) as f64, multiz.max(1) as f64),
+        }
+    }
+
+    fn xy<T: Copy + Into<f64>>(&self, x: T, y: T) -> String{
+        format!("[{},{}]", x.into() / self.multi.0 + self.offset.0, y.into() / self.multi.1 + self.offset.1)
+    }
+
+    fn z<T: Copy + Into<f64>>(&self, z: T) -> f64{
+        z.into() / self.multi.2 + self.offset.2
+    }
+
+    fn xyz<T: Copy + Into<f64>>(&self, x: T, y: T, z: T) -> String{
+        format!("[{},{},{}]", x.into() / self.multi.0 + self.offset.0, y.into() / self.multi.1 + self.offset.1, self.z(z))
+    }
+}
+
+fn join(items: impl Iterator<Item = String>) -> String{
+    items.collect::<Vec<_>>().join(",")
+}
+
+fn json_string(s: &str) -> String{
+    let mut res = String::from("\"");
+    for c in s.chars(){
+        match c{
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn colour((r,g,b): (u8,u8,u8)) -> String{
+    json_string(&format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+// rings are closed in GeoJSON, the first and last point are the same
+fn ring<T: Copy + Into<f64> + PartialEq>(points: &[P3<T>], tf: &Transform) -> String{
+    let mut coords: Vec<String> = points.iter().map(|(x,y,z)| tf.xyz(*x, *y, *z)).collect();
+    if let (Some(first), Some(last)) = (points.first(), points.last()){
+        if first != last{
+            coords.push(tf.xyz(first.0, first.1, first.2));
+        }
+    }
+    format!("[{}]", coords.join(","))
+}
+
+// even-odd rule
+fn in_ring<T: Copy + Into<f64>>(ring: &[P3<T>], (px,py): P2<f64>) -> bool{
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len(){
+        let (xi,yi) = (ring[i].0.into(), ring[i].1.into());
+        let (xj,yj) = (ring[j].0.into(), ring[j].1.into());
+        if (yi > py) != (yj > py) && px < (xj - xi) * (py - yi) / (yj - yi) + xi{
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+impl<T: Copy + Into<f64>> GeoJsonFeature for ShapeZ<T>{
+    fn geometry(&self, tf: &Transform) -> String{
+        let coords = join(self.points.iter().map(|(x,y)| tf.xy(*x, *y)));
+        format!("{{\"type\":\"LineString\",\"coordinates\":[{}]}}", coords)
+    }
+
+    fn properties(&self, tf: &Transform, _styles: &[(u8,u8,u8,u8)]) -> Vec<(String,String)>{
+        vec![(String::from("z"), format!("{}", tf.z(self.z)))]
+    }
+}
+
+// The inner rings are not linked to an outer ring, so every inner ring becomes a hole
+// of the first outer ring it lies in.
+impl<T: Copy + Into<f64> + PartialEq> GeoJsonFeature for PolygonZ<T>{
+    fn geometry(&self, tf: &Transform) -> String{
+        let mut holes: Vec<Vec<&Vec<P3<T>>>> = vec![Vec::new(); self.outers.len()];
+        for inner in &self.inners{
+            let p = match inner.first(){
+                Some((x,y,_)) => ((*x).into(), (*y).into()),
+                None => continue,
+            };
+            if let Some(i) = self.outers.iter().position(|o| in_ring(o, p)){
+                holes[i].push(inner);
+            }
+        }
+        let polys = join(self.outers.iter().zip(holes.iter()).map(|(outer,inners)|{
+            let rings = std::iter::once(outer).chain(inners.iter().copied());
+            format!("[{}]", join(rings.map(|r| ring(r, tf))))
+        }));
+        format!("{{\"type\":\"MultiPolygon\",\"coordinates\":[{}]}}", polys)
+    }
+
+    fn properties(&self, _tf: &Transform, styles: &[(u8,u8,u8,u8)]) -> Vec<(String,String)>{
+        poly_style(self.style, styles)
+    }
+}
+
+impl<T: Copy + Into<f64>> GeoJsonFeature for PolyTriangle<T>{
+    fn geometry(&self, tf: &Transform) -> String{
+        let valid = |t: &&[u16]| t.len() == 3 && t.iter().all(|i| (*i as usize) < self.vertices.len());
+        let triangles = join(self.indices.chunks(3).filter(valid).map(|t|{
+            let v = |i: u16| { let (x,y) = self.vertices[i as usize]; tf.xy(x, y) };
+            format!("[[{},{},{},{}]]", v(t[0]), v(t[1]), v(t[2]), v(t[0]))
+        }));
+        format!("{{\"type\":\"MultiPolygon\",\"coordinates\":[{}]}}", triangles)
+    }
+
+    fn properties(&self, _tf: &Transform, styles: &[(u8,u8,u8,u8)]) -> Vec<(String,String)>{
+        poly_style(self.style, styles)
+    }
+}
+
+impl<T: Copy + Into<f64>> GeoJsonFeature for StyledLine<T>{
+    fn geometry(&self, tf: &Transform) -> String{
+        let coords = join(self.points.iter().map(|(x,y)| tf.xy(*x, *y)));
+        format!("{{\"type\":\"LineString\",\"coordinates\":[{}]}}", coords)
+    }
+
+    // line styles are (width,r,g,b)
+    fn properties(&self, _tf: &Transform, styles: &[(u8,u8,u8,u8)]) -> Vec<(String,String)>{
+        let mut props = vec![(String::from("style"), format!("{}", self.style))];
+        if let Some((w,r,g,b)) = styles.get(self.style){
+            props.push((String::from("stroke"), colour((*r,*g,*b))));
+            props.push((String::from("stroke-width"), format!("{}", w)));
+        }
+        props
+    }
+}
+
+// polygon styles are (outline,r,g,b)
+fn poly_style(style: usize, styles: &[(u8,u8,u8,u8)]) -> Vec<(String,String)>{
+    let mut props = vec![(String::from("style"), format!("{}", style))];
+    if let Some((o,r,g,b)) = styles.get(style){
+        props.push((String::from("fill"), colour((*r,*g,*b))));
+        props.push((String::from("outline"), format!("{}", o)));
+    }
+    props
+}
+
+// Collects features and turns them into a FeatureCollection
+#[derive(Default)]
+pub struct GeoJson{
+    features: Vec<String>,
+}
+
+impl GeoJson{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    // Add a shape with extra properties, like the chunk it is in.
+    // Extra property values need to be valid json.
+    pub fn add<S: GeoJsonFeature>(&mut self, shape: &S, tf: &Transform, styles: &[(u8,u8,u8,u8)], extra: &[(&str,String)]){
+        let props = shape.properties(tf, styles).into_iter()
+            .chain(extra.iter().map(|(k,v)| (k.to_string(), v.clone())))
+            .map(|(k,v)| format!("{}:{}", json_string(&k), v));
+        self.features.push(format!(
+            "{{\"type\":\"Feature\",\"geometry\":{},\"properties\":{{{}}}}}",
+            shape.geometry(tf), join(props)
+        ));
+    }
+
+    pub fn add_all<S: GeoJsonFeature>(&mut self, shapes: &[S], tf: &Transform, styles: &[(u8,u8,u8,u8)], extra: &[(&str,String)]){
+        for shape in shapes{
+            self.add(shape, tf, styles, extra);
+        }
+    }
+
+    pub fn len(&self) -> usize{
+        self.features.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.features.is_empty()
+    }
+
+    // One feature per line, so big files stay somewhat readable and diffable
+    pub fn finish(self) -> String{
+        format!("{{\"type\":\"FeatureCollection\",\"features\":[\n{}\n]}}\n", self.features.join(",\n"))
+    }
+}
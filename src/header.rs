@@ -0,0 +1,109 @@
+use crate::{
+    decode::FormatKind,
+    error::{ Error, Result },
+};
+
+use bin_buffer::*;
+
+// Every file we write starts with a header:
+// magic: [u8;4], kind: u8, version: u16, header length: u16, flags: u8
+// The header length is the length of the whole header in bytes, so fields can be added later
+// and older readers can skip them.
+pub const MAGIC: [u8; 4] = *b"SHPL";
+pub const HEADER_LEN: u16 = 10;
+
+// the points of the shapes are zigzag varint deltas, see encoding
+pub const FLAG_DELTA: u8 = 1;
+const KNOWN_FLAGS: u8 = FLAG_DELTA;
+
+#[derive(Clone,Copy,Debug)]
+pub struct Header{
+    pub kind: FormatKind,
+    pub version: u16,
+    pub header_len: u16,
+    pub flags: u8,
+}
+
+// Put the header for the given kind of file at the start of the buffer
+pub fn write_header(kind: FormatKind, flags: u8, buf: &mut Buffer){
+    buf.extend_from_slice(&MAGIC);
+    kind.code().into_buffer(buf);
+    kind.version().into_buffer(buf);
+    HEADER_LEN.into_buffer(buf);
+    flags.into_buffer(buf);
+}
+
+// A new buffer that starts with the header for the given kind, without flags
+pub fn header_buffer(kind: FormatKind) -> Buffer{
+    flagged_header_buffer(kind, 0)
+}
+
+pub fn flagged_header_buffer(kind: FormatKind, flags: u8) -> Buffer{
+    let mut buf = Vec::new();
+    write_header(kind, flags, &mut buf);
+    buf
+}
+
+// Read the header, fails if it is not one of our files or if the version is not the one we write.
+// If a kind is expected, fails if the file is of another kind.
+pub fn read_header(buf: &mut ReadBuffer, path: &str, expected: Option<FormatKind>) -> Result<Header>{
+    let eof = || Error::format(path, "file too short to have a header");
+    let mut magic = [0u8; 4];
+    for m in magic.iter_mut(){
+        *m = u8::from_buffer(buf).ok_or_else(eof)?;
+    }
+    if magic != MAGIC{
+        return Err(Error::format(path, "no magic bytes, not a file written by shapefile-linter or written by a version without headers"));
+    }
+    let code = u8::from_buffer(buf).ok_or_else(eof)?;
+    let version = u16::from_buffer(buf).ok_or_else(eof)?;
+    let header_len = u16::from_buffer(buf).ok_or_else(eof)?;
+    let kind = FormatKind::from_code(code)
+        .ok_or_else(|| Error::format(path, format!("unknown format kind {}", code)))?;
+    if let Some(exp) = expected{
+        if exp != kind{
+            return Err(Error::format(path, format!("expected a {} file but this is a {} file", exp.name(), kind.name())));
+        }
+    }
+    if version != kind.version(){
+        return Err(Error::format(path, format!("{} file has format version {}, expected version {}", kind.name(), version, kind.version())));
+    }
+    if header_len < HEADER_LEN{
+        return Err(Error::format(path, format!("header length {} is too short", header_len)));
+    }
+    let flags = u8::from_buffer(buf).ok_or_else(eof)?;
+    if flags & !KNOWN_FLAGS != 0{
+        return Err(Error::format(path, format!("unknown header flags {:#010b}", flags)));
+    }
+    // skip fields of a newer header we do not know about
+    for _ in HEADER_LEN..header_len{
+        u8::from_buffer(buf).ok_or_else(eof)?;
+    }
+    Ok(Header{ kind, version, header_len, flags })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn read(buf: Buffer) -> Result<Header>{
+        read_header(&mut ReadBuffer::from_raw(buf), "test", None)
+    }
+
+    #[test]
+    fn header_round_trip(){
+        let header = read(flagged_header_buffer(FormatKind::Styles, FLAG_DELTA)).unwrap();
+        assert_eq!(header.kind, FormatKind::Styles);
+        assert_eq!((header.header_len, header.flags), (HEADER_LEN, FLAG_DELTA));
+    }
+
+    #[test]
+    fn header_needs_the_flags(){
+        // a header of 9 bytes, without the flags
+        let mut buf = MAGIC.to_vec();
+        FormatKind::Styles.code().into_buffer(&mut buf);
+        FormatKind::Styles.version().into_buffer(&mut buf);
+        9u16.into_buffer(&mut buf);
+        assert!(read(buf).unwrap_err().to_string().contains("too short"));
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{
     logger::*,
     data::*,
+    decode::*,
+    triangulate::PolyTriangle,
 };
 
 use std::collections::HashMap;
@@ -9,14 +11,82 @@ use shapefile::*;
 use ass::*;
 
 // ranges: (offset x, range x, offset y, range y, offset z, range z)
-pub type Ranges = (u64,u64,u64,u64,u64,u64);
+// offsets can be below zero, like the depth of geological layers or coordinates west of a false origin
+pub type Ranges = (i64,u64,i64,u64,i64,u64);
+
+// What to compress with, for every axis: q = (w - offset) * multi, written in the type of the axis
+#[derive(Clone,Copy,Debug)]
+pub struct Infos{
+    pub offset: (i64,i64,i64),
+    pub multi: P3<u64>,
+    pub target: (CompTarget,CompTarget,CompTarget),
+}
+
+impl Infos{
+    // the smallest type of every axis and the multiplier that fills it
+    pub fn fit((ox,rx,oy,ry,oz,rz): Ranges) -> Self{
+        let axis = |range: u64|{
+            let target = target_compression_type(range);
+            (target, target_multiplier(range, target).0)
+        };
+        let ((tx,mx),(ty,my),(tz,mz)) = (axis(rx), axis(ry), axis(rz));
+        Self{
+            offset: (ox,oy,oz),
+            multi: (mx,my,mz),
+            target: (tx,ty,tz),
+        }
+    }
+
+    // The smallest multiplier that rounds every value to within the tolerance, the same for every axis,
+    // and the smallest type of every axis that fits its range with it
+    pub fn within((ox,rx,oy,ry,oz,rz): Ranges, tolerance: f64) -> Self{
+        // rounding is off by at most half a step of 1 / multi
+        let multi = ((0.5 / tolerance).ceil() as u64).max(1);
+        let target = |range: u64| target_compression_type(range.saturating_mul(multi));
+        Self{
+            offset: (ox,oy,oz),
+            multi: (multi,multi,multi),
+            target: (target(rx), target(ry), target(rz)),
+        }
+    }
+
+    // The z of heightlines is the height of the line, not of its points, so the ranges don't have it.
+    // It is offset by the lowest height and the type has to fit the range with the multiplier.
+    pub fn with_z(self, zmin: i64, zrange: u64, multi: u64) -> Self{
+        Self{
+            offset: (self.offset.0, self.offset.1, zmin),
+            multi: (self.multi.0, self.multi.1, multi),
+            target: (self.target.0, self.target.1, target_compression_type(zrange.saturating_mul(multi))),
+        }
+    }
+
+    // an axis too big for u32 can not be quantized, then all points are written as f64
+    pub fn floats(&self) -> bool{
+        let (tx,ty,tz) = self.target;
+        tx == CompTarget::NONE || ty == CompTarget::NONE || tz == CompTarget::NONE
+    }
+
+    // a quantized point back in the values it came from
+    pub fn dequantize(&self, (x,y,z): P3<f64>) -> P3<f64>{
+        let (ox,oy,oz) = self.offset;
+        let (mx,my,mz) = self.multi;
+        (x / mx as f64 + ox as f64, y / my as f64 + oy as f64, z / mz as f64 + oz as f64)
+    }
+
+    // the size in bytes of every axis as written in the file
+    pub fn sizes(&self) -> P3<u8>{
+        if self.floats() { return (8,8,8); }
+        (self.target.0.size(), self.target.1.size(), self.target.2.size())
+    }
+}
 
 // calculate info needed to compress
-pub fn info_package<'a,S: CustomShape,T>(shapes: &'a [S]) -> (u64,u64,u64,u64,CompTarget)
+// with a tolerance the multiplier is the smallest that meets it instead of the one that fills the type
+pub fn info_package<'a,S: CustomShape,T>(shapes: &'a [S], tolerance: Option<f64>) -> Infos
     where
         for<'b> &'b S: IntoIterator,
         <&'a S as IntoIterator>::Item: HasXyz<T> + PartialEq,
-        T: Ass<u64>,
+        T: Ass<u64> + Ass<f64>,
 {
     let ranges = compress_doubles_stats(shapes);
     let (mx,rx,my,ry,mz,rz) = ranges;
@@ -25,10 +95,29 @@ pub fn info_package<'a,S: CustomShape,T>(shapes: &'a [S]) -> (u64,u64,u64,u64,Co
     println!("shaperangex: {}, shaperangey: {}", shapesrange.0, shapesrange.1);
     let counts = compress_repeated_points_in_lines_stats(shapes);
     println!("total: {}, repeated: {}", counts.0, counts.1);
-    let (range,target) = target_compression_type(ranges);
-    let (multi,usage) = target_multiplier(range,target);
-    println!("target {} with multiplier {} using {} of range", target, multi, usage);
-    (mx,my,mz,multi,target)
+    let infos = match tolerance{
+        Some(tolerance) => Infos::within(ranges, tolerance),
+        None => Infos::fit(ranges),
+    };
+    for (axis,range,target,multi) in [("x",rx,infos.target.0,infos.multi.0), ("y",ry,infos.target.1,infos.multi.1), ("z",rz,infos.target.2,infos.multi.2)]{
+        let usage = range.saturating_mul(multi) as f64 / target.max() as f64;
+        println!("{}: target {} with multiplier {} using {} of range", axis, target, multi, usage);
+    }
+    infos
+}
+
+// Quantize a chunk relative to the min corner of the cell it was cut from instead of the whole dataset,
+// so small chunks get a small type. The app adds the corner back to get global coordinates.
+pub fn cell_info_package<'a,S,T>(shapes: &'a [S], (cx,cy): P2<i64>) -> Infos
+    where
+        for<'b> &'b S: IntoIterator,
+        <&'a S as IntoIterator>::Item: HasXyz<T>,
+        T: Ass<u64> + Ass<f64>,
+{
+    let (mx,rx,my,ry,mz,rz) = compress_doubles_stats(shapes);
+    // points are inside the cell, unless rounding put them just over the edge
+    let (ox,oy) = (cx.min(mx), cy.min(my));
+    Infos::fit((ox, rx + (mx - ox) as u64, oy, ry + (my - oy) as u64, mz, rz))
 }
 
 // calculate the ranges and offsets from the values of the collection
@@ -37,20 +126,20 @@ pub fn compress_doubles_stats<'a,S,T>(shapes: &'a [S]) -> Ranges
     where
         for<'b> &'b S: IntoIterator,
         <&'a S as IntoIterator>::Item: HasXyz<T>,
-        T: Ass<u64>,
+        T: Ass<u64> + Ass<f64>,
 {
-    let mut xmin = std::u64::MAX;
-    let mut xmax = std::u64::MIN;
-    let mut ymin = std::u64::MAX;
-    let mut ymax = std::u64::MIN;
-    let mut zmin = std::u64::MAX;
-    let mut zmax = std::u64::MIN;
+    let mut xmin = f64::MAX;
+    let mut xmax = f64::MIN;
+    let mut ymin = f64::MAX;
+    let mut ymax = f64::MIN;
+    let mut zmin = f64::MAX;
+    let mut zmax = f64::MIN;
     for shape in shapes{
         for p in shape{
             let xyz = p.xyz();
-            let x = (xyz.0).ass();
-            let y = (xyz.1).ass();
-            let z = (xyz.2).ass();
+            let x: f64 = (xyz.0).ass();
+            let y: f64 = (xyz.1).ass();
+            let z: f64 = (xyz.2).ass();
             xmax = xmax.max(x);
             ymax = ymax.max(y);
             zmax = zmax.max(z);
@@ -59,7 +148,16 @@ pub fn compress_doubles_stats<'a,S,T>(shapes: &'a [S]) -> Ranges
             zmin = zmin.min(z);
         }
     }
-    (xmin, xmax - xmin, ymin, ymax - ymin, zmin, zmax - zmin)
+    // an empty chunk has nothing to offset
+    if xmin > xmax { return (0,0,0,0,0,0); }
+    // whole numbers around the values, so the highest one still fits after rounding
+    let range = |min: f64, max: f64| (max.ceil() - min.floor()) as u64;
+    (xmin.floor() as i64, range(xmin,xmax), ymin.floor() as i64, range(ymin,ymax), zmin.floor() as i64, range(zmin,zmax))
+}
+
+// turning a quantized value back is off by float rounding too, around 1e-10 for coordinates in the millions
+pub fn meets_tolerance(error: f64, tolerance: f64) -> bool{
+    error <= tolerance + 1e-6
 }
 
 // calculate the ranges and offsets of individual shapes
@@ -70,19 +168,19 @@ pub fn compress_shapes_stats<'a,S,T>(shapes: &'a [S]) -> (u64,u64,u64)
         <&'a S as IntoIterator>::Item: HasXyz<T>,
         T: Ass<u64>,
 {
-    let mut rangex = std::u64::MIN;
-    let mut rangey = std::u64::MIN;
-    let mut rangez = std::u64::MIN;
+    let mut rangex = u64::MIN;
+    let mut rangey = u64::MIN;
+    let mut rangez = u64::MIN;
     for shape in shapes{
         if shape.points_len() == 0 {
             continue;
         }
-        let mut xmin = std::u64::MAX;
-        let mut xmax = std::u64::MIN;
-        let mut ymin = std::u64::MAX;
-        let mut ymax = std::u64::MIN;
-        let mut zmin = std::u64::MAX;
-        let mut zmax = std::u64::MIN;
+        let mut xmin = u64::MAX;
+        let mut xmax = u64::MIN;
+        let mut ymin = u64::MAX;
+        let mut ymax = u64::MIN;
+        let mut zmin = u64::MAX;
+        let mut zmax = u64::MIN;
         for p in shape{
             let xyz = p.xyz();
             let x = (xyz.0).ass();
@@ -125,11 +223,33 @@ pub fn compress_repeated_points_in_lines_stats<'a, S>(shapes: &'a [S]) -> (usize
 }
 
 // which data type will we use?
-#[derive(Copy,Clone)]
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
 pub enum CompTarget{
     U8,U16,U32,NONE,
 }
 
+impl CompTarget{
+    // the highest value of the type
+    pub fn max(self) -> u64{
+        match self{
+            Self::U8 => u8::MAX.into(),
+            Self::U16 => u16::MAX.into(),
+            Self::U32 => u32::MAX.into(),
+            Self::NONE => u64::MAX,
+        }
+    }
+
+    // size in bytes, NONE is written as f64
+    pub fn size(self) -> u8{
+        match self{
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+            Self::NONE => 8,
+        }
+    }
+}
+
 // how we print out the CompTarget
 impl std::fmt::Display for CompTarget{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
@@ -142,28 +262,20 @@ impl std::fmt::Display for CompTarget{
     }
 }
 
-// check the max value and choose the right primitive for that max
-pub fn target_compression_type((_,rx,_,ry,_,rz): Ranges) -> (u64, CompTarget){
-    fn get_target(range: u64) -> CompTarget{
-        if range < std::u8::MAX.into(){ CompTarget::U8 }
-        else if range < std::u16::MAX.into(){ CompTarget::U16 }
-        else if range < std::u32::MAX.into(){ CompTarget::U32 }
-        else {CompTarget::NONE }
-    }
-    let max = rx.max(ry).max(rz);
-    (max,get_target(max))
+// check the range of an axis and choose the right primitive for it
+pub fn target_compression_type(range: u64) -> CompTarget{
+    if range < u8::MAX.into(){ CompTarget::U8 }
+    else if range < u16::MAX.into(){ CompTarget::U16 }
+    else if range < u32::MAX.into(){ CompTarget::U32 }
+    else {CompTarget::NONE }
 }
 
 // how many times does the max fit in the primitive max?
 pub fn target_multiplier(mr: u64, target: CompTarget) -> (u64,f64){
-    let max: u64 = match target{
-        CompTarget::U8 => std::u8::MAX.into(),
-        CompTarget::U16 => std::u16::MAX.into(),
-        CompTarget::U32 => std::u32::MAX.into(),
-        _ => std::u64::MAX,
-    };
-    let m = max / mr;
-    if m < 1 { panic!("Error: target_multiplier smaller than one!"); }
+    let max = target.max();
+    // the target is chosen so that the range fits, a zero range fits any multiplier
+    if mr == 0 { return (1,0.0); }
+    let m = (max / mr).max(1);
     (m,(m * mr) as f64 / max as f64)
 }
 
@@ -206,7 +318,7 @@ pub fn collect_wrong_heightlines(shapes: VvP4, logger: &mut Logger)
         let mut is_wrong = false;
         let z = shape[0].2;
         for point in &shape{
-            if (point.2 - z).abs() > std::f64::EPSILON{
+            if (point.2 - z).abs() > f64::EPSILON{
                 is_wrong = true;
                 break;
             }
@@ -283,3 +395,164 @@ pub fn print_shape_content(shapes: &[Shape]){
     println!("Multipatch's: {}, with patches: {}", ma, map);
 }
 
+// print statistics about heightlines that have more than one height
+pub fn print_wrong_heightlines_stats(wrongs: &[Vec<f64>]){
+    println!("There are {} wrong heightlines", wrongs.len());
+    let mut diffs = Vec::new();
+    let mut sames = Vec::new();
+    let mut lens = Vec::new();
+    for (i,wrong) in wrongs.iter().enumerate(){
+        let min = wrong.iter().fold(f64::MAX, |m,x| m.min(*x));
+        let max = wrong.iter().fold(f64::MIN, |m,x| m.max(*x));
+        let mut countmap = HashMap::new();
+        for x in wrong{
+            let y = x.to_bits();
+            let newcount = match countmap.get(&y){
+                Some(n) => { n + 1 },
+                None => { 1 },
+            };
+            countmap.insert(y, newcount);
+        }
+        let mut vec = Vec::new();
+        for item in &countmap{
+            vec.push(item);
+        }
+        vec.sort_by_key(|x| x.1);
+        let same = if vec.is_empty(){ 0 }
+        else { *vec[vec.len() - 1].1 };
+        let diff = max - min;
+        diffs.push(diff);
+        sames.push(same);
+        lens.push(wrong.len());
+        println!("Line {}: min: {} max: {} diff: {} same: {} len: {}", i, min, max, diff, same, wrong.len());
+    }
+    if diffs.is_empty(){
+        println!("median: 0\nmean: 0");
+        return;
+    }
+    let mean = diffs.iter().fold(0.0, |sum,x| sum + x) / diffs.len() as f64;
+    let min = diffs.iter().fold(f64::MAX, |m,x| m.min(*x));
+    let max = diffs.iter().fold(f64::MIN, |m,x| m.max(*x));
+    let mut diffs: Vec<u64> = diffs.iter().map(|x| *x as u64).collect::<Vec<u64>>();
+    diffs.sort_unstable();
+    let median = diffs[diffs.len() / 2];
+    lens.sort_unstable();
+    sames.sort_unstable();
+    let lens_median = lens[lens.len() / 2];
+    let sames_median = sames[sames.len() / 2];
+    println!("Differences between min and max in lines, summary:");
+    println!("median: {}", median);
+    println!("mean: {}", mean);
+    println!("min: {}", min);
+    println!("max: {}", max);
+    println!("length median: {}", lens_median);
+    println!("same value's median: {}", sames_median);
+}
+
+// print what attribute value every style index stands for
+pub fn print_style_classes(classes: &[String]){
+    for (i,class) in classes.iter().enumerate(){
+        println!("style {}: {}", i, class);
+    }
+}
+
+// print what is inside a decoded file, with dump every shape is printed as well
+// with world the points are turned back into world coordinates
+pub fn print_decoded(decoded: &Decoded, dump: bool, world: bool){
+    fn print_quant(q: &Quantization){
+        println!("offset: ({}, {}, {}), multipliers: {:?}, type sizes: {:?}", q.mx, q.my, q.mz, q.multi, q.tsize);
+    }
+    fn print_points(points: &[P2<u32>], q: Option<&Quantization>){
+        let strings: Vec<String> = points.iter().map(|p| match q{
+            Some(q) => { let (x,y) = q.dequantize(*p); format!("({:.2},{:.2})", x, y) },
+            None => format!("({},{})", p.0, p.1),
+        }).collect();
+        println!("    points: [{}]", strings.join(","));
+    }
+    fn print_compressed<S: CustomShape>(c: &Compressed<S>, dump: bool, world: bool, print: fn(usize, &S, Option<&Quantization>)){
+        print_quant(&c.quant);
+        println!("boundingbox: {:?}", c.bb);
+        let points = c.shapes.iter().fold(0, |sum, s| sum + s.points_len());
+        println!("shapes: {}, points: {}", c.shapes.len(), points);
+        if !dump { return; }
+        for (i,s) in c.shapes.iter().enumerate(){
+            print(i, s, if world { Some(&c.quant) } else { None });
+        }
+    }
+    fn print_shapez(i: usize, s: &ShapeZ<u32>, q: Option<&Quantization>){
+        println!("  shape {}: z: {} bb: {:?}", i, s.z, s.bb);
+        print_points(&s.points, q);
+    }
+    fn print_polygonz(i: usize, s: &crate::data::PolygonZ<u32>, q: Option<&Quantization>){
+        println!("  polygon {}: style: {} outers: {} inners: {} bb: {:?}", i, s.style, s.outers.len(), s.inners.len(), s.bb);
+        for ring in s.outers.iter().chain(s.inners.iter()){
+            print_points(&ring.iter().map(|(x,y,_)| (*x,*y)).collect::<Vec<_>>(), q);
+        }
+    }
+    fn print_polytriangle(i: usize, s: &PolyTriangle<u32>, q: Option<&Quantization>){
+        println!("  triangles {}: style: {} triangles: {} bb: {:?}", i, s.style, s.indices.len() / 3, s.bb);
+        print_points(&s.vertices, q);
+        println!("    indices: {:?}", s.indices);
+    }
+    fn print_styledline(i: usize, s: &StyledLine<u32>, q: Option<&Quantization>){
+        println!("  line {}: style: {} bb: {:?}", i, s.style, s.bb);
+        print_points(&s.points, q);
+    }
+    match decoded{
+        Decoded::Height(c) => {
+            println!("Compressed heightlines");
+            print_compressed(c, dump, world, print_shapez);
+        },
+        Decoded::Polygonz(c) => {
+            println!("Compressed polygons");
+            print_compressed(c, dump, world, print_polygonz);
+        },
+        Decoded::Triangles(c) => {
+            println!("Compressed triangles");
+            print_compressed(c, dump, world, print_polytriangle);
+        },
+        Decoded::PolyChunk(c) => {
+            println!("Polygon chunk");
+            print_compressed(c, dump, world, print_polytriangle);
+        },
+        Decoded::GeolineChunk(c) => {
+            println!("Geoline chunk");
+            print_compressed(c, dump, world, print_styledline);
+        },
+        Decoded::HlineChunk(c) => {
+            println!("Heightline chunk: level: {} x: {} y: {}", c.level, c.x, c.y);
+            print_compressed(&c.chunk, dump, world, print_shapez);
+        },
+        Decoded::ChunksInfo(info) => {
+            println!("Heightline chunks info");
+            println!("levels: {}, cuts: {:?}, mods: {:?}", info.mods.len(), info.cuts, info.mods);
+            if info.cuts.is_empty() { println!("no grid, the chunks are in chunks.quadtree"); }
+            print_quant(&info.quant);
+            println!("boundingbox: {:?}", info.bb);
+        },
+        Decoded::PolyInfo(info) | Decoded::GeolineInfo(info) => {
+            println!("Grid chunks info");
+            if info.cuts.is_empty() { println!("no grid, the chunks are in chunks.quadtree"); }
+            println!("levels: {}, cuts: {:?}, boundingbox: {:?}", info.cuts.len(), info.cuts, info.bb);
+        },
+        Decoded::Quadtree(index) => {
+            println!("Quadtree chunks index");
+            println!("origin: {:?}, side: {}", index.origin, index.side);
+            for (i,nodes) in index.levels.iter().enumerate(){
+                let files = nodes.iter().filter(|n| n.points > 0).count();
+                let points = nodes.iter().map(|n| n.points).sum::<u64>();
+                println!("level {}: nodes: {}, files: {}, points: {}", i, nodes.len(), files, points);
+                if !dump { continue; }
+                for (j,n) in nodes.iter().enumerate(){
+                    println!("  node {}: children: {}, points: {}", j, n.children, n.points);
+                }
+            }
+        },
+        Decoded::Styles(styles) => {
+            println!("Styles: {}", styles.len());
+            for (i,(a,r,g,b)) in styles.iter().enumerate(){
+                println!("  style {}: ({}, #{:02x}{:02x}{:02x})", i, a, r, g, b);
+            }
+        },
+    }
+}
@@ -1,17 +1,22 @@
 use crate::{
     data::{ VvP4, VvP2, P4 },
-    convert::degree_to_utm,
+    crs::Points,
+    error::{ Error, Result },
     logger::*,
 };
 
 use std::{
     fs::File,
+    io::BufReader,
     collections::{HashMap,HashSet},
     str::FromStr,
 };
 
 use hex::FromHex;
-use xml::reader::{EventReader,XmlEvent};
+use xml::{
+    common::Position,
+    reader::{EventReader,XmlEvent},
+};
 
 // right amount of spaces for x indentations
 fn indent(size: usize) -> String{
@@ -39,131 +44,134 @@ fn clean_name(name: String) -> String{
     builder.to_ascii_lowercase()
 }
 
-// open file or die
-macro_rules! open_file{
-    ($path:expr) => {
-        if let Ok(ffile) = File::open($path){
-            ffile
-        }else{
-            panic!("Could not open file: {}", $path);
+// xml events of a file, together with the line they are on
+// yields an error and stops when the xml is invalid
+struct Events{
+    path: String,
+    parser: EventReader<BufReader<File>>,
+    done: bool,
+}
+
+impl Iterator for Events{
+    type Item = Result<(u64,XmlEvent)>;
+
+    fn next(&mut self) -> Option<Self::Item>{
+        if self.done { return None; }
+        let e = self.parser.next();
+        let line = self.parser.position().row + 1;
+        match e{
+            Ok(XmlEvent::EndDocument) => {
+                self.done = true;
+                None
+            },
+            Ok(e) => Some(Ok((line,e))),
+            Err(cause) => {
+                self.done = true;
+                Some(Err(Error::Xml{ path: self.path.clone(), cause }))
+            },
         }
-    };
+    }
+}
+
+// open file and start parsing it
+fn xml_events(path: &str) -> Result<Events>{
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    Ok(Events{
+        path: path.to_string(),
+        parser: EventReader::new(BufReader::new(file)),
+        done: false,
+    })
 }
 
 // print a indented tree of opening xml tags
-pub fn print_xml_tag_tree(path: &str){
-    let file = open_file!(path);
-    let parser = EventReader::new(file);
+pub fn print_xml_tag_tree(path: &str) -> Result<()>{
     let mut depth = 0;
-    for e in parser{
-        match e{
-            Ok(XmlEvent::StartElement { name, .. }) => {
+    for e in xml_events(path)?{
+        match e?.1{
+            XmlEvent::StartElement { name, .. } => {
                 println!("{}-{}", indent(depth), clean_name(name.to_string()));
                 depth += 1;
             }
-            Ok(XmlEvent::EndElement { .. }) => {
+            XmlEvent::EndElement { .. } => {
                 depth -= 1;
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
-            }
             _ => {}
         }
     }
+    Ok(())
 }
 
 // check if a certain tags is always present in another tag
-pub fn check_tag_child(path: &str, parent: &str, child: &str) -> bool{
-    let file = open_file!(path);
-    let parser = EventReader::new(file);
+pub fn check_tag_child(path: &str, parent: &str, child: &str) -> Result<bool>{
     let mut inside = false;
     let mut seen = false;
-    for e in parser{
+    for e in xml_events(path)?{
+        let (line, e) = e?;
         match e{
-            Ok(XmlEvent::StartElement { name, .. }) => {
+            XmlEvent::StartElement { name, .. } => {
                 let cleaned = clean_name(name.to_string());
                 if cleaned == parent{ inside = true; seen = false; }
                 else if cleaned == child && inside { seen = true; }
             }
-            Ok(XmlEvent::EndElement { name, .. }) => {
+            XmlEvent::EndElement { name, .. } => {
                 let cleaned = clean_name(name.to_string());
                 if cleaned == parent{
-                    if !inside { panic!("Found end tag of parent with seeing the start tag, somehow..."); }
+                    if !inside {
+                        return Err(Error::kml(path, line, parent, "end tag of parent without start tag"));
+                    }
                     if !seen{
-                        return false;
+                        return Ok(false);
                     }
                     inside = false;
                     seen = false;
                 }
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
-            }
             _ => {}
         }
     }
-    true
+    Ok(true)
 }
 
 // check if certain tags are never empty
-pub fn check_nonempty_tag(path: &str, tag: &str) -> bool{
-    let file = open_file!(path);
-    let parser = EventReader::new(file);
+pub fn check_nonempty_tag(path: &str, tag: &str) -> Result<bool>{
     let mut in_tag = false;
     let mut inside = String::new();
-    for e in parser{
-        match e{
-            Ok(XmlEvent::StartElement { name, .. }) => {
+    for e in xml_events(path)?{
+        match e?.1{
+            XmlEvent::StartElement { name, .. } => {
                 let nname = clean_name(name.to_string());
                 if nname == tag{
                     in_tag = true;
                 }
             }
-            Ok(XmlEvent::Characters(content)) => {
-                if in_tag{
-                    inside = content;
-                }
+            XmlEvent::Characters(content) if in_tag => {
+                inside = content;
             }
-            Ok(XmlEvent::EndElement{ name }) => {
+            XmlEvent::EndElement{ name } => {
                 let nname = clean_name(name.to_string());
                 if nname == tag{
-                    if inside.is_empty() { return false; }
+                    if inside.is_empty() { return Ok(false); }
                     in_tag = false;
                     inside = String::new();
                 }
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
-            }
             _ => {}
         }
     }
-    true
+    Ok(true)
 }
 
 // count all tags and print them out with counts
-pub fn print_xml_tag_count(path: &str){
-    let file = open_file!(path);
+pub fn print_xml_tag_count(path: &str) -> Result<()>{
     let mut map = HashMap::new();
-    let parser = EventReader::new(file);
-    for e in parser{
-        match e{
-            Ok(XmlEvent::StartElement { name, .. }) => {
-                let nname = clean_name(name.to_string());
-                let newcount = match map.get(&nname){
-                    Some(n) => { n + 1 },
-                    None => { 1 },
-                };
-                map.insert(nname, newcount);
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
-            }
-            _ => {}
+    for e in xml_events(path)?{
+        if let XmlEvent::StartElement { name, .. } = e?.1{
+            let nname = clean_name(name.to_string());
+            let newcount = match map.get(&nname){
+                Some(n) => { n + 1 },
+                None => { 1 },
+            };
+            map.insert(nname, newcount);
         }
     }
     let mut vec = Vec::new();
@@ -174,79 +182,114 @@ pub fn print_xml_tag_count(path: &str){
     for (key,count) in vec{
         println!("Tag: {}, count: {}", key, count);
     }
+    Ok(())
 }
 
-// parse string of coordinates
-pub fn parse_coords(string: String) -> Vec<P4<f64>>{
-    let mut line = Vec::new();
-    let points_str: Vec<_> = string.split(' ').collect();
-    for point_str in points_str{
+// parse string of lon,lat,alt coordinates, project them and snap the height
+// path and line are only used to report errors
+pub fn parse_coords(string: &str, points: &Points, path: &str, line: u64) -> Result<Vec<P4<f64>>>{
+    let mut res = Vec::new();
+    for point_str in string.split_whitespace(){
         let comps: Vec<_> = point_str.split(',').collect();
         if comps.len() != 3 { continue; }
-        let x = comps[0].parse::<f64>();
-        let y = comps[1].parse::<f64>();
-        let z = comps[2].parse::<f64>();
-        if x.is_err() || y.is_err() || z.is_err(){
-            panic!("xyz none");
-        }
-        fn hclamp<T>(c: Result<f64,T>) -> f64{
-            (c.unwrap_or_default() / 5.0).round() * 5.0
-        }
-        let (_,_,x,y) = degree_to_utm((x.unwrap(),y.unwrap()));
-        line.push((x, y, hclamp(z), 0.0));
+        let parse = |c: &str| c.parse::<f64>().map_err(|_|
+            Error::kml(path, line, "coordinates", format!("invalid coordinate \"{}\"", point_str))
+        );
+        let x = parse(comps[0])?;
+        let y = parse(comps[1])?;
+        let z = parse(comps[2])?;
+        let (x,y) = points.xy((x,y));
+        res.push((x, y, points.z(z), 0.0));
     }
-    line
+    Ok(res)
 }
 
 // parse heightlines from kml file
-pub fn kml_height(path: &str) -> VvP4{
-    let file = open_file!(path);
-    let parser = EventReader::new(file);
+// lines with invalid coordinates are skipped
+pub fn kml_height(path: &str, points: &Points, logger: &mut Logger) -> Result<VvP4>{
     let coord_name = String::from("coordinates");
     let mut coor = false;
     let mut strings = Vec::new();
     // collect the data first
-    for e in parser{
+    for e in xml_events(path)?{
+        let (line, e) = e?;
         match e{
-            Ok(XmlEvent::StartElement { name, .. }) => {
+            XmlEvent::StartElement { name, .. } => {
                 let nname = clean_name(name.to_string());
                 if nname != coord_name{ continue; }
                 coor = true;
             }
-            Ok(XmlEvent::Characters(content)) => {
+            XmlEvent::Characters(content) => {
                 if !coor { continue; }
-                strings.push(content);
+                strings.push((line,content));
             }
-            Ok(XmlEvent::EndElement{ name }) => {
+            XmlEvent::EndElement{ name } => {
                 let nname = clean_name(name.to_string());
                 if nname != coord_name { continue; }
                 coor = false;
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
-            }
             _ => {}
         }
     }
-    println!("{}", strings.len());
     // then we parse
     let mut vvp4 = Vec::new();
-    for string in strings{
-        vvp4.push(parse_coords(string));
+    for (line,string) in strings{
+        match parse_coords(&string, points, path, line){
+            Ok(coords) => vvp4.push(coords),
+            Err(e) => logger.skip(Issue::SkippedPlacemark, e),
+        }
+    }
+    Ok(vvp4)
+}
+
+// the first point of a kml file as it is in the file, to pick a utm zone with
+// coordinates that can not be parsed are skipped, like reading the file does
+pub fn kml_first_point(path: &str) -> Result<Option<(f64,f64)>>{
+    let coord_name = String::from("coordinates");
+    let mut coor = false;
+    for e in xml_events(path)?{
+        let (line, e) = e?;
+        match e{
+            XmlEvent::StartElement { name, .. } => coor = clean_name(name.to_string()) == coord_name,
+            XmlEvent::Characters(content) if coor => {
+                if let Some(p) = parse_coords(&content, &Points::raw(), path, line).ok().and_then(|c| c.first().copied()){
+                    return Ok(Some((p.0,p.1)));
+                }
+            }
+            _ => {}
+        }
     }
-    vvp4
+    Ok(None)
+}
+
+// get the id of a style tag
+fn style_id(attributes: &[xml::attribute::OwnedAttribute], path: &str, line: u64) -> Result<String>{
+    attributes.iter()
+        .find(|a| a.name.local_name == "id")
+        .map(|a| a.value.clone())
+        .ok_or_else(|| Error::kml(path, line, "style", "style tag has no id attribute"))
+}
+
+// parse a kml colour (aabbggrr) into r, g, b
+fn parse_colour(colourstr: &str, path: &str, line: u64) -> Result<(u8,u8,u8)>{
+    let components = Vec::from_hex(colourstr.trim())
+        .map_err(|_| Error::kml(path, line, "color", format!("could not parse hex colour \"{}\"", colourstr)))?;
+    if components.len() < 3 {
+        return Err(Error::kml(path, line, "color", "expected at least 3 components in colour"));
+    }
+    let offset = components.len() - 3;
+    Ok((components[offset], components[offset + 1], components[offset + 2]))
 }
 
 //parse polygons from geological kml file
-pub fn kml_geo(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize, logger: &mut Logger) -> Vec<(usize,(VvP4,VvP4))>{
-    let file = open_file!(path);
-    let parser = EventReader::new(file);
+//styles and placemarks that can not be parsed are reported and skipped
+pub fn kml_geo(path: &str, points: &Points, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize, logger: &mut Logger) -> Result<Vec<(usize,(VvP4,VvP4))>>{
     // just a lot of stuff we need to keep track and collect the data
     let mut colset = HashSet::new();
     let mut colmap = HashMap::new();
     let mut in_poly_style = false;
-    let mut style_id = String::new();
+    let mut style_id_res = Ok(String::new());
+    let mut style_line = 0;
     let mut in_colour = false;
     let mut in_outline = false;
     let mut colour = String::new();
@@ -261,15 +304,14 @@ pub fn kml_geo(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize,
     let mut inners = Vec::new();
     let mut polygons = Vec::new();
     // do the collecting
-    for e in parser{
+    for e in xml_events(path)?{
+        let (line, e) = e?;
         match e{
-            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+            XmlEvent::StartElement { name, attributes, .. } => {
                 let nname = clean_name(name.to_string());
                 if &nname == "style"{
-                    if attributes.len() != 1{
-                        panic!("style tag should have only one attribute: id");
-                    }
-                    style_id.clone_from(&attributes[0].value);
+                    style_id_res = style_id(&attributes, path, line);
+                    style_line = line;
                 }else if &nname == "polystyle"{
                     in_poly_style = true;
                 }else if &nname == "color" && in_poly_style{// fool, orang is coloure!
@@ -286,26 +328,25 @@ pub fn kml_geo(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize,
                     in_coordinates = true;
                 }
             }
-            Ok(XmlEvent::Characters(content)) => {
+            XmlEvent::Characters(content) => {
                 if in_colour{
                     colour = content;
                 }else if in_outline{
-                    if content.is_empty() { panic!("Outline tag content can not be empty!"); }
                     outline = content.chars().next().unwrap_or('0');
                 }else if in_style_url{
                     style_url = content;
                 }else if in_coordinates && in_outer{
-                    outers.push(content);
+                    outers.push((line,content));
                 }else if in_coordinates && in_inner{
-                    inners.push(content);
+                    inners.push((line,content));
                 }
             }
-            Ok(XmlEvent::EndElement{ name }) => {
+            XmlEvent::EndElement{ name } => {
                 let nname = clean_name(name.to_string());
                 if &nname == "polystyle" {
                     in_poly_style = false;
-                    styles_raw.push((style_id,colour,outline));
-                    style_id = String::new(); // befriend the borrowchecker
+                    styles_raw.push((style_id_res,style_line,colour,outline));
+                    style_id_res = Ok(String::new()); // befriend the borrowchecker
                     colour = String::new(); // by giving him crap to eat
                 }
                 else if &nname == "color" { in_colour = false; }
@@ -322,35 +363,30 @@ pub fn kml_geo(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize,
                     style_url = String::new();
                 }
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
-            }
             _ => {}
         }
     }
     // parse the styles from the data
-    for (id,colourstr,outline) in styles_raw{
+    for (id,line,colourstr,outline) in styles_raw{
+        let id = match id{
+            Ok(id) => id,
+            Err(e) => { logger.skip(Issue::SkippedStyle, e); continue; },
+        };
         if colset.contains(&id) { continue; }
+        let (r,g,b) = match parse_colour(&colourstr, path, line){
+            Ok(rgb) => rgb,
+            Err(e) => { logger.skip(Issue::SkippedStyle, e); continue; },
+        };
         colmap.insert(id.clone(), *counter);
         colset.insert(id.clone());
         let outl = if outline == '1' { 1u8 }
         else { 0u8 };
-        let components = if let Ok(c) = Vec::from_hex(colourstr)
-        { c } else { panic!("Could not parse hex colour!"); };
-        if components.len() < 3 {
-            panic!("Expected at least 3 components in colour!");
-        }
-        let offset = components.len() - 3;
-        let r = components[offset];
-        let g = components[offset + 1];
-        let b = components[offset + 2];
         styles.push((outl,r,g,b));
         *counter += 1;
     }
     // parse the polygons from the data
     let mut polys = Vec::new();
-    for (sturl,outersraw,innersraw) in polygons{
+    'polys: for (sturl,outersraw,innersraw) in polygons{
         if sturl.is_empty(){
             logger.log(Issue::EmptyStyleId);
             continue;
@@ -361,26 +397,31 @@ pub fn kml_geo(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize,
             continue;
         };
         let mut outers = Vec::new();
-        for outerraw in outersraw{
-            outers.push(parse_coords(outerraw));
-        }
         let mut inners = Vec::new();
-        for innerraw in innersraw{
-            inners.push(parse_coords(innerraw));
+        for (raws,parsed) in [(outersraw,&mut outers),(innersraw,&mut inners)]{
+            for (line,raw) in raws{
+                match parse_coords(&raw, points, path, line){
+                    Ok(coords) => parsed.push(coords),
+                    Err(e) => {
+                        logger.skip(Issue::SkippedPlacemark, e);
+                        continue 'polys;
+                    },
+                }
+            }
         }
         polys.push((id,(outers,inners)));
     }
-    polys
+    Ok(polys)
 }
 
 //parse lines from geological kml file
-pub fn kml_geo_lines(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize, logger: &mut Logger) -> Vec<(usize,VvP2)>{
-    let file = open_file!(path);
-    let parser = EventReader::new(file);
+//styles and placemarks that can not be parsed are reported and skipped
+pub fn kml_geo_lines(path: &str, points: &Points, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut usize, logger: &mut Logger) -> Result<Vec<(usize,VvP2)>>{
     let mut colset = HashSet::new();
     let mut colmap = HashMap::new();
     let mut in_line_style = false;
-    let mut style_id = String::new();
+    let mut style_id_res = Ok(String::new());
+    let mut style_line = 0;
     let mut in_colour = false;
     let mut in_width = false;
     let mut colour = String::new();
@@ -397,15 +438,14 @@ pub fn kml_geo_lines(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut
     let mut lines = Vec::new();
     let mut polygons = Vec::new();
     // collect the data raw as strings first
-    for e in parser{
+    for e in xml_events(path)?{
+        let (line, e) = e?;
         match e{
-            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+            XmlEvent::StartElement { name, attributes, .. } => {
                 let nname = clean_name(name.to_string());
                 if &nname == "style"{
-                    if attributes.len() != 1{
-                        panic!("style tag should have only one attribute: id");
-                    }
-                    style_id.clone_from(&attributes[0].value);
+                    style_id_res = style_id(&attributes, path, line);
+                    style_line = line;
                 }else if &nname == "linestyle"{
                     in_line_style = true;
                 }else if &nname == "color" && in_line_style{// fool, orang is coloure!
@@ -426,25 +466,24 @@ pub fn kml_geo_lines(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut
                     in_outline = true;
                 }
             }
-            Ok(XmlEvent::Characters(content)) => {
+            XmlEvent::Characters(content) => {
                 if in_colour{
                     colour = content;
                 }else if in_width{
-                    if content.is_empty() { panic!("Width should have content!"); }
                     width = content;
                 }else if in_style_url{
                     style_url = content;
                 }else if in_coordinates && (in_outer || in_inner || in_line){
-                    lines.push(content);
+                    lines.push((line,content));
                 }else if in_outline{
-                    outline = content.chars().next().expect("Outline content should have at least on char!");
+                    outline = content.chars().next().unwrap_or('-');
                 }
             }
-            Ok(XmlEvent::EndElement{ name }) => {
+            XmlEvent::EndElement{ name } => {
                 let nname = clean_name(name.to_string());
                 if &nname == "style" {
-                    styles_raw.push((style_id,colour,width,outline));
-                    style_id = String::new(); // befriend the borrowchecker
+                    styles_raw.push((style_id_res,style_line,colour,width,outline));
+                    style_id_res = Ok(String::new()); // befriend the borrowchecker
                     colour = String::new(); // by giving him crap to eat
                     width = String::new(); // take this mr crab
                     outline = '-';
@@ -465,40 +504,39 @@ pub fn kml_geo_lines(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut
                     style_url = String::new();
                 }
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
-            }
             _ => {}
         }
     }
     // parse the styles
     let mut skipmap = HashSet::new();
-    for (id,colourstr,width,outline) in styles_raw{
+    for (id,line,colourstr,width,outline) in styles_raw{
+        let id = match id{
+            Ok(id) => id,
+            Err(e) => { logger.skip(Issue::SkippedStyle, e); continue; },
+        };
         if colset.contains(&id) { continue; }
         if outline == '0' {
             skipmap.insert(id.clone());
             continue;
         }
+        let width_int = if let Ok(v) = f32::from_str(width.trim()) { (v * 10.0) as u8 }
+        else {
+            let e = Error::kml(path, line, "width", format!("could not parse width \"{}\" as float", width));
+            logger.skip(Issue::SkippedStyle, e);
+            continue;
+        };
+        let (r,g,b) = match parse_colour(&colourstr, path, line){
+            Ok(rgb) => rgb,
+            Err(e) => { logger.skip(Issue::SkippedStyle, e); continue; },
+        };
         colmap.insert(id.clone(), *counter);
         colset.insert(id.clone());
-        let width_int = if let Ok(v) = f32::from_str(&width) { (v * 10.0) as u8 }
-        else { panic!("Could not parse width as float!"); };
-        let components = if let Ok(c) = Vec::from_hex(colourstr)
-        { c } else { panic!("Could not parse hex colour!"); };
-        if components.len() < 3 {
-            panic!("Expected at least 3 components in colour!");
-        }
-        let offset = components.len() - 3;
-        let r = components[offset];
-        let g = components[offset + 1];
-        let b = components[offset + 2];
         styles.push((width_int,r,g,b));
         *counter += 1;
     }
     // parse the lines and link them with a style
     let mut res = Vec::new();
-    for (sturl,rawlines) in polygons{
+    'lines: for (sturl,rawlines) in polygons{
         let url = &sturl.chars().filter(|c| *c != '#').collect::<String>();
         if skipmap.contains(url){ continue; }
         if url.is_empty(){
@@ -511,12 +549,30 @@ pub fn kml_geo_lines(path: &str, styles: &mut Vec<(u8,u8,u8,u8)>, counter: &mut
             continue;
         };
         let mut lines = Vec::new();
-        for linesraw in rawlines{
-            let parsed = parse_coords(linesraw);
+        for (line,linesraw) in rawlines{
+            let parsed = match parse_coords(&linesraw, points, path, line){
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    logger.skip(Issue::SkippedPlacemark, e);
+                    continue 'lines;
+                },
+            };
             lines.push(parsed.into_iter().map(|(x,y,_,_)| (x,y)).collect::<Vec<_>>());
         }
         res.push((id,lines));
     }
-    res
+    Ok(res)
 }
 
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn first_point_skips_bad_coordinates(){
+        let path = std::env::temp_dir().join("shapefile-linter-kml-first.kml");
+        std::fs::write(&path, "<kml><Placemark><coordinates>5.1,north,0</coordinates></Placemark>\
+            <Placemark><coordinates>6.5,52.1,0 6.6,52.2,0</coordinates></Placemark></kml>").unwrap();
+        assert_eq!(kml_first_point(path.to_str().unwrap()).unwrap(), Some((6.5,52.1)));
+    }
+}
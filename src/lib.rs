@@ -0,0 +1,32 @@
+//! Linting, cleaning and compression for shapefiles, KML and GeoJSON files.
+//!
+//! The `pipeline` module is the entry point for other tools: it reads input files, lints them,
+//! compresses them, cuts them into chunks and gives back the buffers to write.
+//! The other modules hold the building blocks the pipeline is made of.
+//! The `shapefile-linter` binary is a thin command line wrapper around this crate.
+
+extern crate shapefile;
+extern crate bin_buffer;
+extern crate dlv_list;
+extern crate xml;
+extern crate hex;
+extern crate ass;
+extern crate json;
+
+pub mod error;
+pub mod data;
+pub mod info;
+pub mod compress;
+pub mod encoding;
+pub mod logger;
+pub mod chunkify;
+pub mod quadtree;
+pub mod triangulate;
+pub mod kml;
+pub mod dbf;
+pub mod convert;
+pub mod crs;
+pub mod decode;
+pub mod header;
+pub mod geojson;
+pub mod pipeline;
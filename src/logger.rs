@@ -1,3 +1,5 @@
+use crate::error::Error;
+
 use std::collections::HashMap;
 
 // Issues you can give the logger.
@@ -8,13 +10,17 @@ pub enum Issue{
     UnsupportedShape,
     EmptyShape,
     MultiChunkShape,
-    NonOriginBoundingbox,
     EmptyStyleId,
     MissingStyleId,
     PolyNotEnoughVertices,
-    OutOfIndicesBound,
     NoEarsLeft,
     InnerNotInside,
+    SkippedPlacemark,
+    SkippedStyle,
+    SkippedFeature,
+    SkippedRecord,
+    SkippedFile,
+    RejectedSimplification,
 }
 
 #[derive(Default)]
@@ -25,6 +31,12 @@ pub struct Logger{
 }
 
 impl Logger{
+    // Log an error about a part of a file we could not use and skip it.
+    pub fn skip(&mut self, issue: Issue, err: Error){
+        self.log(Issue::Message(format!("Skipping: {}", err)));
+        self.log(issue);
+    }
+
     // Give an issue, A message will be printed and others will be counted.
     pub fn log(&mut self, issue: Issue){
         match issue{
@@ -36,6 +48,12 @@ impl Logger{
             }
         }
     }
+    // Count the issues of another logger too, like one that was used to try something out.
+    pub fn merge(&mut self, other: Logger){
+        for (issue, count) in other.issues{
+            *self.issues.entry(issue).or_insert(0) += count;
+        }
+    }
     // Print out all the Issues with how often they occured.
     pub fn report(&self){
         for (issue, count) in self.issues.clone(){
@@ -49,20 +67,28 @@ impl Logger{
                     println!("({} times) Empty shape!", count),
                 Issue::MultiChunkShape =>
                     println!("({} times) Multi chunk shape!", count),
-                Issue::NonOriginBoundingbox =>
-                    println!("({} times) Mother chunk left top bounding box is not at origin!", count),
                 Issue::EmptyStyleId =>
                     println!("({} times) Empty Style ID!", count),
                 Issue::MissingStyleId =>
                     println!("({} times) Missing Style ID!", count),
                 Issue::PolyNotEnoughVertices =>
                     println!("({} times) Polygon has less than 3 vertices!", count),
-                Issue::OutOfIndicesBound =>
-                    println!("({} times) Polygon has more indices than fit in u16!", count),
                 Issue::NoEarsLeft =>
                     println!("({} times) Triangulation: no ears left!", count),
                 Issue::InnerNotInside =>
                     println!("({} times) Inner polygon not inside any outer polygon!", count),
+                Issue::SkippedPlacemark =>
+                    println!("({} times) Skipped placemark that could not be parsed!", count),
+                Issue::SkippedStyle =>
+                    println!("({} times) Skipped style that could not be parsed!", count),
+                Issue::SkippedFeature =>
+                    println!("({} times) Skipped GeoJSON feature that could not be parsed!", count),
+                Issue::SkippedRecord =>
+                    println!("({} times) Skipped shape with attributes that could not be used!", count),
+                Issue::SkippedFile =>
+                    println!("({} times) Skipped file that could not be read!", count),
+                Issue::RejectedSimplification =>
+                    println!("({} times) Simplification rejected because lines would cross!", count),
             }
         }
     }
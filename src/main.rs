@@ -1,380 +1,646 @@
 extern crate lapp;
-extern crate shapefile;
-extern crate bin_buffer;
-extern crate dlv_list;
-extern crate xml;
-extern crate hex;
-extern crate ass;
-
-use bin_buffer::*;
+extern crate shapefile_linter;
+
+use lapp::Args;
 use std::{
     path::Path,
     time::Instant,
-    collections::HashMap,
 };
 
-pub mod data;
-pub mod info;
-pub mod compress;
-pub mod logger;
-pub mod chunkify;
-pub mod triangulate;
-pub mod kml;
-pub mod convert;
-
-use info::*;
-use compress::*;
-use logger::*;
-use data::*;
-use crate::data::{PolygonZ};
-use chunkify::*;
-use kml::*;
+use shapefile_linter::{
+    info::*,
+    kml::*,
+    logger::*,
+    data::{ split, PolygonZ },
+    pipeline::*,
+    error::{ Error, Result },
+};
+
+// Exit codes, so scripts can tell what went wrong.
+const EXIT_OK: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_UNKNOWN_COMMAND: i32 = 2;
 
-fn main(){
-    do_things();
+type CmdResult = Result<()>;
+
+// A subcommand: its name, lapp usage text and the function that runs it.
+struct Command{
+    name: &'static str,
+    about: &'static str,
+    usage: &'static str,
+    run: fn(&Args, &mut Logger) -> CmdResult,
 }
 
-fn do_things() -> Option<()>{
-    // Set up cli arguments
-    let args = lapp::parse_args("
-    Preprocess shapefiles into more efficient files.
-      <inputfile> (string...) input file(s) name(s)
+const COMMANDS: &[Command] = &[
+    Command{
+        name: "shapeinfo",
+        about: "print what is inside a shapefile",
+        usage: "
+    Print how much of each shape type is in a shapefile.
+      <inputfile> (string) input shapefile
+    ",
+        run: cmd_shapeinfo,
+    },
+    Command{
+        name: "height",
+        about: "compress a file of heightlines",
+        usage: "
+    Compress a file with heightlines into one custom file.
+      <inputfile> (string) input file
+      --ft (string) type of input file: shape, kml or geojson
+      --height-field (default none) (string) attribute or geojson property with the height, when the points have no z
+      --output (default outp) (string) define output file
+      --tolerance (default none) (string) largest quantization error up to 0.5, picks the smallest type and multiplier that meet it
+      --encoding (default raw) (string) how points are written: raw or delta, zigzag varint differences between points
+      --export (default none) (string) also write the shapes as geojson for debugging: none or geojson
+      --crs (default none) (string) target crs: wgs84, utm<zone><n|s>, rdnew or epsg:<code>
+      --utm-zone (default auto) (string) utm zone for lon/lat input without --crs, like 31n, auto takes the zone of the first point
+      --precision (default none) (string) round projected x and y to multiples of this, in units of the target crs
+      --height-snap (default auto) (string) snap heights to multiples of this or off, auto snaps kml and geojson to 5
+    ",
+        run: cmd_height,
+    },
+    Command{
+        name: "mergeheight",
+        about: "compress many heightline files into one file",
+        usage: "
+    Take many files with heightlines and compress them into one custom file.
+      <inputfile> (string...) input files
+      --ft (string) type of input files: shape, kml or geojson
+      --height-field (default none) (string) attribute or geojson property with the height, when the points have no z
       --output (default outp) (string) define output file
-      --mode (default info) (string) what to do
-      --ft (default none) (string) type of input file
-      --tag0 (default none) (string) xml tag variable
-      --tag1 (default none) (string) xml tag variable
-      --cuts (default 1) number of cuts when chunking
-      --cuts_multi (default 2) subdivide multiplier
+      --tolerance (default none) (string) largest quantization error up to 0.5, picks the smallest type and multiplier that meet it
+      --encoding (default raw) (string) how points are written: raw or delta, zigzag varint differences between points
+      --export (default none) (string) also write the shapes as geojson for debugging: none or geojson
+      --crs (default none) (string) target crs: wgs84, utm<zone><n|s>, rdnew or epsg:<code>
+      --utm-zone (default auto) (string) utm zone for lon/lat input without --crs, like 31n, auto takes the zone of the first point
+      --precision (default none) (string) round projected x and y to multiples of this, in units of the target crs
+      --height-snap (default auto) (string) snap heights to multiples of this or off, auto snaps kml and geojson to 5
+    ",
+        run: cmd_mergeheight,
+    },
+    Command{
+        name: "lintheight",
+        about: "print statistics about wrong heightlines",
+        usage: "
+    Find heightlines with more than one z value and print statistics about them.
+      <inputfile> (string...) input files
+      --ft (string) type of input files: shape, kml or geojson
+      --height-field (default none) (string) attribute or geojson property with the height, when the points have no z
+      --crs (default none) (string) target crs: wgs84, utm<zone><n|s>, rdnew or epsg:<code>
+      --utm-zone (default auto) (string) utm zone for lon/lat input without --crs, like 31n, auto takes the zone of the first point
+      --precision (default none) (string) round projected x and y to multiples of this, in units of the target crs
+      --height-snap (default auto) (string) snap heights to multiples of this or off, auto snaps kml and geojson to 5
+    ",
+        run: cmd_lintheight,
+    },
+    Command{
+        name: "chunkify",
+        about: "build LOD chunks from a merged height file",
+        usage: "
+    Take one compressed height file and build LOD chunks from it.
+      <inputfile> (string) compressed height file, made by height or mergeheight
+      --cuts (default 1) number of cuts over each axis on the first level
+      --cuts_multi (default 2) cuts multiplier between levels
       --levels (default 6) how many LOD's we have
-      --mods (integer...) heightline modulo's
-      "
-    );
-    // Read in cli arguments
-    let infiles = args.get_strings("inputfile");
-    let outfile = args.get_string("output");
-    let mode = args.get_string("mode");
-    let ft = args.get_string("ft");
-    let tag0 = args.get_string("tag0");
-    let tag1 = args.get_string("tag1");
-    let cuts = args.get_integer("cuts");
-    let cuts_multi = args.get_integer("cuts_multi");
-    let levels = args.get_integer("levels");
-    let mods = args.get_integers("mods");
+      --mods (integer...) heightline modulo of every level
+      --simplify (string...) line simplification of every level: none, every, dp, vw or topo
+      --detail (default 2000.0) the simplification tolerance is the chunk size divided by this
+      --budget (default none) (string) split chunks in a quadtree until they fit, points:<n> or bytes:<n>, instead of cutting a grid
+      --max-depth (default 10) deepest level of the quadtree, 0..32
+      --encoding (default raw) (string) how points are written: raw or delta, zigzag varint differences between points
+      --export (default none) (string) also write the shapes as geojson for debugging: none or geojson
+    ",
+        run: cmd_chunkify,
+    },
+    Command{
+        name: "polygonz",
+        about: "compress the PolygonZ's of a shapefile",
+        usage: "
+    Take a shapefile and compress its PolygonZ's into one custom file.
+      <inputfile> (string) input shapefile
+      --height-field (default none) (string) attribute with the height of polygons without z
+      --style-field (default none) (string) attribute with the style of every polygon
+      --output (default outp) (string) define output file
+      --tolerance (default none) (string) largest quantization error up to 0.5, picks the smallest type and multiplier that meet it
+      --encoding (default raw) (string) how points are written: raw or delta, zigzag varint differences between points
+      --export (default none) (string) also write the shapes as geojson for debugging: none or geojson
+      --crs (default none) (string) target crs: wgs84, utm<zone><n|s>, rdnew or epsg:<code>
+      --utm-zone (default auto) (string) utm zone for lon/lat input without --crs, like 31n, auto takes the zone of the first point
+      --precision (default none) (string) round projected x and y to multiples of this, in units of the target crs
+      --height-snap (default auto) (string) snap heights to multiples of this or off, auto snaps kml and geojson to 5
+    ",
+        run: cmd_polygonz,
+    },
+    Command{
+        name: "triangulate",
+        about: "triangulate and compress the PolygonZ's of a shapefile",
+        usage: "
+    Take a shapefile, triangulate its PolygonZ's and compress the triangles into one custom file.
+      <inputfile> (string) input shapefile
+      --height-field (default none) (string) attribute with the height of polygons without z
+      --style-field (default none) (string) attribute with the style of every polygon
+      --output (default outp) (string) define output file
+      --tolerance (default none) (string) largest quantization error up to 0.5, picks the smallest type and multiplier that meet it
+      --encoding (default raw) (string) how points are written: raw or delta, zigzag varint differences between points
+      --export (default none) (string) also write the shapes as geojson for debugging: none or geojson
+      --crs (default none) (string) target crs: wgs84, utm<zone><n|s>, rdnew or epsg:<code>
+      --utm-zone (default auto) (string) utm zone for lon/lat input without --crs, like 31n, auto takes the zone of the first point
+      --precision (default none) (string) round projected x and y to multiples of this, in units of the target crs
+      --height-snap (default auto) (string) snap heights to multiples of this or off, auto snaps kml and geojson to 5
+    ",
+        run: cmd_triangulate,
+    },
+    Command{
+        name: "geopolys",
+        about: "triangulate and chunk the polygons of geological kml files",
+        usage: "
+    Take the polygons from kml or geojson files, triangulate them and cut them into chunks.
+      <inputfile> (string...) input files
+      --ft (default kml) (string) type of input files: kml or geojson
+      --style-field (default style) (string) geojson property with the style id
+      --cuts (default 1) number of cuts over each axis on the first level
+      --cuts_multi (default 2) cuts multiplier between levels
+      --levels (default 1) how many LOD's we have
+      --simplify (string...) polygon simplification of every level: none, dp, vw or topo
+      --detail (default 2000.0) the simplification tolerance is the chunk size divided by this
+      --cull (default 4.0) leave out polygons and holes smaller than this many tolerances squared, on all but the last level
+      --budget (default none) (string) split chunks in a quadtree until they fit, points:<n> or bytes:<n>, instead of cutting a grid
+      --max-depth (default 10) deepest level of the quadtree, 0..32
+      --encoding (default raw) (string) how points are written: raw or delta, zigzag varint differences between points
+      --export (default none) (string) also write the shapes as geojson for debugging: none or geojson
+      --crs (default none) (string) target crs: wgs84, utm<zone><n|s>, rdnew or epsg:<code>
+      --utm-zone (default auto) (string) utm zone for lon/lat input without --crs, like 31n, auto takes the zone of the first point
+      --precision (default none) (string) round projected x and y to multiples of this, in units of the target crs
+      --height-snap (default auto) (string) snap heights to multiples of this or off, auto snaps kml and geojson to 5
+    ",
+        run: cmd_geopolys,
+    },
+    Command{
+        name: "geolines",
+        about: "chunk the lines of geological kml files",
+        usage: "
+    Take the lines from kml or geojson files and cut them into chunks.
+      <inputfile> (string...) input files
+      --ft (default kml) (string) type of input files: kml or geojson
+      --style-field (default style) (string) geojson property with the style id
+      --cuts (default 1) number of cuts over each axis on the first level
+      --cuts_multi (default 2) cuts multiplier between levels
+      --levels (default 1) how many LOD's we have
+      --simplify (string...) line simplification of every level: none, every, dp, vw or topo
+      --detail (default 2000.0) the simplification tolerance is the chunk size divided by this
+      --budget (default none) (string) split chunks in a quadtree until they fit, points:<n> or bytes:<n>, instead of cutting a grid
+      --max-depth (default 10) deepest level of the quadtree, 0..32
+      --encoding (default raw) (string) how points are written: raw or delta, zigzag varint differences between points
+      --export (default none) (string) also write the shapes as geojson for debugging: none or geojson
+      --crs (default none) (string) target crs: wgs84, utm<zone><n|s>, rdnew or epsg:<code>
+      --utm-zone (default auto) (string) utm zone for lon/lat input without --crs, like 31n, auto takes the zone of the first point
+      --precision (default none) (string) round projected x and y to multiples of this, in units of the target crs
+      --height-snap (default auto) (string) snap heights to multiples of this or off, auto snaps kml and geojson to 5
+    ",
+        run: cmd_geolines,
+    },
+    Command{
+        name: "inspect",
+        about: "decode output files and print what is inside",
+        usage: "
+    Read back files written by this tool and print what is inside them.
+    The format is read from the file header. Give --kind to only accept files of that format:
+    height, polygonz, triangles, hlinechunk, polychunk, geolinechunk, info, polyinfo, geolineinfo, styles, quadtree.
+      <inputfile> (string...) files to decode
+      --kind (default auto) (string) expected format of the files
+      --dump print every shape instead of a summary
+      --world print points in world coordinates instead of quantized values
+    ",
+        run: cmd_inspect,
+    },
+    Command{
+        name: "xmltree",
+        about: "print the xml start tags as an indented tree",
+        usage: "
+    Print the xml start tags of every file in indented tree form.
+      <inputfile> (string...) input xml files
+    ",
+        run: cmd_xmltree,
+    },
+    Command{
+        name: "xmltags",
+        about: "print every xml tag with its count",
+        usage: "
+    Print every xml tag of every file with its count.
+      <inputfile> (string...) input xml files
+    ",
+        run: cmd_xmltags,
+    },
+    Command{
+        name: "check-tag-child",
+        about: "check if a tag always has a certain child tag",
+        usage: "
+    Check for every file if the parent tag always has the child tag inside.
+      <inputfile> (string...) input xml files
+      --parent (string) parent tag
+      --child (string) child tag
+    ",
+        run: cmd_check_tag_child,
+    },
+    Command{
+        name: "check-nonempty-tag",
+        about: "check if a tag is never empty",
+        usage: "
+    Check for every file if the tag never is empty.
+      <inputfile> (string...) input xml files
+      --tag (string) tag to check
+    ",
+        run: cmd_check_nonempty_tag,
+    },
+];
 
-    let mut logger = Logger::default();
+fn main(){
+    std::process::exit(run());
+}
 
-    println!("Shapefile/KML processor...");
-    let timer = Instant::now();
-    // Take one file
-    let get_only_path = ||{
-        if infiles.is_empty() {
-            logger.report();
-            return Option::None;
-        }
-        Option::Some(infiles[0].clone())
+// Pick the subcommand and run it, returns the exit code.
+fn run() -> i32{
+    let args: Vec<String> = std::env::args().collect();
+    let name = if let Some(name) = args.get(1){ name.as_str() }
+    else {
+        print_commands();
+        return EXIT_FAILURE;
     };
-    // Take all files
-    let read_single_file = |infile: String|{
-        if let Ok(shapes) = shapefile::read(&infile){
-            println!("Read file \"{}\": {} ms, shapes: {}", infile, timer.elapsed().as_millis(), shapes.len());
-            Option::Some(shapes)
+    if name == "help" || name == "--help" || name == "-h"{
+        if let Some(cmd) = args.get(2).and_then(|n| find_command(n)){
+            println!("{}", cmd.usage);
         }else{
-            println!("Could not read file: {}", infile);
-            Option::None
+            print_commands();
         }
+        return EXIT_OK;
+    }
+    let cmd = if let Some(cmd) = find_command(name){ cmd }
+    else {
+        eprintln!("Unknown command: {}", name);
+        print_commands();
+        return EXIT_UNKNOWN_COMMAND;
     };
-    // Read one file, assume it's the only one, more can't hurt.
-    let read_only_file = ||{
-        if infiles.is_empty() {
-            logger.report();
-            return Option::None;
-        }
-        read_single_file(infiles[0].clone())
-    };
-    let write_buffer = |filename: &str, buffer: &Buffer, timer: &std::time::Instant|{
-        let ok = buffer_write_file(Path::new(filename), buffer);
-        println!("Writing file \"{}\", went ok?: {}, {} ms", filename, ok, timer.elapsed().as_millis());
-    };
-    // Compress and bufferize and write collection.
-    macro_rules! compress_and_write{
-        ($col:expr) =>{
-            let infos = info_package(&$col);
-            let buffer = $col.compress(infos, &mut logger);
-            write_buffer(&outfile, &buffer, &timer);
+    // lapp quits with a non zero exit code itself on invalid arguments
+    let mut cargs = Args::new(cmd.usage).start(2);
+    cargs.parse();
+    let mut logger = Logger::default();
+    println!("Shapefile/KML processor...");
+    let res = (cmd.run)(&cargs, &mut logger);
+    logger.report();
+    match res{
+        Ok(()) => EXIT_OK,
+        Err(err) => {
+            eprintln!("Error ({}): {}", cmd.name, err);
+            EXIT_FAILURE
         }
     }
-    // read in heightlines. depending on the choice the user made, shapefile or kml.
-    // shapefile is assumed to be in utm and kml is assumed to be in lat/lon.
-    macro_rules! get_plinezs{
-        ($path:expr) => {
-            if &ft == "none"{
-                println!("No filetype specified!");
-                logger.report();
-                return None;
-            }else if &ft == "shape"{
-                let shapes = read_single_file($path)?;
-                split(shapes, &mut logger).5
-            }else if &ft == "kml"{
-                kml_height(&$path)
-            }else{
-                println!("Unknown filetype specified!");
-                logger.report();
-                return None;
-            }
-        }
+}
+
+fn find_command(name: &str) -> Option<&'static Command>{
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+fn print_commands(){
+    println!("Preprocess shapefiles and kml files into more efficient files.");
+    println!("Usage: shapefile-linter <command> [options] <inputfile>...");
+    println!("Commands:");
+    for cmd in COMMANDS{
+        println!("  {:20}{}", cmd.name, cmd.about);
     }
-    if mode == "shapeinfo"{// Just print info about shapefile content.
-        if &ft != "shape" {
-            println!("This mode only works on shapefiles!");
-            logger.report();
-            return None;
-        }
-        let shapes = read_only_file()?;
-        print_shape_content(&shapes);
-        let splitted = split(shapes, &mut logger);
-        print_split_content(&splitted);
-    }else if mode == "mergeheight"{// Take many heightfiles and combine them into one big compressed one.
-        println!("{:?}", infiles);
-        let mut collection = Vec::new();
-        for file in infiles{
-            let plinezs = get_plinezs!(file);
-            let mut shapezs = compress_heightmap(plinezs, &mut logger);
-            collection.append(&mut shapezs);
-        }
-        compress_and_write!(collection);
-    }else if mode == "lintheight"{// Print info about heightlines
-        let mut wrongs = Vec::new();
-        for file in infiles{
-            let plinezs = get_plinezs!(file);
-            let mut vec = collect_wrong_heightlines(plinezs, &mut logger);
-            wrongs.append(&mut vec);
-        }
-        println!("There are {} wrong heightlines", wrongs.len());
-        let mut diffs = Vec::new();
-        let mut sames = Vec::new();
-        let mut lens = Vec::new();
-        for (i,wrong) in wrongs.iter().enumerate(){
-            let min = wrong.iter().fold(std::f64::MAX, |m,x| m.min(*x));
-            let max = wrong.iter().fold(std::f64::MIN, |m,x| m.max(*x));
-            let mut countmap = HashMap::new();
-            for x in wrong{
-                let y: usize;
-                unsafe{ y = std::mem::transmute(x); }
-                let newcount = match countmap.get(&y){
-                    Some(n) => { n + 1 },
-                    None => { 1 },
-                };
-                countmap.insert(y, newcount);
-            }
-            let mut vec = Vec::new();
-            for item in &countmap{
-                vec.push(item);
-            }
-            vec.sort_by_key(|x| x.1);
-            let same = if vec.is_empty(){ 0 }
-            else { *vec[vec.len() - 1].1 };
-            let diff = max - min;
-            diffs.push(diff);
-            sames.push(same);
-            lens.push(wrong.len());
-            println!("Line {}: min: {} max: {} diff: {} same: {} len: {}", i, min, max, diff, same, wrong.len());
-        }
-        if diffs.is_empty(){
-            println!("median: 0\nmean: 0");
-            logger.report();
-            return Option::Some(());
-        }
-        let mean = diffs.iter().fold(0.0, |sum,x| sum + x) / diffs.len() as f64;
-        let min = diffs.iter().fold(std::f64::MAX, |m,x| m.min(*x));
-        let max = diffs.iter().fold(std::f64::MIN, |m,x| m.max(*x));
-        let mut diffs: Vec<u64> = diffs.iter().map(|x| *x as u64).collect::<Vec<u64>>();
-        diffs.sort_unstable();
-        let median = diffs[diffs.len() / 2];
-        lens.sort_unstable();
-        sames.sort_unstable();
-        let lens_median = lens[lens.len() / 2];
-        let sames_median = sames[sames.len() / 2];
-        println!("Differences between min and max in lines, summary:");
-        println!("median: {}", median);
-        println!("mean: {}", mean);
-        println!("min: {}", min);
-        println!("max: {}", max);
-        println!("length median: {}", lens_median);
-        println!("same value's median: {}", sames_median);
-    }else if mode == "chunkify"{// Take one compressed height file and build chunks from it.
-        let string_path = &get_only_path()?;
-        let path = std::path::Path::new(string_path);
-        let mut buffer = ReadBuffer::from_raw(buffer_read_file(path)?);
-        let mx = u64::from_buffer(&mut buffer)?;
-        let my = u64::from_buffer(&mut buffer)?;
-        let mz = u64::from_buffer(&mut buffer)?;
-        let multi = u64::from_buffer(&mut buffer)?;
-        let tsize = u8::from_buffer(&mut buffer)?;
-        let bmin = <(u16,u16,u16)>::from_buffer(&mut buffer)?;
-        let bmax = <(u16,u16,u16)>::from_buffer(&mut buffer)?;
-        let shapes = <std::vec::Vec<ShapeZ<u16>> as Bufferable>::from_buffer(&mut buffer)?;
-        println!("mx: {} my: {} mz: {} multi: {}", mx, my, mz, multi);
-        println!("{:?}{:?}", bmin, bmax);
-        print_height_distribution(&shapes);
-        let cuts_mul = if cuts_multi > 0 { cuts_multi as u64 }
-        else { panic!("Cuts multiplier must be at least one!"); };
-        let mut cuts = if cuts > 0 { cuts as u64 }
-        else { panic!("Cuts should be at least one!"); };
-        let mut info_buffer = Vec::new();
-        let levels = if levels > 0 { levels as u64 }
-        else { panic!("Levels can not be smaller than one!"); };
-        levels.into_buffer(&mut info_buffer);
-        let mods = if !mods.is_empty() { mods.into_iter().map(|x| x as u64).collect::<Vec<_>>() }
-        else { vec![400,200,100,50,25,5] };
-        if mods.len() != levels as usize{
-            panic!("Mods lenght must equal levels!");
-        }
-        for i in 0..levels{
-            for (x,y,chunk) in cut(cuts.max(1), (bmin,bmax), &shapes, &mut logger){
-                let points0 = chunk.iter().fold(0, |sum, sz| sum + sz.points_len());
-                let mut buffer = Vec::new();
-                i.into_buffer(&mut buffer);
-                x.into_buffer(&mut buffer);
-                y.into_buffer(&mut buffer);
-                let filtered = pick_heights(mods[i as usize], chunk);
-                let points1 = filtered.iter().fold(0, |sum, sz| sum + sz.points_len());
-                let max = if mods[i as usize] <= 5 { std::usize::MAX } else { 5000 };
-                let picked = pick_points(max, filtered);
-                let points2 = picked.iter().fold(0, |sum, sz| sum + sz.points_len());
-                let lines0 = picked.len();
-                let mut lines1 = lines0;
-                let finalv = if i < 4 {
-                    let opti = optimize_lines(picked);
-                    lines1 = opti.len();
-                    opti
-                }else{
-                    picked
-                };
-                finalv.into_buffer(&mut buffer);
-                let filename = &format!("{}-{}-{}.hlinechunk", i, x, y);
-                write_buffer(filename, &buffer, &timer);
-                println!("l0: {} l1: {} l2: {} s0: {} s1: {}", points0, points1, points2, lines0, lines1);
-            }
-            cuts.into_buffer(&mut info_buffer);
-            cuts *= cuts_mul;
-        }
-        mx.into_buffer(&mut info_buffer);
-        my.into_buffer(&mut info_buffer);
-        mz.into_buffer(&mut info_buffer);
-        multi.into_buffer(&mut info_buffer);
-        tsize.into_buffer(&mut info_buffer);
-        bmin.into_buffer(&mut info_buffer);
-        bmax.into_buffer(&mut info_buffer);
-        mods.into_buffer(&mut info_buffer);
-        let ok = buffer_write_file(Path::new("chunks.info"), &info_buffer);
-        println!("Writing file \"chunks.info\" ok?: {}", ok);
-    }else if mode == "polygonz"{// Take shapefile and compress the polygonZ's
-        let shapes = read_only_file()?;
-        let polys = split(shapes, &mut logger).11;
-        let polyzs: Vec<PolygonZ<f64>> = polys.into_iter().map(|p| PolygonZ::from(p,0)).collect();
-        let infos = info_package(&polyzs);
-        let buffer = polyzs.compress(infos, &mut logger);
-        println!("Bufferized: {} ms", timer.elapsed().as_millis());
-        write_buffer(&outfile, &buffer, &timer);
-    }else if mode == "triangulate"{ // take polygonz's and triangulate and compress them
-        let shapes = read_only_file()?;
-        let polys = split(shapes, &mut logger).11;
-        let polyzs: Vec<PolygonZ<f64>> = polys.into_iter().map(|p| PolygonZ::from(p,0)).collect();
-        let infos = info_package(&polyzs);
-        let buffer = polyzs.triangle_compress(infos, &mut logger);
-        write_buffer(&outfile, &buffer, &timer);
-    }else if mode == "height"{// Compress shapefile, assuming it consist of height lines.
-        let path = get_only_path()?;
-        let plinezs = get_plinezs!(path);
-        let shapezs = compress_heightmap(plinezs, &mut logger);
-        println!("Compressed: {} ms", timer.elapsed().as_millis());
-        let infos = info_package(&shapezs);
-        let buffer = shapezs.compress(infos, &mut logger);
-        println!("Bufferized: {} ms", timer.elapsed().as_millis());
-        write_buffer(&outfile, &buffer, &timer);
-    }else if mode == "xmltree"{// print out the xml open tags in indented tree form.
-        for file in infiles{
-            println!("\t File: {}", file);
-            print_xml_tag_tree(&file);
-        }
-    }else if mode == "xmltags"{// print out every xml tag with its count.
-        for file in infiles{
-            println!("\t File: {}", file);
-            print_xml_tag_count(&file);
-        }
-    }else if mode == "geopolys"{// take the polygons from kml and chunkify them
-        let mut styles = Vec::new();
-        let mut counter = 0;
-        let mut polyzs = Vec::new();
-        for file in infiles{
-            let polys = kml_geo(&file, &mut styles, &mut counter, &mut logger);
-            let stpolyzs: Vec<_> = polys.into_iter().map(|(sty,poly)| PolygonZ::from(poly,sty)).collect();
-            polyzs.extend(stpolyzs);
-        }
-        let mut polyzs = polyzs.into_iter().map(int_cast).collect::<Vec<_>>();
-        polyzs.iter_mut().for_each(|p| p.stretch_bb());
-        println!("There are {} polygons!", polyzs.len());
-        let gbb = get_global_bb(&polyzs);
-        let cuts = if cuts > 0 && cuts < 265{ cuts as u8 }
-        else { panic!("Cuts need to be in range 1..256"); };
-        let triangles = crate::triangulate::triangulate(polyzs, &mut logger);
-        let chunks = crate::chunkify::chunkify_polytriangles(cuts, gbb, triangles);
-        for (x,y,chunk) in chunks{
-            let infos = info_package(&chunk);
-            let buffer = chunk.compress(infos, &mut logger);
-            let filename = &format!("{}-{}.polychunk", x, y);
-            write_buffer(filename, &buffer, &timer);
-        }
-        let mut stylebuffer = Vec::new();
-        styles.into_buffer(&mut stylebuffer);
-        write_buffer("styles", &stylebuffer, &timer);
-        let mut infobuffer = Vec::new();
-        gbb.into_buffer(&mut infobuffer);
-        cuts.into_buffer(&mut infobuffer);
-        write_buffer("chunks.polyinfo", &infobuffer, &timer);
-    }else if mode == "geolines"{// take the lines from kml and chunkify them
-        let mut styles = Vec::new();
-        let mut counter = 0;
-        let mut lines = Vec::new();
-        for file in infiles{
-            let llines = kml_geo_lines(&file, &mut styles, &mut counter, &mut logger);
-            lines.extend(llines);
-        }
-        let mut slines = Vec::new();
-        for l in lines{
-            StyledLine::<u32>::from_as_int(l, &mut slines);
-        }
-        println!("There are {} lines!", slines.len());
-        let gbb = get_global_bb(&slines);
-        let cuts = if cuts > 0 && cuts < 265{ cuts as u8 }
-        else { panic!("Cuts need to be in range 1..256"); };
-        let chunks = crate::chunkify::cut_styled(cuts, gbb, &slines);
-        for (x,y,chunk) in chunks{
-            let infos = info_package(&chunk);
-            let buffer = chunk.compress(infos, &mut logger);
-            let filename = &format!("{}-{}.geolinechunk", x, y);
-            write_buffer(filename, &buffer, &timer);
-        }
-        let mut infobuffer = Vec::new();
-        gbb.into_buffer(&mut infobuffer);
-        cuts.into_buffer(&mut infobuffer);
-        write_buffer("chunks.geolineinfo", &infobuffer, &timer);
-        let mut stylebuffer = Vec::new();
-        styles.into_buffer(&mut stylebuffer);
-        write_buffer("styles", &stylebuffer, &timer);
-    }else if mode == "check-tag-child"{
-        for file in infiles{
-            println!("{}", check_tag_child(&file,&tag0,&tag1));
-        }
-    }else if mode == "check-nonempty-tag"{
-        for file in infiles{
-            println!("{}", check_nonempty_tag(&file,&tag0));
+    println!("Use `shapefile-linter help <command>` for the options of a command.");
+}
+
+// integer flag that needs to be at least one
+fn get_positive(args: &Args, flag: &str) -> Result<u64>{
+    let v = args.get_integer(flag);
+    if v > 0 { Ok(v as u64) }
+    else { Err(Error::Argument(format!("--{} should be at least one, got {}", flag, v))) }
+}
+
+// the simplification of every level, dp on all but the last when not given
+fn get_simplify(args: &Args, levels: usize) -> Result<Vec<Simplify>>{
+    let simplify = args.get_strings("simplify").iter().map(|s| s.parse()).collect::<Result<Vec<_>>>()?;
+    let simplify = if !simplify.is_empty() { simplify } else { default_simplify(levels) };
+    if simplify.len() != levels{
+        return Err(Error::Argument(format!("Simplify length ({}) must equal levels ({})!", simplify.len(), levels)));
+    }
+    Ok(simplify)
+}
+
+// a quadtree budget, none cuts a grid
+fn get_budget(args: &Args) -> Result<Option<Budget>>{
+    match args.get_string("budget").as_str(){
+        "none" => Ok(None),
+        s => s.parse().map(Some),
+    }
+}
+
+fn get_max_depth(args: &Args) -> Result<u8>{
+    let depth = args.get_integer("max-depth");
+    if (0..=32).contains(&depth) { Ok(depth as u8) }
+    else { Err(Error::Argument(format!("--max-depth needs to be in range 0..32, got {}", depth))) }
+}
+
+// the levels of geopolys and geolines, only geopolys culls
+fn get_geo_lod_settings(args: &Args) -> Result<GeoLodSettings>{
+    Ok(GeoLodSettings{
+        cuts: get_positive(args, "cuts")?,
+        cuts_multi: get_positive(args, "cuts_multi")?,
+        simplify: get_simplify(args, get_positive(args, "levels")? as usize)?,
+        detail: args.get_float("detail") as f64,
+        cull: args.get_float_result("cull").map_or(0.0, |c| c as f64),
+        budget: get_budget(args)?,
+        max_depth: get_max_depth(args)?,
+        encoding: get_encoding(args)?,
+        export: get_export(args)?,
+    })
+}
+
+// attribute flag, none or a command without the flag means not given
+fn get_field(args: &Args, flag: &str) -> Option<String>{
+    args.get_string_result(flag).ok().filter(|f| f != "none")
+}
+
+// a positive step to round to, none or off means not rounding
+fn get_step(args: &Args, flag: &str) -> Result<Option<f64>>{
+    match args.get_string_result(flag).ok().as_deref(){
+        None | Some("none") | Some("off") => Ok(None),
+        Some(s) => s.parse::<f64>().ok().filter(|x| *x > 0.0).map(Some).ok_or_else(||
+            Error::Argument(format!("--{} needs to be a positive number or off, got {}", flag, s))
+        ),
+    }
+}
+
+// kml and geojson heights used to be always snapped to 5, shapefiles never
+fn get_height_snap(args: &Args) -> Result<Option<f64>>{
+    if args.get_string_result("height-snap").ok().as_deref() != Some("auto"){
+        return get_step(args, "height-snap");
+    }
+    match args.get_string_result("ft").ok().map(|ft| ft.parse()).transpose()?{
+        Some(FileType::Kml) | Some(FileType::GeoJson) => Ok(Some(5.0)),
+        _ => Ok(None),
+    }
+}
+
+// the fields, target crs, utm zone and rounding to read input with
+fn get_read_settings(args: &Args) -> Result<ReadSettings>{
+    Ok(ReadSettings{
+        height_field: get_field(args, "height-field"),
+        style_field: get_field(args, "style-field"),
+        crs: get_field(args, "crs").map(|c| c.parse()).transpose()?,
+        utm_zone: args.get_string_result("utm-zone").ok().filter(|z| z != "auto").map(|z| z.parse()).transpose()?,
+        precision: get_step(args, "precision")?,
+        height_snap: get_height_snap(args)?,
+    })
+}
+
+// polygons from a shapefile, with the height and style attributes
+fn get_polygonzs(args: &Args, logger: &mut Logger) -> Result<Vec<PolygonZ<f64>>>{
+    let settings = get_read_settings(args)?;
+    let (polyzs, classes) = read_polygonzs(&args.get_string("inputfile"), &settings, logger)?;
+    print_style_classes(&classes);
+    Ok(polyzs)
+}
+
+// the largest quantization error, none picks the type by the range only
+// whole steps already round to within 0.5, coarser steps than that can not be written
+fn get_tolerance(args: &Args) -> Result<Option<f64>>{
+    match get_step(args, "tolerance")?{
+        Some(t) if t > 0.5 => Err(Error::Argument(format!("--tolerance can be at most 0.5, whole steps already meet it, got {}", t))),
+        tolerance => Ok(tolerance),
+    }
+}
+
+fn get_encoding(args: &Args) -> Result<Encoding>{
+    args.get_string("encoding").parse()
+}
+
+fn get_export(args: &Args) -> Result<Export>{
+    args.get_string("export").parse()
+}
+
+fn write_file(filename: &str, buffer: &bin_buffer::Buffer, timer: &Instant) -> CmdResult{
+    write_buffer(filename, buffer)?;
+    println!("Written file \"{}\", {} ms", filename, timer.elapsed().as_millis());
+    Ok(())
+}
+
+// Just print info about shapefile content.
+fn cmd_shapeinfo(args: &Args, logger: &mut Logger) -> CmdResult{
+    let shapes = read_shapefile(&args.get_string("inputfile"))?;
+    print_shape_content(&shapes);
+    let splitted = split(shapes, logger);
+    print_split_content(&splitted);
+    Ok(())
+}
+
+// Compress shapefile, assuming it consist of height lines.
+fn cmd_height(args: &Args, logger: &mut Logger) -> CmdResult{
+    let timer = Instant::now();
+    let ft = args.get_string("ft").parse()?;
+    let settings = get_read_settings(args)?;
+    let plinezs = read_heightlines(&Input::open(ft, &args.get_string("inputfile"))?, &settings, logger)?;
+    let export = get_export(args)?;
+    let tolerance = get_tolerance(args)?;
+    let encoding = get_encoding(args)?;
+    let output = args.get_string("output");
+    let shapezs = heightlines_to_shapezs(plinezs, logger);
+    println!("Compressed: {} ms", timer.elapsed().as_millis());
+    if export == Export::GeoJson{
+        let geojson = geojson_output(&format!("{}.geojson", output), &shapezs, &[]);
+        write_file(&geojson.name, &geojson.buffer, &timer)?;
+    }
+    let buffer = compress_heightlines(shapezs, tolerance, encoding, logger)?;
+    println!("Bufferized: {} ms", timer.elapsed().as_millis());
+    write_file(&output, &buffer, &timer)
+}
+
+// Take many heightfiles and combine them into one big compressed one.
+fn cmd_mergeheight(args: &Args, logger: &mut Logger) -> CmdResult{
+    let timer = Instant::now();
+    let ft = args.get_string("ft").parse()?;
+    let settings = get_read_settings(args)?;
+    let export = get_export(args)?;
+    let tolerance = get_tolerance(args)?;
+    let encoding = get_encoding(args)?;
+    let output = args.get_string("output");
+    let infiles = args.get_strings("inputfile");
+    println!("{:?}", infiles);
+    // one bad file does not stop the batch
+    let mut inputs = Vec::new();
+    for file in infiles{
+        match Input::open(ft, &file){
+            Ok(input) => inputs.push(input),
+            Err(e) => logger.skip(Issue::SkippedFile, e),
         }
-    }else{
-        println!("Unsupported mode!");
     }
-    logger.report();
-    Option::Some(())
+    check_same_crs(&inputs, settings.crs)?;
+    // one zone for all files
+    let settings = with_utm_zone(&inputs, &settings)?;
+    let mut collection = Vec::new();
+    for input in &inputs{
+        let plinezs = match read_heightlines(input, &settings, logger){
+            Ok(plinezs) => plinezs,
+            Err(e) => { logger.skip(Issue::SkippedFile, e); continue; },
+        };
+        collection.append(&mut heightlines_to_shapezs(plinezs, logger));
+    }
+    if export == Export::GeoJson{
+        let geojson = geojson_output(&format!("{}.geojson", output), &collection, &[]);
+        write_file(&geojson.name, &geojson.buffer, &timer)?;
+    }
+    let buffer = compress_heightlines(collection, tolerance, encoding, logger)?;
+    write_file(&output, &buffer, &timer)
+}
+
+// Print info about heightlines
+fn cmd_lintheight(args: &Args, logger: &mut Logger) -> CmdResult{
+    let ft = args.get_string("ft").parse()?;
+    let settings = get_read_settings(args)?;
+    let inputs = open_inputs(ft, &args.get_strings("inputfile"))?;
+    check_same_crs(&inputs, settings.crs)?;
+    // one zone for all files
+    let settings = with_utm_zone(&inputs, &settings)?;
+    let mut wrongs = Vec::new();
+    for input in &inputs{
+        let plinezs = read_heightlines(input, &settings, logger)?;
+        wrongs.append(&mut lint_heightlines(plinezs, logger));
+    }
+    print_wrong_heightlines_stats(&wrongs);
+    Ok(())
 }
 
+// Take one compressed height file and build chunks from it.
+fn cmd_chunkify(args: &Args, logger: &mut Logger) -> CmdResult{
+    let timer = Instant::now();
+    let levels = get_positive(args, "levels")?;
+    let mods = args.get_integers("mods");
+    let mods = if !mods.is_empty() { mods.into_iter().map(|x| x as u64).collect::<Vec<_>>() }
+    else { LodSettings::default().mods };
+    if mods.len() != levels as usize{
+        return Err(Error::Argument(format!("Mods length ({}) must equal levels ({})!", mods.len(), levels)));
+    }
+    let simplify = get_simplify(args, levels as usize)?;
+    let settings = LodSettings{
+        cuts: get_positive(args, "cuts")?,
+        cuts_multi: get_positive(args, "cuts_multi")?,
+        mods,
+        simplify,
+        detail: args.get_float("detail") as f64,
+        budget: get_budget(args)?,
+        max_depth: get_max_depth(args)?,
+        encoding: get_encoding(args)?,
+        export: get_export(args)?,
+    };
+    let hf = read_height_file(&args.get_string("inputfile"))?;
+    let outputs = chunkify_heightlines(&hf, &settings, logger)?;
+    write_outputs(Path::new("."), &outputs)?;
+    println!("Done: {} ms", timer.elapsed().as_millis());
+    Ok(())
+}
+
+// Take shapefile and compress the polygonZ's
+fn cmd_polygonz(args: &Args, logger: &mut Logger) -> CmdResult{
+    let timer = Instant::now();
+    let export = get_export(args)?;
+    let tolerance = get_tolerance(args)?;
+    let encoding = get_encoding(args)?;
+    let output = args.get_string("output");
+    let polyzs = get_polygonzs(args, logger)?;
+    if export == Export::GeoJson{
+        let geojson = geojson_output(&format!("{}.geojson", output), &polyzs, &[]);
+        write_file(&geojson.name, &geojson.buffer, &timer)?;
+    }
+    let buffer = compress_polygonzs(polyzs, tolerance, encoding, logger)?;
+    println!("Bufferized: {} ms", timer.elapsed().as_millis());
+    write_file(&output, &buffer, &timer)
+}
+
+// take polygonz's and triangulate and compress them
+fn cmd_triangulate(args: &Args, logger: &mut Logger) -> CmdResult{
+    let timer = Instant::now();
+    let export = get_export(args)?;
+    let tolerance = get_tolerance(args)?;
+    let encoding = get_encoding(args)?;
+    let output = args.get_string("output");
+    let polyzs = get_polygonzs(args, logger)?;
+    if export == Export::GeoJson{
+        let geojson = triangulated_geojson_output(&format!("{}.geojson", output), &polyzs);
+        write_file(&geojson.name, &geojson.buffer, &timer)?;
+    }
+    let buffer = compress_triangulated(polyzs, tolerance, encoding, logger)?;
+    write_file(&output, &buffer, &timer)
+}
+
+// take the polygons from kml and chunkify them
+fn cmd_geopolys(args: &Args, logger: &mut Logger) -> CmdResult{
+    let timer = Instant::now();
+    let settings = get_geo_lod_settings(args)?;
+    let ft = args.get_string("ft").parse()?;
+    let inputs = open_inputs(ft, &args.get_strings("inputfile"))?;
+    let (polyzs, styles) = read_geopolys(&inputs, &get_read_settings(args)?, logger)?;
+    let outputs = chunkify_geopolys(polyzs, styles, &settings, logger)?;
+    write_outputs(Path::new("."), &outputs)?;
+    println!("Done: {} ms", timer.elapsed().as_millis());
+    Ok(())
+}
+
+// take the lines from kml and chunkify them
+fn cmd_geolines(args: &Args, logger: &mut Logger) -> CmdResult{
+    let timer = Instant::now();
+    let settings = get_geo_lod_settings(args)?;
+    let ft = args.get_string("ft").parse()?;
+    let inputs = open_inputs(ft, &args.get_strings("inputfile"))?;
+    let (slines, styles) = read_geolines(&inputs, &get_read_settings(args)?, logger)?;
+    let outputs = chunkify_geolines(slines, styles, &settings, logger)?;
+    write_outputs(Path::new("."), &outputs)?;
+    println!("Done: {} ms", timer.elapsed().as_millis());
+    Ok(())
+}
+// decode our own binary files and print them
+fn cmd_inspect(args: &Args, _logger: &mut Logger) -> CmdResult{
+    let kind = args.get_string("kind");
+    let kind = if kind == "auto" { None } else { Some(kind.parse()?) };
+    for file in args.get_strings("inputfile"){
+        println!("\t File: {}", file);
+        let decoded = decode_file(&file, kind)?;
+        print_decoded(&decoded, args.get_bool("dump"), args.get_bool("world"));
+    }
+    Ok(())
+}
+
+// print out the xml open tags in indented tree form.
+fn cmd_xmltree(args: &Args, _logger: &mut Logger) -> CmdResult{
+    for file in args.get_strings("inputfile"){
+        println!("\t File: {}", file);
+        print_xml_tag_tree(&file)?;
+    }
+    Ok(())
+}
+
+// print out every xml tag with its count.
+fn cmd_xmltags(args: &Args, _logger: &mut Logger) -> CmdResult{
+    for file in args.get_strings("inputfile"){
+        println!("\t File: {}", file);
+        print_xml_tag_count(&file)?;
+    }
+    Ok(())
+}
+
+fn cmd_check_tag_child(args: &Args, _logger: &mut Logger) -> CmdResult{
+    let parent = args.get_string("parent");
+    let child = args.get_string("child");
+    for file in args.get_strings("inputfile"){
+        println!("{}", check_tag_child(&file, &parent, &child)?);
+    }
+    Ok(())
+}
+
+fn cmd_check_nonempty_tag(args: &Args, _logger: &mut Logger) -> CmdResult{
+    let tag = args.get_string("tag");
+    for file in args.get_strings("inputfile"){
+        println!("{}", check_nonempty_tag(&file, &tag)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,917 @@
+//! The stable API of this crate.
+//!
+//! Every stage of what the command line tool does is a function here:
+//! read → lint → compress → chunk → write.
+//! The reading functions give back raw shapes, the compress and chunk functions give back
+//! [`OutputFile`]s that can be written to disk with [`write_outputs`] or used in memory.
+
+use crate::{
+    data::*,
+    info::*,
+    compress::*,
+    chunkify::*,
+    kml::*,
+    logger::*,
+    error::{ Error, Result },
+    header::{ header_buffer, flagged_header_buffer },
+    dbf::{ read_shapes_and_records, check_field, record_number, record_class },
+    geojson::{ GeoJson, GeoJsonFeature, GeoJsonFile, Transform, geojson_height, geojson_geo, geojson_geo_lines },
+    crs::{ Projection, Points, crs_name },
+    triangulate::triangulate,
+    quadtree::{ QuadNode, Square, quad_root, quadtree, square_corner },
+};
+
+pub use crate::decode::{ FormatKind, Decoded, Quantization };
+pub use crate::crs::{ Crs, UtmZone };
+pub use crate::chunkify::Simplify;
+pub use crate::quadtree::Budget;
+pub use crate::encoding::Encoding;
+
+use std::{
+    path::Path,
+    str::FromStr,
+    collections::HashMap,
+};
+
+use bin_buffer::*;
+
+/// Extra debug output next to the binary files.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Export{
+    None,
+    GeoJson,
+}
+
+impl FromStr for Export{
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "none" => Ok(Export::None),
+            "geojson" => Ok(Export::GeoJson),
+            x => Err(Error::Argument(format!("Unknown export format: {}, expected none or geojson", x))),
+        }
+    }
+}
+
+/// The input filetypes shapes can come from.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum FileType{
+    Shape,
+    Kml,
+    GeoJson,
+}
+
+impl FromStr for FileType{
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s{
+            "shape" => Ok(FileType::Shape),
+            "kml" => Ok(FileType::Kml),
+            "geojson" => Ok(FileType::GeoJson),
+            x => Err(Error::Argument(format!("Unknown filetype specified: {}, expected shape, kml or geojson", x))),
+        }
+    }
+}
+
+/// A file the pipeline wants written: its name and its content.
+pub struct OutputFile{
+    pub name: String,
+    pub buffer: Buffer,
+}
+
+impl OutputFile{
+    pub fn new(name: &str, buffer: Buffer) -> Self{
+        Self{
+            name: name.to_string(),
+            buffer,
+        }
+    }
+}
+
+/// A style as written in the `styles` file: (outline or width, r, g, b).
+pub type Style = (u8,u8,u8,u8);
+
+/// Read all shapes from a shapefile.
+pub fn read_shapefile(path: &str) -> Result<Vec<shapefile::Shape>>{
+    match shapefile::read(path){
+        Ok(shapes) => {
+            println!("Read file \"{}\": shapes: {}", path, shapes.len());
+            Ok(shapes)
+        },
+        Err(cause) => Err(Error::Shapefile{ path: path.to_string(), cause }),
+    }
+}
+
+/// How input files are read.
+#[derive(Clone,Default,Debug)]
+pub struct ReadSettings{
+    // attribute (shapefile) or property (geojson) with the height
+    pub height_field: Option<String>,
+    // attribute (shapefile) or property (geojson) with the style, geojson uses "style" when not given
+    pub style_field: Option<String>,
+    // project everything into this crs, else projected inputs are kept as they are and lon/lat goes to utm
+    pub crs: Option<Crs>,
+    // the utm zone lon/lat goes into without a target crs, picked from the first point when not given
+    pub utm_zone: Option<UtmZone>,
+    // round projected x and y to multiples of this, in units of the target crs
+    pub precision: Option<f64>,
+    // snap heights to multiples of this
+    pub height_snap: Option<f64>,
+}
+
+impl ReadSettings{
+    /// What is done to the points of an input in the given crs.
+    pub fn points(&self, source: Option<Crs>, path: &str) -> Result<Points>{
+        Ok(Points{
+            proj: Projection::new(source, self.crs, self.utm_zone, path)?,
+            precision: self.precision,
+            height_snap: self.height_snap,
+        })
+    }
+}
+
+/// An input file of some filetype.
+/// GeoJSON is parsed when it is opened, its crs, first point and shapes all come from that one parse.
+pub struct Input(Source);
+
+enum Source{
+    Shape(String),
+    Kml(String),
+    GeoJson(GeoJsonFile),
+}
+
+impl Input{
+    pub fn open(ft: FileType, path: &str) -> Result<Self>{
+        Ok(Input(match ft{
+            FileType::Shape => Source::Shape(path.to_string()),
+            FileType::Kml => Source::Kml(path.to_string()),
+            FileType::GeoJson => Source::GeoJson(GeoJsonFile::open(path)?),
+        }))
+    }
+
+    pub fn path(&self) -> &str{
+        match &self.0{
+            Source::Shape(path) | Source::Kml(path) => path,
+            Source::GeoJson(file) => file.path(),
+        }
+    }
+
+    pub fn file_type(&self) -> FileType{
+        match self.0{
+            Source::Shape(_) => FileType::Shape,
+            Source::Kml(_) => FileType::Kml,
+            Source::GeoJson(_) => FileType::GeoJson,
+        }
+    }
+
+    /// The crs of the input, `None` when it is not known.
+    /// Shapefiles have it in the `.prj` next to them, kml is always WGS84.
+    pub fn crs(&self) -> Result<Option<Crs>>{
+        match &self.0{
+            Source::Shape(path) => Crs::read_prj(path),
+            Source::Kml(_) => Ok(Some(Crs::Wgs84)),
+            Source::GeoJson(file) => Ok(file.crs()),
+        }
+    }
+
+    // The first point as it is in the file
+    fn first_point(&self) -> Result<Option<P2<f64>>>{
+        match &self.0{
+            Source::Shape(path) => {
+                let s = split(read_shapefile(path)?, &mut Logger::default());
+                let p4s = s.5.iter().flatten().chain(s.11.iter().flat_map(|(outers,_)| outers.iter().flatten()));
+                let p2s = s.3.iter().flatten().chain(s.9.iter().flat_map(|(outers,_)| outers.iter().flatten()));
+                Ok(p4s.map(|p| (p.0,p.1)).chain(p2s.copied()).next())
+            },
+            Source::Kml(path) => kml_first_point(path),
+            Source::GeoJson(file) => file.first_point(),
+        }
+    }
+
+    // What is done to the points of this input
+    fn points(&self, settings: &ReadSettings) -> Result<Points>{
+        settings.points(self.crs()?, self.path())
+    }
+}
+
+/// Open every input, fails on the first that can not be opened.
+pub fn open_inputs(ft: FileType, paths: &[String]) -> Result<Vec<Input>>{
+    paths.iter().map(|path| Input::open(ft, path)).collect()
+}
+
+/// The settings with the utm zone lon/lat input goes into filled in, when there is no target crs.
+/// Unless a zone is given it is the zone of the first point of the first lon/lat file,
+/// so a whole dataset ends up in one zone.
+pub fn with_utm_zone(inputs: &[Input], settings: &ReadSettings) -> Result<ReadSettings>{
+    let mut settings = settings.clone();
+    if let (Some(crs), Some(zone)) = (settings.crs, settings.utm_zone){
+        return Err(Error::Argument(format!("Give a target crs ({}) or a utm zone ({}), not both", crs, zone.crs())));
+    }
+    if settings.crs.is_some() || settings.utm_zone.is_some() { return Ok(settings); }
+    // a file that can not be read does not pick the zone, reading it reports the error
+    for input in inputs{
+        if input.crs().ok().flatten() != Some(Crs::Wgs84) { continue; }
+        if let Ok(Some(p)) = input.first_point(){
+            let zone = UtmZone::of(p);
+            println!("Lon/lat is projected into {}", zone.crs());
+            settings.utm_zone = Some(zone);
+            break;
+        }
+    }
+    Ok(settings)
+}
+
+/// Fails when the inputs are in different crs's and there is no target crs to project them into.
+pub fn check_same_crs(inputs: &[Input], target: Option<Crs>) -> Result<()>{
+    if target.is_some() { return Ok(()); }
+    let mut first: Option<(&str, Option<Crs>)> = None;
+    for input in inputs{
+        let (path, crs) = (input.path(), input.crs()?);
+        match first{
+            None => first = Some((path, crs)),
+            Some((fpath, fcrs)) if fcrs != crs => return Err(Error::crs(path, format!(
+                "crs {} is not the same as {} of \"{}\", give a target crs to merge them",
+                crs_name(crs), crs_name(fcrs), fpath
+            ))),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Read heightlines from a shapefile, kml or geojson file.
+/// Points are projected into the target crs of the settings.
+/// The height is taken from the height field attribute (shapefile) or property (geojson) when given,
+/// else from the z of the points. With a height field shapefiles can have 2d polylines.
+pub fn read_heightlines(input: &Input, settings: &ReadSettings, logger: &mut Logger) -> Result<VvP4>{
+    let settings = &with_utm_zone(std::slice::from_ref(input), settings)?;
+    let height_field = settings.height_field.as_deref();
+    let mut lines = match (&input.0, height_field){
+        (Source::Shape(path), None) => {
+            let shapes = read_shapefile(path)?;
+            split(shapes, logger).5
+        },
+        (Source::Shape(path), Some(field)) => {
+            let records = read_shapes_and_records(path)?;
+            check_field(&records, field, path)?;
+            let mut vvp4 = Vec::new();
+            for (i,(shape,record)) in records.into_iter().enumerate(){
+                match record_number(&record, field, path, i){
+                    Ok(z) => vvp4.append(&mut polylines_at_height(shape, z, logger)),
+                    Err(e) => logger.skip(Issue::SkippedRecord, e),
+                }
+            }
+            vvp4
+        },
+        (Source::Kml(path), _) => return kml_height(path, &input.points(settings)?, logger),
+        (Source::GeoJson(file), _) => return geojson_height(file, &input.points(settings)?, height_field, logger),
+    };
+    input.points(settings)?.lines(&mut lines);
+    Ok(lines)
+}
+
+/// Read the polygons from a shapefile, projected into the target crs of the settings.
+/// Without fields only the PolygonZ's are read and they all get style 0.
+/// With a height field polygons without z get their height from that attribute,
+/// with a style field the distinct values of that attribute become the styles.
+/// Gives back the polygons and the attribute value of every style.
+pub fn read_polygonzs(path: &str, settings: &ReadSettings, logger: &mut Logger)
+    -> Result<(Vec<PolygonZ<f64>>, Vec<String>)>
+{
+    let input = Input::open(FileType::Shape, path)?;
+    let settings = &with_utm_zone(std::slice::from_ref(&input), settings)?;
+    let points = input.points(settings)?;
+    let height_field = settings.height_field.as_deref();
+    let style_field = settings.style_field.as_deref();
+    if height_field.is_none() && style_field.is_none(){
+        let shapes = read_shapefile(path)?;
+        let mut polys = split(shapes, logger).11;
+        points.polys(&mut polys);
+        return Ok((polys.into_iter().map(|p| PolygonZ::from(p,0)).collect(), Vec::new()));
+    }
+    let records = read_shapes_and_records(path)?;
+    for field in [height_field, style_field].iter().flatten(){
+        check_field(&records, field, path)?;
+    }
+    let mut classes = Vec::new();
+    let mut class_ids = HashMap::new();
+    let mut polyzs = Vec::new();
+    for (i,(shape,record)) in records.into_iter().enumerate(){
+        let z = match height_field{
+            Some(field) => record_number(&record, field, path, i),
+            None => Ok(0.0),
+        };
+        let class = match style_field{
+            Some(field) => record_class(&record, field, path, i).map(Some),
+            None => Ok(None),
+        };
+        let (z,class) = match (z,class){
+            (Ok(z), Ok(class)) => (z,class),
+            (Err(e), _) | (_, Err(e)) => { logger.skip(Issue::SkippedRecord, e); continue; },
+        };
+        let style = match class{
+            Some(class) => *class_ids.entry(class.clone()).or_insert_with(||{
+                classes.push(class);
+                classes.len() - 1
+            }),
+            None => 0,
+        };
+        let mut polys = polygons_at_height(shape, z, logger);
+        points.polys(&mut polys);
+        polyzs.extend(polys.into_iter().map(|p| PolygonZ::from(p,style)));
+    }
+    Ok((polyzs, classes))
+}
+
+// geopolys and geolines store whole metres
+fn check_projected(settings: &ReadSettings) -> Result<()>{
+    match settings.crs{
+        Some(crs) if !crs.is_projected() =>
+            Err(Error::Argument(format!("Styled shapes need a projected crs in metres, not {}", crs))),
+        _ => Ok(()),
+    }
+}
+
+/// Read the styled polygons from geological kml or geojson files, projected into the target crs.
+/// The styles of all files are merged into one list, the polygons index into it.
+/// For geojson the style id is the style field property.
+pub fn read_geopolys(inputs: &[Input], settings: &ReadSettings, logger: &mut Logger)
+    -> Result<(Vec<PolygonZ<u32>>, Vec<Style>)>
+{
+    check_projected(settings)?;
+    let settings = &with_utm_zone(inputs, settings)?;
+    let style_field = settings.style_field.as_deref().unwrap_or("style");
+    let mut styles = Vec::new();
+    let mut counter = 0;
+    let mut polyzs = Vec::new();
+    for input in inputs{
+        let polys = match &input.0{
+            Source::GeoJson(file) => geojson_geo(file, &input.points(settings)?, style_field, &mut styles, &mut counter, logger)?,
+            Source::Kml(file) => kml_geo(file, &input.points(settings)?, &mut styles, &mut counter, logger)?,
+            Source::Shape(_) => return Err(Error::Argument(String::from("Styled polygons can only be read from kml or geojson"))),
+        };
+        polyzs.extend(polys.into_iter().map(|(sty,poly)| PolygonZ::from(poly,sty)));
+    }
+    let mut polyzs = polyzs.into_iter().map(int_cast).collect::<Vec<_>>();
+    polyzs.iter_mut().for_each(|p| p.stretch_bb());
+    println!("There are {} polygons!", polyzs.len());
+    Ok((polyzs, styles))
+}
+
+/// Read the styled lines from geological kml or geojson files, projected into the target crs.
+/// The styles of all files are merged into one list, the lines index into it.
+/// For geojson the style id is the style field property.
+pub fn read_geolines(inputs: &[Input], settings: &ReadSettings, logger: &mut Logger)
+    -> Result<(Vec<StyledLine<u32>>, Vec<Style>)>
+{
+    check_projected(settings)?;
+    let settings = &with_utm_zone(inputs, settings)?;
+    let style_field = settings.style_field.as_deref().unwrap_or("style");
+    let mut styles = Vec::new();
+    let mut counter = 0;
+    let mut slines = Vec::new();
+    for input in inputs{
+        let lines = match &input.0{
+            Source::GeoJson(file) => geojson_geo_lines(file, &input.points(settings)?, style_field, &mut styles, &mut counter, logger)?,
+            Source::Kml(file) => kml_geo_lines(file, &input.points(settings)?, &mut styles, &mut counter, logger)?,
+            Source::Shape(_) => return Err(Error::Argument(String::from("Styled lines can only be read from kml or geojson"))),
+        };
+        for l in lines{
+            StyledLine::<u32>::from_as_int(l, &mut slines);
+        }
+    }
+    println!("There are {} lines!", slines.len());
+    Ok((slines, styles))
+}
+
+/// Collect the heightlines that have more than one z value, as lists of their z values.
+pub fn lint_heightlines(plinezs: VvP4, logger: &mut Logger) -> Vvec<f64>{
+    collect_wrong_heightlines(plinezs, logger)
+}
+
+/// Turn raw heightlines into `ShapeZ`s, skipping the ones that have more than one z value.
+pub fn heightlines_to_shapezs(plinezs: VvP4, logger: &mut Logger) -> Vec<ShapeZ<f64>>{
+    compress_heightmap(plinezs, logger)
+}
+
+// the lowest height and the range of the heights in whole numbers, heights can be below zero
+fn height_range(shapezs: &[ShapeZ<f64>]) -> (i64,u64){
+    if shapezs.is_empty() { return (0,0); }
+    let zmin = shapezs.iter().fold(f64::MAX, |min, s| min.min(s.z)).floor();
+    let zmax = shapezs.iter().fold(f64::MIN, |max, s| max.max(s.z)).ceil();
+    (zmin as i64, (zmax - zmin) as u64)
+}
+
+// The largest error of rounding every axis, measured while compressing.
+// Fails when an axis is further off than the tolerance.
+fn check_tolerance((ex,ey,ez): P3<f64>, tolerance: Option<f64>) -> Result<()>{
+    for (axis,error) in [("x",ex), ("y",ey), ("z",ez)]{
+        println!("{}: max error {}", axis, error);
+    }
+    let tolerance = match tolerance{
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    for (axis,error) in [("x",ex), ("y",ey), ("z",ez)]{
+        if !meets_tolerance(error, tolerance){
+            return Err(Error::Argument(format!("{} is up to {} off after quantizing, more than the tolerance {}", axis, error, tolerance)));
+        }
+    }
+    Ok(())
+}
+
+/// Compress heightlines into the format `chunkify_heightlines` takes.
+/// Heights are whole numbers, offset by the lowest one.
+/// With a tolerance, every axis gets the smallest type and multiplier that quantize it within it, heights too.
+/// Fails when an axis does not fit u16, `chunkify_heightlines` can not read that.
+pub fn compress_heightlines(shapezs: Vec<ShapeZ<f64>>, tolerance: Option<f64>, encoding: Encoding, logger: &mut Logger) -> Result<Buffer>{
+    let (zmin,zrange) = height_range(&shapezs);
+    let infos = info_package(&shapezs, tolerance);
+    let zmulti = if tolerance.is_some() { infos.multi.2 } else { 1 };
+    let infos = infos.with_z(zmin, zrange, zmulti);
+    let (x,y,z) = infos.sizes();
+    if x > 2 || y > 2 || z > 2{
+        let hint = if tolerance.is_some() { ", a larger tolerance gives smaller types" } else { "" };
+        return Err(Error::Argument(format!("chunkify reads height files of at most u16, this one would have type sizes {:?}{}", infos.sizes(), hint)));
+    }
+    let (buffer, error) = shapezs.compress_measured(infos, encoding, logger);
+    check_tolerance(error, tolerance)?;
+    Ok(buffer)
+}
+
+/// Compress PolygonZ's as they are.
+/// With a tolerance, every axis gets the smallest type and multiplier that quantize it within it.
+pub fn compress_polygonzs(polyzs: Vec<PolygonZ<f64>>, tolerance: Option<f64>, encoding: Encoding, logger: &mut Logger) -> Result<Buffer>{
+    let infos = info_package(&polyzs, tolerance);
+    let (buffer, error) = polyzs.compress_measured(infos, encoding, logger);
+    check_tolerance(error, tolerance)?;
+    Ok(buffer)
+}
+
+/// Triangulate PolygonZ's and compress the triangles.
+/// The tolerance is checked on the quantized polygons the triangles are made of.
+pub fn compress_triangulated(polyzs: Vec<PolygonZ<f64>>, tolerance: Option<f64>, encoding: Encoding, logger: &mut Logger) -> Result<Buffer>{
+    let infos = info_package(&polyzs, tolerance);
+    let (buffer, error) = polyzs.triangle_compress_measured(infos, encoding, logger);
+    check_tolerance(error, tolerance)?;
+    Ok(buffer)
+}
+
+/// Write shapes in world coordinates as a GeoJSON FeatureCollection, for debugging.
+/// Styles give the features their colours, pass an empty slice when there are none.
+pub fn geojson_output<S: GeoJsonFeature>(name: &str, shapes: &[S], styles: &[Style]) -> OutputFile{
+    let mut geojson = GeoJson::new();
+    geojson.add_all(shapes, &Transform::identity(), styles, &[]);
+    OutputFile::new(name, geojson.finish().into_bytes())
+}
+
+/// Triangulate PolygonZ's in world coordinates and write the triangles as GeoJSON.
+/// `compress_triangulated` triangulates the quantized polygons, this is only to look at the result.
+pub fn triangulated_geojson_output(name: &str, polyzs: &[PolygonZ<f64>]) -> OutputFile{
+    // the issues are already reported by the real triangulation
+    let triangles = triangulate(polyzs.to_vec(), &mut Logger::default());
+    geojson_output(name, &triangles, &[])
+}
+
+pub use crate::decode::HeightFile;
+
+/// Read a compressed height file from disk.
+pub fn read_height_file(path: &str) -> Result<HeightFile>{
+    let raw = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    let mut buffer = ReadBuffer::from_raw(raw);
+    crate::decode::read_height_file(&mut buffer, path)
+}
+
+/// Read back any file this crate writes, the kind is read from the file header.
+/// When a kind is given, files of another kind are rejected.
+pub fn decode_file(path: &str, kind: Option<FormatKind>) -> Result<Decoded>{
+    crate::decode::decode_file(path, kind)
+}
+
+/// How the LOD levels of heightline chunks are built.
+/// There are as many levels as there are mods.
+pub struct LodSettings{
+    // cuts over each axis on the first level
+    pub cuts: u64,
+    // multiplier of the cuts between levels
+    pub cuts_multi: u64,
+    // heightline modulo of every level
+    pub mods: Vec<u64>,
+    // how the lines of every level are simplified
+    pub simplify: Vec<Simplify>,
+    // the simplification tolerance is the chunk size divided by this
+    pub detail: f64,
+    // split chunks in a quadtree until they fit this instead of cutting them on a grid
+    pub budget: Option<Budget>,
+    // the deepest the quadtree goes
+    pub max_depth: u8,
+    // how the points of the chunks are written
+    pub encoding: Encoding,
+    // also write all chunks to hlinechunks.geojson
+    pub export: Export,
+}
+
+impl Default for LodSettings{
+    fn default() -> Self{
+        Self{
+            cuts: 1,
+            cuts_multi: 2,
+            mods: vec![400,200,100,50,25,5],
+            simplify: default_simplify(6),
+            detail: 2000.0,
+            budget: None,
+            max_depth: 10,
+            encoding: Encoding::Raw,
+            export: Export::None,
+        }
+    }
+}
+
+/// Douglas–Peucker on every level but the last, which keeps all points.
+pub fn default_simplify(levels: usize) -> Vec<Simplify>{
+    let mut simplify = vec![Simplify::DouglasPeucker; levels];
+    if let Some(last) = simplify.last_mut(){
+        *last = Simplify::None;
+    }
+    simplify
+}
+
+// the simplification tolerance on a grid of cuts × cuts over the bounding box
+fn tolerance<T: Copy + Into<f64>>(bb: &BB<T>, cuts: u64, detail: f64) -> f64{
+    let size = ((bb.1).0.into() - (bb.0).0.into()).max((bb.1).1.into() - (bb.0).1.into());
+    size / cuts as f64 / detail
+}
+
+// the quadtree can not go deeper than the bits of its squares
+fn check_max_depth(max_depth: u8) -> Result<()>{
+    if max_depth > 32 { return Err(Error::Argument(format!("Max depth can be at most 32, got {}", max_depth))); }
+    Ok(())
+}
+
+// Chunk a level with a quadtree. make gives back the chunk of a square and write gives back its file,
+// a square with more points than the budget can take is split without writing it.
+// They get a logger of their own, so what happens in squares that are split again is not counted.
+type QuadChunks<C> = Vec<(Square,(Vec<C>,Buffer))>;
+
+fn quadtree_level<S,C>((budget,max_depth): (Budget,u8), root: (P2<f64>,f64), items: Vec<S>,
+    split: impl Fn(&Grid, &[S]) -> Cells<S>,
+    make: impl Fn(Square, &[S], &mut Logger) -> Vec<C>,
+    write: impl Fn(Square, &[C], &mut Logger) -> Buffer,
+    logger: &mut Logger,
+) -> (Vec<QuadNode>, QuadChunks<C>)
+    where
+        C: CustomShape,
+{
+    quadtree(root, items, max_depth, split, |square, items, last|{
+        let mut trial = Logger::default();
+        let chunk = make(square, items, &mut trial);
+        let points = chunk.iter().map(|s| s.points_len() as u64).sum();
+        if !last && !budget.can_fit(points) { return None; }
+        let buffer = write(square, &chunk, &mut trial);
+        if !last && !budget.fits(points, buffer.len()) { return None; }
+        logger.merge(trial);
+        Some(((chunk,buffer),points))
+    })
+}
+
+// The chunks.quadtree file: the root square and the nodes of every level
+fn quadtree_output((origin,side): (P2<f64>,f64), levels: Vvec<QuadNode>) -> OutputFile{
+    let mut buffer = header_buffer(FormatKind::Quadtree);
+    (origin.0 as u64, origin.1 as u64).into_buffer(&mut buffer);
+    (side as u64).into_buffer(&mut buffer);
+    levels.into_buffer(&mut buffer);
+    OutputFile::new("chunks.quadtree", buffer)
+}
+
+// Chunks are quantized relative to a corner of their cell, which is a whole number for grids
+// and for quadtrees until the squares get smaller than one
+fn corner_i64((x,y): P2<f64>) -> P2<i64>{
+    (x as i64, y as i64)
+}
+
+// A hlinechunk: its level and place, then the lines compressed relative to the corner of its cell
+fn hline_chunk_buffer(level: u64, (x,y): (u64,u64), corner: P2<i64>, chunk: &[ShapeZ<u16>], encoding: Encoding, logger: &mut Logger) -> Buffer{
+    let zmax = chunk.iter().map(|s| s.z as u64).max().unwrap_or(0);
+    let infos = cell_info_package(chunk, corner).with_z(0, zmax, 1);
+    let mut buffer = flagged_header_buffer(FormatKind::HlineChunk, target_encoding(&infos, encoding).flags());
+    level.into_buffer(&mut buffer);
+    x.into_buffer(&mut buffer);
+    y.into_buffer(&mut buffer);
+    let shapes = chunk.iter().map(|s| ShapeZ{
+        points: s.points.iter().map(|(x,y)| (*x as f64, *y as f64)).collect(),
+        z: s.z as f64,
+        bb: ((0.0,0.0,0.0),(0.0,0.0,0.0)),
+    }).collect::<Vec<_>>();
+    shapes.compress_into(infos, encoding, &mut buffer, logger);
+    buffer
+}
+
+/// Cut a compressed height file into LOD chunks.
+/// Gives back a `level-x-y.hlinechunk` file for every chunk and the `chunks.info` file.
+/// When exporting, `hlinechunks.geojson` has every chunk with its level, x and y as properties.
+pub fn chunkify_heightlines(hf: &HeightFile, settings: &LodSettings, logger: &mut Logger)
+    -> Result<Vec<OutputFile>>
+{
+    if settings.cuts == 0 || settings.cuts_multi == 0{
+        return Err(Error::Argument(String::from("Cuts and cuts multiplier should be at least one!")));
+    }
+    if settings.mods.is_empty() || settings.mods.contains(&0){
+        return Err(Error::Argument(String::from("Need at least one level and mods can not be zero!")));
+    }
+    if settings.simplify.len() != settings.mods.len(){
+        return Err(Error::Argument(format!(
+            "Need a simplification for every level, got {} for {} levels!", settings.simplify.len(), settings.mods.len()
+        )));
+    }
+    if settings.detail <= 0.0{
+        return Err(Error::Argument(String::from("Detail should be more than zero!")));
+    }
+    check_max_depth(settings.max_depth)?;
+    let HeightFile{ quant, bb, shapes } = hf;
+    let Quantization{ mx, my, mz, multi, tsize } = quant;
+    println!("mx: {} my: {} mz: {} multi: {:?}", mx, my, mz, multi);
+    println!("{:?}{:?}", bb.0, bb.1);
+    print_height_distribution(shapes);
+    let mods = &settings.mods;
+    let levels = mods.len() as u64;
+    let mut cuts = settings.cuts;
+    let mut outputs = Vec::new();
+    let mut geojson = GeoJson::new();
+    let tf = Transform::new(*mx, *my, *mz, *multi);
+    // the cuts of the grid of every level, there is no grid when chunking a quadtree
+    let mut level_cuts = Vec::new();
+    let root = quad_root(bb);
+    let mut quad_levels = Vec::new();
+    for i in 0..levels{
+        let tol = tolerance(bb, cuts, settings.detail);
+        let simplify = settings.simplify[i as usize];
+        if let Some(budget) = settings.budget{
+            let filtered = pick_heights(mods[i as usize], *mz, multi.2, shapes.clone());
+            let (nodes, chunks) = quadtree_level((budget, settings.max_depth), root, filtered, cut_shapes,
+                |_, items, logger| optimize_lines(simplify_lines(simplify, tol, items.to_vec(), logger)),
+                |square, finalv, logger|{
+                    let corner = corner_i64(square_corner(root, square));
+                    hline_chunk_buffer(i, (square.1,square.2), corner, finalv, settings.encoding, logger)
+                }, logger);
+            println!("level {}: quadtree nodes: {}, chunks: {}", i, nodes.len(), chunks.len());
+            for ((d,x,y),(finalv,buffer)) in chunks{
+                if settings.export == Export::GeoJson{
+                    geojson.add_all(&finalv, &tf, &[], &[("level", i.to_string()), ("depth", d.to_string()), ("x", x.to_string()), ("y", y.to_string())]);
+                }
+                outputs.push(OutputFile::new(&format!("{}-{}-{}-{}.hlinechunk", i, d, x, y), buffer));
+            }
+            quad_levels.push(nodes);
+            cuts = cuts.saturating_mul(settings.cuts_multi);
+            continue;
+        }
+        let grid = Grid::new(cuts.max(1), bb);
+        for (x,y,chunk) in cut(cuts.max(1), *bb, shapes){
+            let points0 = chunk.iter().fold(0, |sum, sz| sum + sz.points_len());
+            let filtered = pick_heights(mods[i as usize], *mz, multi.2, chunk);
+            let points1 = filtered.iter().fold(0, |sum, sz| sum + sz.points_len());
+            let picked = simplify_lines(simplify, tol, filtered, logger);
+            let points2 = picked.iter().fold(0, |sum, sz| sum + sz.points_len());
+            let lines0 = picked.len();
+            let finalv = optimize_lines(picked);
+            let lines1 = finalv.len();
+            if settings.export == Export::GeoJson{
+                geojson.add_all(&finalv, &tf, &[], &[("level", i.to_string()), ("x", x.to_string()), ("y", y.to_string())]);
+            }
+            let buffer = hline_chunk_buffer(i, (x,y), corner_i64(grid.rect((x,y)).0), &finalv, settings.encoding, logger);
+            outputs.push(OutputFile::new(&format!("{}-{}-{}.hlinechunk", i, x, y), buffer));
+            println!("l0: {} l1: {} l2: {} s0: {} s1: {}", points0, points1, points2, lines0, lines1);
+        }
+        level_cuts.push(cuts);
+        cuts = cuts.saturating_mul(settings.cuts_multi);
+    }
+    let mut info_buffer = header_buffer(FormatKind::ChunksInfo);
+    level_cuts.into_buffer(&mut info_buffer);
+    (*mx as u64).into_buffer(&mut info_buffer);
+    (*my as u64).into_buffer(&mut info_buffer);
+    (*mz as u64).into_buffer(&mut info_buffer);
+    multi.into_buffer(&mut info_buffer);
+    tsize.into_buffer(&mut info_buffer);
+    bb.0.into_buffer(&mut info_buffer);
+    bb.1.into_buffer(&mut info_buffer);
+    mods.clone().into_buffer(&mut info_buffer);
+    outputs.push(OutputFile::new("chunks.info", info_buffer));
+    if settings.budget.is_some(){
+        outputs.push(quadtree_output(root, quad_levels));
+    }
+    if settings.export == Export::GeoJson{
+        outputs.push(OutputFile::new("hlinechunks.geojson", geojson.finish().into_bytes()));
+    }
+    Ok(outputs)
+}
+
+// The styles file is shared by geopolys and geolines.
+fn styles_output(styles: Vec<Style>) -> OutputFile{
+    let mut stylebuffer = header_buffer(FormatKind::Styles);
+    styles.into_buffer(&mut stylebuffer);
+    OutputFile::new("styles", stylebuffer)
+}
+
+/// How the LOD levels of geological polygons and lines are built.
+/// There are as many levels as there are simplifications.
+pub struct GeoLodSettings{
+    // cuts over each axis on the first level
+    pub cuts: u64,
+    // multiplier of the cuts between levels
+    pub cuts_multi: u64,
+    // how the lines and polygon rings of every level are simplified
+    pub simplify: Vec<Simplify>,
+    // the simplification tolerance is the chunk size divided by this
+    pub detail: f64,
+    // polygons and holes smaller than this many tolerances squared are left out, on every level but the last
+    pub cull: f64,
+    // split chunks in a quadtree until they fit this instead of cutting them on a grid
+    pub budget: Option<Budget>,
+    // the deepest the quadtree goes
+    pub max_depth: u8,
+    // how the points of the chunks are written
+    pub encoding: Encoding,
+    // also write all chunks to geojson
+    pub export: Export,
+}
+
+impl Default for GeoLodSettings{
+    fn default() -> Self{
+        Self{
+            cuts: 1,
+            cuts_multi: 2,
+            simplify: vec![Simplify::None],
+            detail: 2000.0,
+            cull: 4.0,
+            budget: None,
+            max_depth: 10,
+            encoding: Encoding::Raw,
+            export: Export::None,
+        }
+    }
+}
+
+// the cuts of every level
+fn geo_level_cuts(settings: &GeoLodSettings) -> Result<Vec<u64>>{
+    if settings.simplify.is_empty(){
+        return Err(Error::Argument(String::from("Need at least one level!")));
+    }
+    if settings.detail <= 0.0 { return Err(Error::Argument(String::from("Detail should be more than zero!"))); }
+    if settings.cull < 0.0 { return Err(Error::Argument(String::from("Cull can not be negative!"))); }
+    check_max_depth(settings.max_depth)?;
+    if settings.cuts == 0 || settings.cuts_multi == 0{
+        return Err(Error::Argument(String::from("Cuts and cuts multiplier should be at least one!")));
+    }
+    let mut cuts = settings.cuts;
+    let mut res = Vec::new();
+    for _ in &settings.simplify{
+        res.push(cuts);
+        cuts = cuts.saturating_mul(settings.cuts_multi);
+    }
+    Ok(res)
+}
+
+// The chunks.polyinfo and chunks.geolineinfo files
+// There is no grid when chunking a quadtree, then the cuts are left out
+fn grid_info_output(name: &str, kind: FormatKind, gbb: BB<u32>, level_cuts: &[u64], quadtree: bool) -> OutputFile{
+    let mut infobuffer = header_buffer(kind);
+    gbb.into_buffer(&mut infobuffer);
+    let level_cuts = if quadtree { Vec::new() } else { level_cuts.to_vec() };
+    level_cuts.into_buffer(&mut infobuffer);
+    OutputFile::new(name, infobuffer)
+}
+
+/// Triangulate styled polygons and cut them into LOD chunks, a grid of cuts × cuts on every level.
+/// On every level the polygons are simplified with a tolerance of the chunk size divided by the detail
+/// and polygons and holes that are too small to see are left out.
+/// Gives back a `level-x-y.polychunk` file for every chunk, the `styles` and the `chunks.polyinfo` file.
+/// When exporting, `polychunks.geojson` has every chunk with its level, x and y as properties.
+pub fn chunkify_geopolys(polyzs: Vec<PolygonZ<u32>>, styles: Vec<Style>, settings: &GeoLodSettings, logger: &mut Logger)
+    -> Result<Vec<OutputFile>>
+{
+    let level_cuts = geo_level_cuts(settings)?;
+    // every picks from all polygons of a level together, so a level would have the same number of points in all
+    if settings.simplify.contains(&Simplify::Every){
+        return Err(Error::Argument(String::from("Polygons can not be simplified with every, use dp, vw or topo")));
+    }
+    let gbb = get_global_bb(&polyzs);
+    let last = level_cuts.len() - 1;
+    let root = quad_root(&gbb);
+    let mut quad_levels = Vec::new();
+    let mut outputs = Vec::new();
+    let mut geojson = GeoJson::new();
+    for (i,cuts) in level_cuts.iter().enumerate(){
+        let tol = tolerance(&gbb, *cuts, settings.detail);
+        let min_area = if i == last { 0.0 } else { settings.cull * tol * tol };
+        let polygons = simplify_polygons(settings.simplify[i], tol, min_area, &polyzs, logger);
+        println!("level {}: polygons: {} of {}", i, polygons.len(), polyzs.len());
+        let triangles = triangulate(polygons, logger);
+        if let Some(budget) = settings.budget{
+            let (nodes, chunks) = quadtree_level((budget, settings.max_depth), root, triangles, clip_triangles,
+                |_, items, _| items.to_vec(),
+                |square, chunk, logger|{
+                    let infos = cell_info_package(chunk, corner_i64(square_corner(root, square)));
+                    chunk.to_vec().compress(infos, settings.encoding, logger)
+                }, logger);
+            println!("level {}: quadtree nodes: {}, chunks: {}", i, nodes.len(), chunks.len());
+            for ((d,x,y),(chunk,buffer)) in chunks{
+                if settings.export == Export::GeoJson{
+                    geojson.add_all(&chunk, &Transform::identity(), &styles, &[("level", i.to_string()), ("depth", d.to_string()), ("x", x.to_string()), ("y", y.to_string())]);
+                }
+                outputs.push(OutputFile::new(&format!("{}-{}-{}-{}.polychunk", i, d, x, y), buffer));
+            }
+            quad_levels.push(nodes);
+            continue;
+        }
+        let grid = Grid::new(*cuts, &gbb);
+        for (x,y,chunk) in chunkify_polytriangles(*cuts, gbb, &triangles){
+            if settings.export == Export::GeoJson{
+                geojson.add_all(&chunk, &Transform::identity(), &styles, &[("level", i.to_string()), ("x", x.to_string()), ("y", y.to_string())]);
+            }
+            let infos = cell_info_package(&chunk, corner_i64(grid.rect((x,y)).0));
+            let buffer = chunk.compress(infos, settings.encoding, logger);
+            outputs.push(OutputFile::new(&format!("{}-{}-{}.polychunk", i, x, y), buffer));
+        }
+    }
+    outputs.push(styles_output(styles));
+    outputs.push(grid_info_output("chunks.polyinfo", FormatKind::PolyInfo, gbb, &level_cuts, settings.budget.is_some()));
+    if settings.budget.is_some(){
+        outputs.push(quadtree_output(root, quad_levels));
+    }
+    if settings.export == Export::GeoJson{
+        outputs.push(OutputFile::new("polychunks.geojson", geojson.finish().into_bytes()));
+    }
+    Ok(outputs)
+}
+
+/// Cut styled lines into LOD chunks, a grid of cuts × cuts on every level.
+/// On every level the lines are simplified with a tolerance of the chunk size divided by the detail
+/// and lines of the same style that connect are merged.
+/// Gives back a `level-x-y.geolinechunk` file for every chunk, the `chunks.geolineinfo` and the `styles` file.
+/// When exporting, `geolinechunks.geojson` has every chunk with its level, x and y as properties.
+pub fn chunkify_geolines(slines: Vec<StyledLine<u32>>, styles: Vec<Style>, settings: &GeoLodSettings, logger: &mut Logger)
+    -> Result<Vec<OutputFile>>
+{
+    let level_cuts = geo_level_cuts(settings)?;
+    let gbb = get_global_bb(&slines);
+    let root = quad_root(&gbb);
+    let mut quad_levels = Vec::new();
+    let mut outputs = Vec::new();
+    let mut geojson = GeoJson::new();
+    for (i,cuts) in level_cuts.iter().enumerate(){
+        let tol = tolerance(&gbb, *cuts, settings.detail);
+        let simplify = settings.simplify[i];
+        if let Some(budget) = settings.budget{
+            let (nodes, chunks) = quadtree_level((budget, settings.max_depth), root, slines.clone(), cut_styled_lines,
+                |_, items, logger| optimize_lines(simplify_lines(simplify, tol, items.to_vec(), logger)),
+                |square, chunk, logger|{
+                    let infos = cell_info_package(chunk, corner_i64(square_corner(root, square)));
+                    chunk.to_vec().compress(infos, settings.encoding, logger)
+                }, logger);
+            println!("level {}: quadtree nodes: {}, chunks: {}", i, nodes.len(), chunks.len());
+            for ((d,x,y),(chunk,buffer)) in chunks{
+                if settings.export == Export::GeoJson{
+                    geojson.add_all(&chunk, &Transform::identity(), &styles, &[("level", i.to_string()), ("depth", d.to_string()), ("x", x.to_string()), ("y", y.to_string())]);
+                }
+                outputs.push(OutputFile::new(&format!("{}-{}-{}-{}.geolinechunk", i, d, x, y), buffer));
+            }
+            quad_levels.push(nodes);
+            continue;
+        }
+        let grid = Grid::new(*cuts, &gbb);
+        for (x,y,chunk) in cut_styled(*cuts, gbb, &slines){
+            let chunk = optimize_lines(simplify_lines(simplify, tol, chunk, logger));
+            if settings.export == Export::GeoJson{
+                geojson.add_all(&chunk, &Transform::identity(), &styles, &[("level", i.to_string()), ("x", x.to_string()), ("y", y.to_string())]);
+            }
+            let infos = cell_info_package(&chunk, corner_i64(grid.rect((x,y)).0));
+            let buffer = chunk.compress(infos, settings.encoding, logger);
+            outputs.push(OutputFile::new(&format!("{}-{}-{}.geolinechunk", i, x, y), buffer));
+        }
+    }
+    outputs.push(grid_info_output("chunks.geolineinfo", FormatKind::GeolineInfo, gbb, &level_cuts, settings.budget.is_some()));
+    if settings.budget.is_some(){
+        outputs.push(quadtree_output(root, quad_levels));
+    }
+    if settings.export == Export::GeoJson{
+        outputs.push(OutputFile::new("geolinechunks.geojson", geojson.finish().into_bytes()));
+    }
+    outputs.push(styles_output(styles));
+    Ok(outputs)
+}
+
+/// Write a buffer to disk.
+pub fn write_buffer(path: &str, buffer: &Buffer) -> Result<()>{
+    std::fs::write(path, buffer).map_err(|e| Error::io(path, e))
+}
+
+/// Write all output files into a directory.
+pub fn write_outputs(dir: &Path, outputs: &[OutputFile]) -> Result<()>{
+    for out in outputs{
+        let path = dir.join(&out.name);
+        write_buffer(&path.to_string_lossy(), &out.buffer)?;
+        println!("Written file \"{}\"", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use crate::{
+    data::{ BB, P2 },
+    chunkify::{ Grid, Cells },
+    error::{ Error, Result },
+};
+
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+};
+
+use bin_buffer::*;
+
+// How big a chunk may get before the quadtree splits it
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Budget{
+    // points in the chunk
+    Points(u64),
+    // bytes of the chunk file
+    Bytes(u64),
+}
+
+impl Budget{
+    pub fn fits(self, points: u64, bytes: usize) -> bool{
+        match self{
+            Budget::Points(max) => points <= max,
+            Budget::Bytes(max) => bytes as u64 <= max,
+        }
+    }
+
+    // If a chunk with this many points can fit before it is written,
+    // both encodings take at least a byte for the x and for the y of a point
+    pub fn can_fit(self, points: u64) -> bool{
+        match self{
+            Budget::Points(max) => points <= max,
+            Budget::Bytes(max) => points.saturating_mul(2) <= max,
+        }
+    }
+}
+
+impl FromStr for Budget{
+    type Err = Error;
+
+    // points:<n> or bytes:<n>
+    fn from_str(s: &str) -> Result<Self>{
+        let err = || Error::Argument(format!("Unknown budget: {}, expected points:<n> or bytes:<n>", s));
+        let (kind, n) = s.split_once(':').ok_or_else(err)?;
+        let n = n.trim().parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(err)?;
+        match kind.trim(){
+            "points" => Ok(Budget::Points(n)),
+            "bytes" => Ok(Budget::Bytes(n)),
+            _ => Err(err()),
+        }
+    }
+}
+
+// A square of the quadtree: its depth and its x and y among the squares of that depth
+pub type Square = (u8,u64,u64);
+
+// A node of the quadtree. The four children of a split node follow each other in the list of nodes,
+// in the order (2x,2y), (2x+1,2y), (2x,2y+1), (2x+1,2y+1).
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct QuadNode{
+    // index of the first child, 0 for a leaf as the root is never a child
+    pub children: u32,
+    // points in the leaf, 0 when it is split or has nothing in it and so has no file
+    pub points: u64,
+}
+
+impl Bufferable for QuadNode{
+    fn into_buffer(self, buf: &mut Buffer){
+        self.children.into_buffer(buf);
+        self.points.into_buffer(buf);
+    }
+
+    fn copy_into_buffer(&self, buf: &mut Buffer){
+        (*self).into_buffer(buf);
+    }
+
+    fn from_buffer(buf: &mut ReadBuffer) -> Option<Self>{
+        Some(Self{
+            children: u32::from_buffer(buf)?,
+            points: u64::from_buffer(buf)?,
+        })
+    }
+}
+
+// The square the quadtree starts with: at the min corner of the boundingbox,
+// with a side of a power of two so the squares of every depth have a whole number as corners
+pub fn quad_root<T: Copy + Into<f64>>(gbb: &BB<T>) -> (P2<f64>,f64){
+    let extent = ((gbb.1).0.into() - (gbb.0).0.into()).max((gbb.1).1.into() - (gbb.0).1.into());
+    let side = (extent.max(0.0) as u64 + 1).next_power_of_two();
+    (((gbb.0).0.into(), (gbb.0).1.into()), side as f64)
+}
+
+// The min corner of a square
+pub fn square_corner((origin,side): (P2<f64>,f64), (depth,x,y): Square) -> P2<f64>{
+    let size = side / (1u64 << depth) as f64;
+    (origin.0 + x as f64 * size, origin.1 + y as f64 * size)
+}
+
+// Split squares in four until the chunk of every square fits, or it is at the max depth.
+// leaf gets the square, what is in it and if it is at the max depth,
+// and gives back the chunk and its points or None when it does not fit.
+// split cuts what is in a square over the 2 × 2 grid of its children.
+// Gives back the nodes breadth first and the chunks of the leaves that are not empty.
+pub fn quadtree<S,C>(root: (P2<f64>,f64), items: Vec<S>, max_depth: u8,
+    split: impl Fn(&Grid, &[S]) -> Cells<S>,
+    mut leaf: impl FnMut(Square, &[S], bool) -> Option<(C,u64)>,
+) -> (Vec<QuadNode>, Vec<(Square,C)>)
+{
+    let (origin, side) = root;
+    let mut nodes = vec![QuadNode{ children: 0, points: 0 }];
+    let mut chunks = Vec::new();
+    let mut queue = VecDeque::new();
+    queue.push_back((0, (0,0,0), items));
+    while let Some((i, (depth,x,y), items)) = queue.pop_front(){
+        if items.is_empty() { continue; }
+        if let Some((chunk,points)) = leaf((depth,x,y), &items, depth >= max_depth){
+            nodes[i].points = points;
+            chunks.push(((depth,x,y),chunk));
+            continue;
+        }
+        let size = side / (1u64 << depth) as f64;
+        let grid = Grid{
+            cuts: 2,
+            origin: square_corner((origin,side), (depth,x,y)),
+            csize: (size / 2.0, size / 2.0),
+        };
+        nodes[i].children = nodes.len() as u32;
+        for (cx,cy,part) in grid.all(split(&grid, &items)){
+            queue.push_back((nodes.len(), (depth + 1, 2 * x + cx, 2 * y + cy), part));
+            nodes.push(QuadNode{ children: 0, points: 0 });
+        }
+    }
+    (nodes, chunks)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parse_budget(){
+        assert_eq!("points:5000".parse::<Budget>().unwrap(), Budget::Points(5000));
+        assert_eq!("bytes: 20".parse::<Budget>().unwrap(), Budget::Bytes(20));
+        for bad in ["points", "points:0", "points:-3", "points:x", "lines:5", ""]{
+            assert!(bad.parse::<Budget>().is_err(), "{} was parsed", bad);
+        }
+    }
+
+    #[test]
+    fn budget_fits(){
+        assert!(Budget::Points(10).fits(10, 1000));
+        assert!(!Budget::Points(10).fits(11, 0));
+        assert!(Budget::Bytes(10).fits(100, 10));
+        // two bytes a point at least
+        assert!(Budget::Bytes(10).can_fit(5));
+        assert!(!Budget::Bytes(10).can_fit(6));
+    }
+
+    #[test]
+    fn root_square(){
+        assert_eq!(quad_root(&((10.0,20.0,0.0),(110.0,70.0,0.0))), ((10.0,20.0), 128.0));
+        assert_eq!(quad_root(&((5u32,5u32,0u32),(5,5,0))), ((5.0,5.0), 1.0));
+        assert_eq!(square_corner(((10.0,20.0), 128.0), (2,1,3)), (42.0,116.0));
+    }
+
+    // points as the items, a chunk fits with at most max points
+    fn tree(points: Vec<P2<f64>>, max: usize, max_depth: u8) -> (Vec<QuadNode>, Vec<(Square,usize)>){
+        let split = |grid: &Grid, items: &[P2<f64>]|{
+            let mut cells: Cells<P2<f64>> = HashMap::new();
+            for p in items{
+                cells.entry(grid.cell(*p)).or_default().push(*p);
+            }
+            cells
+        };
+        quadtree(((0.0,0.0), 8.0), points, max_depth, split, |_, items, last|{
+            if !last && items.len() > max { return None; }
+            Some((items.len(), items.len() as u64))
+        })
+    }
+
+    #[test]
+    fn split_at_the_budget(){
+        let (nodes, chunks) = tree(vec![(1.0,1.0),(1.0,2.0),(6.0,6.0)], 2, 10);
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(nodes[0], QuadNode{ children: 1, points: 0 });
+        assert_eq!(nodes[1], QuadNode{ children: 0, points: 2 });
+        assert_eq!(nodes[4], QuadNode{ children: 0, points: 1 });
+        assert_eq!(chunks, vec![((1,0,0),2), ((1,1,1),1)]);
+        // it all fits in the root
+        let (nodes, chunks) = tree(vec![(1.0,1.0),(6.0,6.0)], 2, 10);
+        assert_eq!(nodes, vec![QuadNode{ children: 0, points: 2 }]);
+        assert_eq!(chunks, vec![((0,0,0),2)]);
+    }
+
+    #[test]
+    fn stop_at_max_depth(){
+        let (nodes, chunks) = tree(vec![(1.0,1.0); 3], 2, 2);
+        assert_eq!(nodes.len(), 9);
+        assert_eq!(chunks, vec![((2,0,0),3)]);
+    }
+}
@@ -7,7 +7,7 @@ use crate::{
 use std::{
     cmp::Ordering,
     ops::{ Add, Sub, Div, Mul },
-    convert::TryFrom,
+    collections::HashMap,
 };
 
 use bin_buffer::*;
@@ -51,12 +51,44 @@ impl<T: Bufferable + Clone> Bufferable for PolyTriangle<T>{
     }
 }
 
+// The most vertices a PolyTriangle can have, as its indices are u16
+pub const MAX_VERTICES: usize = u16::MAX as usize + 1;
+
+// Put triangles in PolyTriangles of at most MAX_VERTICES vertices.
+// A polygon with more vertices is split over more PolyTriangles instead of being left out.
+pub fn batches<T: Copy>(vertices: &[P2<T>], indices: &[usize], style: usize, bb: BB<T>) -> Vec<PolyTriangle<T>>{
+    let empty = || PolyTriangle{ vertices: Vec::new(), indices: Vec::new(), style, bb };
+    if vertices.len() <= MAX_VERTICES{
+        return vec![PolyTriangle{ vertices: vertices.to_vec(), indices: indices.iter().map(|i| *i as u16).collect(), style, bb }];
+    }
+    let mut res = Vec::new();
+    let mut batch = empty();
+    // where the vertices of the polygon are in the batch
+    let mut local = HashMap::new();
+    for tri in indices.chunks_exact(3){
+        let new = tri.iter().filter(|i| !local.contains_key(*i)).count();
+        if batch.vertices.len() + new > MAX_VERTICES{
+            res.push(std::mem::replace(&mut batch, empty()));
+            local.clear();
+        }
+        for i in tri{
+            let li = *local.entry(*i).or_insert_with(||{
+                batch.vertices.push(vertices[*i]);
+                batch.vertices.len() - 1
+            });
+            batch.indices.push(li as u16);
+        }
+    }
+    if !batch.indices.is_empty() { res.push(batch); }
+    res
+}
+
 #[derive(Clone,PartialEq)]
 struct PolyPoint<T>{
     point: P3<T>,
     reflex: bool,
     ear: bool,
-    index: u16
+    index: usize
 }
 
 pub fn test(){
@@ -145,12 +177,7 @@ where
             for (x,y,_) in vertices{
                 p2vertices.push((x,y));
             }
-            res.push(PolyTriangle{
-                vertices: p2vertices,
-                indices: cur_indices,
-                style,
-                bb,
-            });
+            res.extend(batches(&p2vertices, &cur_indices, style, bb));
         }
     }
     if skipped > 0 { println!("Skipped {} inner polygons.", skipped); }
@@ -396,7 +423,7 @@ where
     rightmost
 }
 
-fn make_indices<T>(vertices: &[P3<T>], logger: &mut Logger) -> Option<Vec<u16>>
+fn make_indices<T>(vertices: &[P3<T>], logger: &mut Logger) -> Option<Vec<usize>>
 where
     T: Mul<Output = T> + Div<Output = T> + Add<Output = T> + Sub<Output = T> + PartialOrd + Copy + std::fmt::Display,
     T: Into<f64> + std::fmt::Debug
@@ -410,16 +437,11 @@ where
     remaining_polygon.reserve(vertices.len());
     let mut orig_indices = Vec::new();
     for (i,point) in vertices.iter().enumerate(){
-        let x = if let Ok(xx) = u16::try_from(i) { xx }
-        else{
-            logger.log(Issue::OutOfIndicesBound);
-            return None;
-        };
         let p = PolyPoint{
             point: *point,
             reflex: false,
             ear: false,
-            index: x,
+            index: i,
         };
         orig_indices.push(remaining_polygon.push_back(p));
     }
@@ -513,8 +535,8 @@ fn prev_index_cyclic<T>(polygon: &VecList<T>, i: Index<T>) -> Index<T>{
         y
     }else{
         //return the last index
-        let indices = polygon.indices();
-        indices.last().expect("Critical triangulation unwrap failed nr. 6")
+        let mut indices = polygon.indices();
+        indices.next_back().expect("Critical triangulation unwrap failed nr. 6")
     }
 }
 